@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use ccprocessor_rust::handler::CCTransactionHandler;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use sawtooth_sdk::{
+    messages::processor::TpProcessRequest,
+    processor::handler::{ContextError, TransactionContext, TransactionHandler},
+};
+use serde_cbor::Value;
+
+/// Blocks rewarded by a single `Housekeeping` run.
+const BLOCKS: u64 = 10_000;
+
+/// State held in memory, with block `n` signed by signer `n % signers`.
+struct MemoryContext {
+    state: Mutex<BTreeMap<String, Vec<u8>>>,
+    signers: u64,
+}
+
+impl MemoryContext {
+    fn new(signers: u64) -> Self {
+        Self {
+            state: Mutex::default(),
+            signers,
+        }
+    }
+
+    fn signer(&self, block_num: u64) -> String {
+        format!("signer{}", block_num % self.signers)
+    }
+}
+
+impl TransactionContext for MemoryContext {
+    fn get_state_entry(&self, address: &str) -> Result<Option<Vec<u8>>, ContextError> {
+        Ok(self.state.lock().unwrap().get(address).cloned())
+    }
+
+    fn get_state_entries(
+        &self,
+        addresses: &[String],
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        let state = self.state.lock().unwrap();
+        Ok(addresses
+            .iter()
+            .filter_map(|address| Some((address.clone(), state.get(address)?.clone())))
+            .collect())
+    }
+
+    fn set_state_entry(&self, address: String, data: Vec<u8>) -> Result<(), ContextError> {
+        self.state.lock().unwrap().insert(address, data);
+        Ok(())
+    }
+
+    fn set_state_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), ContextError> {
+        self.state.lock().unwrap().extend(entries);
+        Ok(())
+    }
+
+    fn delete_state_entry(&self, address: &str) -> Result<Option<String>, ContextError> {
+        let deleted = self.state.lock().unwrap().remove(address);
+        Ok(deleted.map(|_| address.to_owned()))
+    }
+
+    fn delete_state_entries(&self, addresses: &[String]) -> Result<Vec<String>, ContextError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(addresses
+            .iter()
+            .filter(|address| state.remove(*address).is_some())
+            .cloned()
+            .collect())
+    }
+
+    fn add_receipt_data(&self, _: &[u8]) -> Result<(), ContextError> {
+        Ok(())
+    }
+
+    fn add_event(&self, _: String, _: Vec<(String, String)>, _: &[u8]) -> Result<(), ContextError> {
+        Ok(())
+    }
+
+    fn get_sig_by_num(&self, block_num: u64) -> Result<String, ContextError> {
+        Ok(self.signer(block_num))
+    }
+
+    fn get_reward_block_signatures(
+        &self,
+        _: &str,
+        first_pred: u64,
+        last_pred: u64,
+    ) -> Result<Vec<String>, ContextError> {
+        Ok((last_pred..=first_pred)
+            .rev()
+            .map(|block_num| self.signer(block_num))
+            .collect())
+    }
+
+    fn get_state_entries_by_prefix(
+        &self,
+        address: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .range(address.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(address))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+/// A `Housekeeping` request rewarding every block up to `BLOCKS`.
+fn housekeeping_request() -> TpProcessRequest {
+    let mut payload = BTreeMap::new();
+    payload.insert(Value::Text("v".into()), Value::Text("Housekeeping".into()));
+    payload.insert(Value::Text("p1".into()), Value::Integer(BLOCKS.into()));
+    TpProcessRequest {
+        payload: serde_cbor::to_vec(&Value::Map(payload)).unwrap(),
+        tip: BLOCKS + 100,
+        ..Default::default()
+    }
+}
+
+fn reward(c: &mut Criterion) {
+    let handler = CCTransactionHandler::new("tcp://localhost:55555");
+    let request = housekeeping_request();
+
+    let mut group = c.benchmark_group("housekeeping_reward");
+    group.sample_size(10);
+    for &signers in &[1, 100, 10_000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(signers),
+            &signers,
+            |b, &signers| {
+                b.iter_batched(
+                    || MemoryContext::new(signers),
+                    |mut tx_ctx| handler.apply(&request, &mut tx_ctx).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reward);
+criterion_main!(benches);
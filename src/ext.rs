@@ -1,10 +1,11 @@
 use crate::handler::{
     constants::{INVALID_NUMBER_FORMAT_ERR, NEGATIVE_NUMBER_ERR},
+    encoding::state_version,
     types::{CCApplyError, TxnResult},
 };
 use anyhow::Context;
 use rug::Integer;
-use sawtooth_sdk::processor::handler::ApplyError;
+use sawtooth_sdk::processor::handler::{ApplyError, TransactionContext};
 
 pub trait IntegerExt {
     fn try_parse<S: AsRef<str>>(s: S) -> TxnResult<Integer> {
@@ -35,12 +36,23 @@ pub trait MessageExt<M> {
     fn to_bytes(&self) -> Vec<u8>;
 }
 
-impl<M: Message + Default> MessageExt<M> for M {
+/// A message stored in state. Entries carry their schema version in field 15, which is absent
+/// (and so 0) in entries written before versioning. Decoders for older versions upgrade the
+/// entry to the latest in-memory form, so handlers only ever see that form.
+pub trait Versioned: Message + Default {
+    fn decode_version(version: u32, buf: &[u8]) -> TxnResult<Self>;
+}
+
+pub fn decode_message<M: Message + Default>(buf: &[u8]) -> TxnResult<M> {
+    M::decode(buf).map_err(|e| {
+        CCApplyError::InvalidTransaction(format!("Failed to parse protobuf message : {}", e)).into()
+    })
+}
+
+impl<M: Versioned> MessageExt<M> for M {
     fn try_parse<B: AsRef<[u8]>>(buf: B) -> TxnResult<M> {
-        M::decode(buf.as_ref()).map_err(|e| {
-            CCApplyError::InvalidTransaction(format!("Failed to parse protobuf message : {}", e))
-                .into()
-        })
+        let buf = buf.as_ref();
+        M::decode_version(state_version(buf)?, buf)
     }
 
     fn to_bytes(&self) -> Vec<u8> {
@@ -50,6 +62,57 @@ impl<M: Message + Default> MessageExt<M> for M {
     }
 }
 
+/// Number of hex digits appended to a prefix to split a scan into buckets, each fetched separately.
+/// A page past the end of a prefix fetches every remaining bucket, so a single digit keeps an
+/// empty prefix at 16 fetches while each fetch loads a sixteenth of the prefix.
+const SCAN_BUCKET_DIGITS: usize = 1;
+
+pub trait TransactionContextExt {
+    /// The first `limit` entries under `prefix` with an address after `cursor`, in address order.
+    /// Entries are fetched one bucket of addresses at a time, starting from the cursor's bucket
+    /// and stopping once the page is full, so a page never loads the whole prefix at once.
+    fn scan_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> TxnResult<Vec<(String, Vec<u8>)>>;
+}
+
+impl TransactionContextExt for dyn TransactionContext + '_ {
+    fn scan_prefix(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> TxnResult<Vec<(String, Vec<u8>)>> {
+        let bucket_count = 1usize << (4 * SCAN_BUCKET_DIGITS);
+        let first_bucket = cursor
+            .and_then(|c| c.get(prefix.len()..prefix.len() + SCAN_BUCKET_DIGITS))
+            .and_then(|bucket| usize::from_str_radix(bucket, 16).ok())
+            .unwrap_or(0);
+
+        let mut page = Vec::new();
+        for bucket in first_bucket..bucket_count {
+            let bucket_prefix =
+                format!("{}{:0width$x}", prefix, bucket, width = SCAN_BUCKET_DIGITS);
+            let mut entries = self
+                .get_state_entries_by_prefix(&bucket_prefix)
+                .map_err(CCApplyError::from)?;
+            entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            for entry in entries {
+                if cursor.map_or(true, |cursor| entry.0.as_str() > cursor) {
+                    page.push(entry);
+                    if page.len() == limit {
+                        return Ok(page);
+                    }
+                }
+            }
+        }
+        Ok(page)
+    }
+}
+
 pub trait ErrorExt: Sized {
     type Return;
     fn to_apply_error(self) -> Self::Return;
@@ -1,15 +1,20 @@
 pub mod constants;
 pub mod context;
+pub mod encoding;
+pub mod forks;
+pub mod params;
+pub mod rewards;
 mod tests;
 pub mod types;
 pub mod utils;
 
 use crate::{
     bail_transaction,
-    ext::{ErrorExt, IntegerExt, MessageExt},
+    ext::{ErrorExt, IntegerExt, MessageExt, TransactionContextExt},
     handler::utils::{
-        add_fee, get_integer, get_integer_string, get_signed_integer, get_string, get_u64,
-        last_block,
+        add_fee, add_fee_at, family_version_at_least, get_bool_or_default, get_integer,
+        get_integer_string, get_signed_integer, get_string, get_string_list, get_transfer_list,
+        get_u64, last_block,
     },
     protos, string,
 };
@@ -22,14 +27,20 @@ use context::HandlerContext;
 use context::mocked::MockHandlerContext as HandlerContext;
 
 use constants::*;
-use log::{debug, info};
+use log::{debug, info, warn};
 use rug::{Assign, Integer};
 use sawtooth_sdk::{
     messages::processor::TpProcessRequest,
     processor::handler::{ApplyError, TransactionContext, TransactionHandler},
 };
 
-use std::{convert::TryFrom, default::Default, ops::Deref};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    default::Default,
+    ops::Deref,
+};
 use types::CCApplyError::InvalidTransaction;
 use types::*;
 
@@ -41,12 +52,16 @@ use std::str;
 use crate::protos::{DealOrder, RepaymentOrder, Wallet};
 use prost::Message;
 
+use self::forks::Feature;
+use self::params::ProcessorParams;
+use self::rewards::{ActiveSchedule, RewardSchedule};
 use self::utils::{add_fee_state, add_state, calc_interest, get_state_data, try_get_state_data};
 
 #[enum_dispatch]
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum CCCommand {
     SendFunds,
+    SendFundsBatch,
     RegisterAddress,
     RegisterTransfer,
     AddAskOrder,
@@ -62,6 +77,17 @@ pub enum CCCommand {
     CloseRepaymentOrder,
     CollectCoins,
     Housekeeping,
+    MigrateState,
+    CreateMultisig,
+    ProposeSpend,
+    ApproveSpend,
+    ExecuteSpend,
+    CreateVesting,
+    ClaimVested,
+    Approve,
+    SendFundsFrom,
+    BurnCoins,
+    ConfirmBurn,
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -70,6 +96,11 @@ pub struct SendFunds {
     sighash: SigHash,
 }
 
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct SendFundsBatch {
+    transfers: Vec<(SigHash, Integer)>,
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct RegisterAddress {
     blockchain: String,
@@ -102,6 +133,7 @@ pub struct AddBidOrder {
     maturity: String,
     fee: String,
     expiration: u64,
+    auto_accept: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -171,6 +203,71 @@ pub struct Housekeeping {
     block_idx: Integer,
 }
 
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct MigrateState;
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct CreateMultisig {
+    members: Vec<SigHash>,
+    threshold: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ProposeSpend {
+    multisig: SigHash,
+    sighash: SigHash,
+    amount: Integer,
+    expiration: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ApproveSpend {
+    proposal_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ExecuteSpend {
+    proposal_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct CreateVesting {
+    sighash: SigHash,
+    amount: Integer,
+    cliff: u64,
+    duration: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ClaimVested {
+    vesting_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Approve {
+    spender: SigHash,
+    amount: Integer,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct SendFundsFrom {
+    owner: SigHash,
+    sighash: SigHash,
+    amount: Integer,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct BurnCoins {
+    eth_address: String,
+    amount: Integer,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ConfirmBurn {
+    burn_id: String,
+    blockchain_tx_id: String,
+}
+
 impl TryFrom<Value> for CCCommand {
     type Error = anyhow::Error;
 
@@ -185,6 +282,11 @@ impl TryFrom<Value> for CCCommand {
                     SendFunds { amount, sighash }.into()
                 }
 
+                "SENDFUNDSBATCH" => SendFundsBatch {
+                    transfers: get_transfer_list(&map, "p1", "transfers")?,
+                }
+                .into(),
+
                 "REGISTERADDRESS" => {
                     let blockchain = get_string(&map, "p1", "blockchain")?.to_lowercase();
                     let address = get_string(&map, "p2", "address")?.clone();
@@ -234,6 +336,7 @@ impl TryFrom<Value> for CCCommand {
                     let maturity = get_integer_string(&map, "p4", "maturity")?.clone();
                     let fee = get_integer_string(&map, "p5", "fee")?.to_owned();
                     let expiration = get_u64(&map, "p6", "expiration")?;
+                    let auto_accept = get_bool_or_default(&map, "p7", "autoAccept")?;
                     AddBidOrder {
                         address_id,
                         amount_str,
@@ -241,6 +344,7 @@ impl TryFrom<Value> for CCCommand {
                         maturity,
                         fee,
                         expiration,
+                        auto_accept,
                     }
                     .into()
                 }
@@ -333,15 +437,109 @@ impl TryFrom<Value> for CCCommand {
                 }
                 .into(),
 
-                "COLLECTCOINS" => CollectCoins {
+                "COLLECTCOINS" => {
+                    let eth_address = get_string(&map, "p1", "ethAddress")?.to_lowercase();
+                    #[cfg(not(feature = "dev-bypass-verification"))]
+                    if eth_address == DEV_BYPASS_ETH_ADDRESS {
+                        bail_transaction!(
+                            "Invalid ethAddress",
+                            context = "Verification bypass is only available in development builds"
+                        );
+                    }
+                    CollectCoins {
+                        eth_address,
+                        amount: get_integer(&map, "p2", "amount")?,
+                        blockchain_tx_id: get_string(&map, "p3", "blockchainTxId")?.to_lowercase(),
+                    }
+                    .into()
+                }
+
+                "HOUSEKEEPING" => Housekeeping {
+                    block_idx: get_integer(&map, "p1", "blockIdx")?,
+                }
+                .into(),
+
+                "MIGRATESTATE" => MigrateState.into(),
+
+                "CREATEMULTISIG" => {
+                    let members = get_string_list(&map, "p1", "members")?
+                        .into_iter()
+                        .map(SigHash)
+                        .collect();
+                    let threshold = get_u64(&map, "p2", "threshold")?;
+                    CreateMultisig { members, threshold }.into()
+                }
+
+                "PROPOSESPEND" => {
+                    let multisig = SigHash(get_string(&map, "p1", "multisig")?.clone());
+                    let sighash = SigHash(get_string(&map, "p2", "sighash")?.clone());
+                    let amount = get_integer(&map, "p3", "amount")?;
+                    let expiration = get_u64(&map, "p4", "expiration")?;
+                    ProposeSpend {
+                        multisig,
+                        sighash,
+                        amount,
+                        expiration,
+                    }
+                    .into()
+                }
+
+                "APPROVESPEND" => ApproveSpend {
+                    proposal_id: get_string(&map, "p1", "proposalId")?.to_lowercase(),
+                }
+                .into(),
+
+                "EXECUTESPEND" => ExecuteSpend {
+                    proposal_id: get_string(&map, "p1", "proposalId")?.to_lowercase(),
+                }
+                .into(),
+
+                "CREATEVESTING" => {
+                    let sighash = SigHash(get_string(&map, "p1", "sighash")?.clone());
+                    let amount = get_integer(&map, "p2", "amount")?;
+                    let cliff = get_u64(&map, "p3", "cliff")?;
+                    let duration = get_u64(&map, "p4", "duration")?;
+                    CreateVesting {
+                        sighash,
+                        amount,
+                        cliff,
+                        duration,
+                    }
+                    .into()
+                }
+
+                "CLAIMVESTED" => ClaimVested {
+                    vesting_id: get_string(&map, "p1", "vestingId")?.to_lowercase(),
+                }
+                .into(),
+
+                "APPROVE" => {
+                    let spender = SigHash(get_string(&map, "p1", "spender")?.clone());
+                    let amount = get_integer(&map, "p2", "amount")?;
+                    Approve { spender, amount }.into()
+                }
+
+                "SENDFUNDSFROM" => {
+                    let owner = SigHash(get_string(&map, "p1", "owner")?.clone());
+                    let sighash = SigHash(get_string(&map, "p2", "sighash")?.clone());
+                    let amount = get_integer(&map, "p3", "amount")?;
+                    SendFundsFrom {
+                        owner,
+                        sighash,
+                        amount,
+                    }
+                    .into()
+                }
+
+                "BURNCOINS" => BurnCoins {
                     eth_address: get_string(&map, "p1", "ethAddress")?.to_lowercase(),
                     amount: get_integer(&map, "p2", "amount")?,
-                    blockchain_tx_id: get_string(&map, "p3", "blockchainTxId")?.to_lowercase(),
                 }
                 .into(),
 
-                "HOUSEKEEPING" => Housekeeping {
-                    block_idx: get_integer(&map, "p1", "blockIdx")?,
+                "CONFIRMBURN" => ConfirmBurn {
+                    burn_id: get_string(&map, "p1", "burnId")?.to_lowercase(),
+                    blockchain_tx_id: get_string(&map, "p2", "blockchainTxId")?.to_lowercase(),
                 }
                 .into(),
 
@@ -381,6 +579,415 @@ fn charge(
     Ok((wallet_id, wallet))
 }
 
+fn has_expired(head: &Integer, block: &str, expiration: u64) -> TxnResult<bool> {
+    let start = Integer::try_parse(block)?;
+    let elapsed = Integer::from(head - &start);
+    Ok(expiration < elapsed)
+}
+
+/// Rates are compared exactly from family version 1.8 on; older transactions keep the
+/// truncating comparison so that replays produce the same state.
+fn exact_rates(request: &TpProcessRequest) -> bool {
+    family_version_at_least(request, 1, 8)
+}
+
+fn compare_rates(lhs: &Rate, rhs: &Rate, exact: bool) -> Ordering {
+    if exact {
+        lhs.cmp(rhs)
+    } else {
+        lhs.legacy_cmp(rhs)
+    }
+}
+
+/// Checks the terms of an ask and bid order against each other, following the rules of `AddOffer`.
+fn orders_match(
+    ask_order: &protos::AskOrder,
+    bid_order: &protos::BidOrder,
+    exact: bool,
+) -> TxnResult<bool> {
+    let ask_fee = Integer::try_parse(&ask_order.fee)?;
+    let bid_fee = Integer::try_parse(&bid_order.fee)?;
+
+    let (ask_rate, bid_rate) = match (ask_order.rate(), bid_order.rate()) {
+        (Ok(ask_rate), Ok(bid_rate)) => (ask_rate, bid_rate),
+        _ => return Ok(false),
+    };
+
+    Ok(ask_order.amount == bid_order.amount
+        && ask_fee <= bid_fee
+        && compare_rates(&ask_rate, &bid_rate, exact) != Ordering::Greater)
+}
+
+/// Creates a deal order from an ask order and an auto-accept bid order, charging the fundraiser
+/// like `AddDealOrder` does. Returns `false` without touching state if the deal cannot be made;
+/// deleting the consumed orders is left to the caller.
+#[allow(clippy::too_many_arguments)]
+fn add_auto_deal_order(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &mut HandlerContext,
+    ask_order_id: &str,
+    ask_order: &protos::AskOrder,
+    bid_order_id: &str,
+    bid_order: &protos::BidOrder,
+    blockchain: &str,
+) -> TxnResult<bool> {
+    let offer_id = Address::with_prefix_key(OFFER, &string!(ask_order_id, bid_order_id));
+    let id = Address::with_prefix_key(DEAL_ORDER, &offer_id);
+
+    if try_get_state_data(tx_ctx, &id)?.is_some() {
+        debug!("Deal order {:?} already exists, skipping match", id);
+        return Ok(false);
+    }
+
+    let fundraiser = SigHash(bid_order.sighash.clone());
+    let wallet_id = WalletId::from(&fundraiser);
+    let mut wallet = match try_get_state_data(tx_ctx, &wallet_id)? {
+        Some(state_data) => Wallet::try_parse(&state_data)?,
+        None => {
+            debug!("Fundraiser {:?} has no wallet, skipping match", fundraiser);
+            return Ok(false);
+        }
+    };
+
+    let mut balance = Integer::try_parse(&wallet.amount)?;
+    let fee = Integer::try_parse(&bid_order.fee)? + ctx.tx_fee()?;
+    if balance < fee {
+        debug!(
+            "Fundraiser {:?} cannot cover the total fee amount {}, skipping match",
+            fundraiser, fee
+        );
+        return Ok(false);
+    }
+    balance -= fee;
+
+    wallet.amount = balance.to_string();
+
+    let deal_order = protos::DealOrder {
+        blockchain: blockchain.to_owned(),
+        src_address: ask_order.address.clone(),
+        dst_address: bid_order.address.clone(),
+        amount: bid_order.amount.clone(),
+        interest: bid_order.interest.clone(),
+        maturity: bid_order.maturity.clone(),
+        fee: bid_order.fee.clone(),
+        expiration: bid_order.expiration,
+        block: last_block(request).to_string(),
+        sighash: fundraiser.clone().into(),
+        ..protos::DealOrder::default()
+    };
+
+    let guid = ctx.guid(request);
+
+    let mut states = StateVec::new();
+    add_expiry_index(
+        request,
+        tx_ctx,
+        ctx,
+        &mut states,
+        &id,
+        &deal_order.block,
+        deal_order.expiration,
+    )?;
+    add_state(&mut states, id.into(), &deal_order)?;
+    add_fee_at(
+        ctx,
+        tx_ctx,
+        request,
+        &string!(guid.as_str(), bid_order_id),
+        &fundraiser,
+        &mut states,
+    )?;
+    add_state(&mut states, wallet_id.into(), &wallet)?;
+    tx_ctx.set_state_entries(states)?;
+
+    info!(
+        "Matched ask order {} with auto-accept bid order {}",
+        ask_order_id, bid_order_id
+    );
+
+    Ok(true)
+}
+
+/// The auto-accept index bucket of the bid orders for `amount` on `blockchain` and `network`.
+/// Bids consumed or expired elsewhere stay listed until an ask on their terms prunes them.
+fn auto_accept_bucket_id(amount: &str, blockchain: &str, network: &str) -> Address {
+    Address::with_prefix_key(
+        AUTO_ACCEPT_INDEX,
+        &format!("{}:{}:{}", amount, blockchain, network),
+    )
+}
+
+/// Reads the indexed auto-accept bid order at `id` along with its start block. Returns `None` if
+/// the bid is gone, has expired or cannot be parsed, so one bad bid never fails an ask order.
+fn live_auto_accept_bid(
+    tx_ctx: &dyn TransactionContext,
+    head: &Integer,
+    id: &str,
+) -> TxnResult<Option<(Integer, protos::BidOrder)>> {
+    let state_data = match try_get_state_data(tx_ctx, id)? {
+        Some(state_data) => state_data,
+        None => return Ok(None),
+    };
+    let bid_order = match protos::BidOrder::try_parse(&state_data) {
+        Ok(bid_order) => bid_order,
+        Err(e) => {
+            warn!("Skipping malformed bid order {}: {}", id, e);
+            return Ok(None);
+        }
+    };
+    if !bid_order.auto_accept
+        || has_expired(head, &bid_order.block, bid_order.expiration).unwrap_or(true)
+    {
+        return Ok(None);
+    }
+    Ok(Integer::try_parse(&bid_order.block)
+        .ok()
+        .map(|start| (start, bid_order)))
+}
+
+/// Pairs a new ask order with the oldest compatible auto-accept bid order on its terms, creating
+/// the deal order directly. The pruned index bucket is added to `states`.
+#[allow(clippy::too_many_arguments)]
+fn match_auto_accept_bid(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &mut HandlerContext,
+    states: &mut StateVec,
+    ask_order_id: &str,
+    ask_order: &protos::AskOrder,
+    src_address: &protos::Address,
+) -> TxnResult<bool> {
+    let head = last_block(request);
+    let exact = exact_rates(request);
+
+    let bucket_id = auto_accept_bucket_id(
+        &ask_order.amount,
+        &src_address.blockchain,
+        &src_address.network,
+    );
+    let bucket = match try_get_state_data(tx_ctx, &bucket_id)? {
+        Some(state_data) => protos::AutoAcceptBucket::try_parse(&state_data)?,
+        None => return Ok(false),
+    };
+
+    let mut live = vec![];
+    let mut candidates = vec![];
+    for bid_order_id in &bucket.ids {
+        let (start, bid_order) = match live_auto_accept_bid(tx_ctx, &head, bid_order_id)? {
+            Some(bid) => bid,
+            None => continue,
+        };
+        live.push(bid_order_id.clone());
+        if bid_order.sighash != ask_order.sighash
+            && orders_match(ask_order, &bid_order, exact).unwrap_or(false)
+        {
+            candidates.push((start, bid_order_id.clone(), bid_order));
+        }
+    }
+
+    candidates
+        .sort_by(|(a_start, a_id, _), (b_start, b_id, _)| (a_start, a_id).cmp(&(b_start, b_id)));
+
+    let mut matched = false;
+    let mut deleted = vec![];
+    for (_, bid_order_id, bid_order) in candidates {
+        if add_auto_deal_order(
+            request,
+            tx_ctx,
+            ctx,
+            ask_order_id,
+            ask_order,
+            &bid_order_id,
+            &bid_order,
+            &src_address.blockchain,
+        )? {
+            live.retain(|id| id != &bid_order_id);
+            deleted.push(bid_order_id);
+            matched = true;
+            break;
+        }
+    }
+
+    if live.is_empty() {
+        deleted.push(bucket_id.into());
+    } else if live.len() != bucket.ids.len() {
+        add_state(
+            states,
+            bucket_id.into(),
+            &protos::AutoAcceptBucket { ids: live },
+        )?;
+    }
+    if !deleted.is_empty() {
+        tx_ctx.delete_state_entries(&deleted)?;
+    }
+
+    Ok(matched)
+}
+
+/// Asks that can match a bid, keyed by the terms that must be equal: the amount and the
+/// blockchain and network of the ask's address.
+type AskIndex = BTreeMap<(String, String, String), Vec<(String, protos::AskOrder, Rate, Integer)>>;
+
+/// The cursor of the order matching scan over `prefix`, kept apart from the one housekeeping
+/// expires orders with.
+fn match_cursor(prefix: &str) -> Address {
+    Address::with_prefix_key(HOUSEKEEPING_CURSOR, &format!("match:{}", prefix))
+}
+
+/// Pairs every auto-accept bid order with the best compatible ask order: the lowest rate,
+/// then the oldest order. With a nonzero `limit`, a run only compares the next `limit` bids with
+/// a page of `limit` asks, and the asks move to their next page once every bid has been compared
+/// with the current one.
+fn match_orders(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &mut HandlerContext,
+    limit: u64,
+) -> TxnResult<()> {
+    let head = last_block(request);
+    let exact = exact_rates(request);
+
+    let bid = string!(NAMESPACE_PREFIX, BID_ORDER);
+    let ask = string!(NAMESPACE_PREFIX, ASK_ORDER);
+    let (bid_entries, ask_entries) = if limit == 0 {
+        (
+            tx_ctx.get_state_entries_by_prefix(&bid)?,
+            tx_ctx.get_state_entries_by_prefix(&ask)?,
+        )
+    } else {
+        let bid_cursor_id = match_cursor(&bid);
+        let ask_cursor_id = match_cursor(&ask);
+        let bid_cursor = read_cursor(tx_ctx, &bid_cursor_id)?;
+        let ask_cursor = read_cursor(tx_ctx, &ask_cursor_id)?;
+        let (bid_entries, next_bid) = scan_page(tx_ctx, &bid, limit, bid_cursor.as_deref())?;
+        let (ask_entries, next_ask) = scan_page(tx_ctx, &ask, limit, ask_cursor.as_deref())?;
+        let bids_done = next_bid.is_none();
+        move_cursor(tx_ctx, &bid_cursor_id, bid_cursor.as_deref(), next_bid)?;
+        if bids_done {
+            move_cursor(tx_ctx, &ask_cursor_id, ask_cursor.as_deref(), next_ask)?;
+        }
+        (bid_entries, ask_entries)
+    };
+
+    // orders that fail to parse or resolve are skipped rather than failing housekeeping
+    let mut bids = vec![];
+    for (addr, proto) in &bid_entries {
+        let bid_order = match protos::BidOrder::try_parse(proto) {
+            Ok(bid_order) => bid_order,
+            Err(_) => continue,
+        };
+        if bid_order.auto_accept
+            && !has_expired(&head, &bid_order.block, bid_order.expiration).unwrap_or(true)
+        {
+            if let Ok(start) = Integer::try_parse(&bid_order.block) {
+                bids.push((start, addr.to_owned(), bid_order));
+            }
+        }
+    }
+
+    if bids.is_empty() {
+        return Ok(());
+    }
+
+    bids.sort_by(|(a_start, a_id, _), (b_start, b_id, _)| (a_start, a_id).cmp(&(b_start, b_id)));
+
+    // each ask's address is read once, and a bid is only compared with the asks on its terms
+    let mut asks = AskIndex::new();
+    for (addr, proto) in &ask_entries {
+        let ask_order = match protos::AskOrder::try_parse(proto) {
+            Ok(ask_order) => ask_order,
+            Err(_) => continue,
+        };
+        if has_expired(&head, &ask_order.block, ask_order.expiration).unwrap_or(true) {
+            continue;
+        }
+        let (rate, start) = match (ask_order.rate(), Integer::try_parse(&ask_order.block)) {
+            (Ok(rate), Ok(start)) => (rate, start),
+            _ => continue,
+        };
+        let src_address = match try_get_state_data(tx_ctx, &ask_order.address)? {
+            Some(state_data) => match protos::Address::try_parse(&state_data) {
+                Ok(src_address) => src_address,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        let terms = (
+            ask_order.amount.clone(),
+            src_address.blockchain,
+            src_address.network,
+        );
+        asks.entry(terms)
+            .or_default()
+            .push((addr.to_owned(), ask_order, rate, start));
+    }
+
+    for candidates in asks.values_mut() {
+        candidates.sort_by(|(a_id, ..), (b_id, ..)| a_id.cmp(b_id));
+    }
+
+    for (_, bid_order_id, bid_order) in bids {
+        let dst_address = match try_get_state_data(tx_ctx, &bid_order.address)? {
+            Some(state_data) => match protos::Address::try_parse(&state_data) {
+                Ok(dst_address) => dst_address,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        let terms = (
+            bid_order.amount.clone(),
+            dst_address.blockchain.clone(),
+            dst_address.network.clone(),
+        );
+        let candidates = match asks.get_mut(&terms) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+
+        let mut best: Option<usize> = None;
+        for (idx, (_, ask_order, rate, start)) in candidates.iter().enumerate() {
+            if ask_order.sighash == bid_order.sighash
+                || !orders_match(ask_order, &bid_order, exact).unwrap_or(false)
+            {
+                continue;
+            }
+
+            let is_better = match best {
+                Some(best) => {
+                    let (_, _, best_rate, best_start) = &candidates[best];
+                    compare_rates(rate, best_rate, exact).then_with(|| start.cmp(best_start))
+                        == Ordering::Less
+                }
+                None => true,
+            };
+            if is_better {
+                best = Some(idx);
+            }
+        }
+
+        if let Some(idx) = best {
+            let (ask_order_id, ask_order, ..) = &candidates[idx];
+            if add_auto_deal_order(
+                request,
+                tx_ctx,
+                ctx,
+                ask_order_id,
+                ask_order,
+                &bid_order_id,
+                &bid_order,
+                &dst_address.blockchain,
+            )? {
+                tx_ctx.delete_state_entries(&[ask_order_id.clone(), bid_order_id])?;
+                candidates.remove(idx);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[enum_dispatch(CCCommand)]
 trait CCTransaction: Sized {
     fn execute(
@@ -407,47 +1014,120 @@ impl CCTransaction for SendFunds {
         }
 
         let src_wallet_id = my_sighash.to_wallet_id();
-        let state_data = get_state_data(tx_ctx, &*src_wallet_id)?;
-
-        let mut src_wallet = Wallet::try_parse(&state_data).context(format!(
-            "Failed to parse source wallet at {:?} from state data",
-            src_wallet_id
-        ))?;
         let amount_plus_fee = self.amount.clone() + ctx.tx_fee()?;
-        let mut src_balance = Integer::try_parse(&src_wallet.amount).context(format!(
-            "Failed to parse wallet balance at {:?}, found {:?}",
-            src_wallet_id, &src_wallet.amount
-        ))?;
+        let src_wallet = debit(tx_ctx, &src_wallet_id, &amount_plus_fee)?;
 
-        if src_balance < amount_plus_fee {
-            bail_transaction!(
-                "Insufficient funds",
-                context = "Failed to withdraw funds from source wallet"
-            );
+        let dest_wallet_id = self.sighash.to_wallet_id();
+        let dest_wallet = credit(tx_ctx, &dest_wallet_id, &self.amount)?;
+
+        let mut states: StateVec = StateVec::new();
+        add_state(&mut states, dest_wallet_id.into(), &dest_wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &src_wallet_id,
+            &src_wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+/// Removes `amount` from the wallet at `wallet_id`, failing if the balance doesn't cover it.
+fn debit(
+    tx_ctx: &dyn TransactionContext,
+    wallet_id: &WalletId,
+    amount: &Integer,
+) -> TxnResult<Wallet> {
+    let state_data = get_state_data(tx_ctx, wallet_id)?;
+
+    let mut wallet = Wallet::try_parse(&state_data).context(format!(
+        "Failed to parse source wallet at {:?} from state data",
+        wallet_id
+    ))?;
+    let mut balance = Integer::try_parse(&wallet.amount).context(format!(
+        "Failed to parse wallet balance at {:?}, found {:?}",
+        wallet_id, &wallet.amount
+    ))?;
+
+    if balance < *amount {
+        bail_transaction!(
+            "Insufficient funds",
+            context = "Failed to withdraw funds from source wallet"
+        );
+    }
+
+    balance -= amount;
+    wallet.amount = balance.to_string();
+    Ok(wallet)
+}
+
+/// Adds `amount` to the wallet at `wallet_id`, creating the wallet if it doesn't exist yet.
+fn credit(
+    tx_ctx: &dyn TransactionContext,
+    wallet_id: &WalletId,
+    amount: &Integer,
+) -> TxnResult<Wallet> {
+    let state_data = try_get_state_data(tx_ctx, wallet_id)?;
+    let wallet = match state_data {
+        Some(state_data) => {
+            let mut wallet = Wallet::try_parse(&state_data)?;
+            let mut balance = Integer::try_parse(&wallet.amount)?;
+            balance += amount;
+            wallet.amount = balance.to_string();
+            wallet
         }
+        None => Wallet {
+            amount: amount.to_string(),
+        },
+    };
+    Ok(wallet)
+}
 
-        src_balance -= amount_plus_fee;
-        src_wallet.amount = src_balance.to_string();
+impl CCTransaction for SendFundsBatch {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
 
-        let dest_wallet_id = self.sighash.to_wallet_id();
-        let state_data = try_get_state_data(tx_ctx, &*dest_wallet_id)?;
-        let dest_wallet = match state_data {
-            Some(state_data) => {
-                let mut dest_wallet = Wallet::try_parse(&state_data)?;
-                let mut dest_balance = Integer::try_parse(&dest_wallet.amount)?;
-                dest_balance += self.amount;
-                dest_wallet.amount = dest_balance.to_string();
-                dest_wallet
+        let mut destinations = HashSet::with_capacity(self.transfers.len());
+        let mut total = Integer::new();
+        for (sighash, amount) in &self.transfers {
+            if *sighash == my_sighash {
+                bail_transaction!(
+                    "Invalid destination",
+                    context = "Cannot send funds, the sender and receiver must be different"
+                );
             }
-            None => Wallet {
-                amount: self.amount.to_string(),
-            },
-        };
+            if !destinations.insert(sighash) {
+                bail_transaction!(
+                    "Duplicate destination",
+                    context = "Cannot send funds, {:?} appears more than once in the batch",
+                    sighash
+                );
+            }
+            total += amount;
+        }
+
+        let src_wallet_id = my_sighash.to_wallet_id();
+        let amount_plus_fee = total + ctx.tx_fee()?;
+        let src_wallet = debit(tx_ctx, &src_wallet_id, &amount_plus_fee)?;
 
         let mut states: StateVec = StateVec::new();
-        add_state(&mut states, dest_wallet_id.into(), &dest_wallet)?;
+        for (sighash, amount) in &self.transfers {
+            let dest_wallet_id = sighash.to_wallet_id();
+            let dest_wallet = credit(tx_ctx, &dest_wallet_id, amount)?;
+            add_state(&mut states, dest_wallet_id.into(), &dest_wallet)?;
+        }
         add_fee_state(
             ctx,
+            tx_ctx,
             request,
             &my_sighash,
             &mut states,
@@ -493,7 +1173,7 @@ impl CCTransaction for RegisterAddress {
         let mut states = StateVec::new();
         add_state(&mut states, id.into(), &address)?;
         add_state(&mut states, wallet_id.into(), &wallet)?;
-        add_fee(ctx, request, &my_sighash, &mut states)?;
+        add_fee(ctx, tx_ctx, request, &my_sighash, &mut states)?;
         tx_ctx.set_state_entries(states)?;
         Ok(())
     }
@@ -621,7 +1301,15 @@ impl CCTransaction for RegisterTransfer {
         };
         let mut states = StateVec::new();
         add_state(&mut states, transfer_id.into(), &transfer)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
         tx_ctx.set_state_entries(states)?;
         Ok(())
     }
@@ -642,6 +1330,9 @@ impl CCTransaction for AddAskOrder {
             fee,
             expiration,
         } = self;
+        if exact_rates(request) {
+            Rate::try_parse(&interest, &maturity)?;
+        }
         let my_sighash = ctx.sighash(request)?;
         let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
 
@@ -682,13 +1373,40 @@ impl CCTransaction for AddAskOrder {
         };
 
         let mut states = StateVec::new();
-        add_state(&mut states, id.into(), &ask_order)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
-        tx_ctx.set_state_entries(states)?;
-        Ok(())
-    }
-}
-
+        let matched = if ctx
+            .forks(request)?
+            .is_active(Feature::AutoAccept, request.get_tip())
+        {
+            match_auto_accept_bid(request, tx_ctx, ctx, &mut states, &id, &ask_order, &address)?
+        } else {
+            false
+        };
+        if !matched {
+            add_expiry_index(
+                request,
+                tx_ctx,
+                ctx,
+                &mut states,
+                &id,
+                &ask_order.block,
+                ask_order.expiration,
+            )?;
+            add_state(&mut states, id.into(), &ask_order)?;
+        }
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
 impl CCTransaction for AddBidOrder {
     fn execute(
         self,
@@ -696,6 +1414,9 @@ impl CCTransaction for AddBidOrder {
         tx_ctx: &dyn TransactionContext,
         ctx: &mut HandlerContext,
     ) -> TxnResult<()> {
+        if exact_rates(request) {
+            Rate::try_parse(&self.interest, &self.maturity)?;
+        }
         let my_sighash = ctx.sighash(request)?;
 
         let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
@@ -723,6 +1444,24 @@ impl CCTransaction for AddBidOrder {
             );
         }
 
+        // before the fork an auto-accept flag is ignored, as it was when it was not parsed
+        let auto_accept = self.auto_accept
+            && ctx
+                .forks(request)?
+                .is_active(Feature::AutoAccept, request.get_tip());
+
+        let mut states = StateVec::new();
+        if auto_accept {
+            let bucket_id =
+                auto_accept_bucket_id(&self.amount_str, &address.blockchain, &address.network);
+            let mut bucket = match try_get_state_data(tx_ctx, &bucket_id)? {
+                Some(state_data) => protos::AutoAcceptBucket::try_parse(&state_data)?,
+                None => protos::AutoAcceptBucket::default(),
+            };
+            bucket.ids.push(id.to_string());
+            add_state(&mut states, bucket_id.into(), &bucket)?;
+        }
+
         let bid_order = crate::protos::BidOrder {
             blockchain: address.blockchain,
             address: self.address_id,
@@ -733,11 +1472,28 @@ impl CCTransaction for AddBidOrder {
             expiration: self.expiration,
             block: last_block(request).to_string(),
             sighash: my_sighash.clone().into(),
+            auto_accept,
         };
 
-        let mut states = StateVec::new();
+        add_expiry_index(
+            request,
+            tx_ctx,
+            ctx,
+            &mut states,
+            &id,
+            &bid_order.block,
+            bid_order.expiration,
+        )?;
         add_state(&mut states, id.into(), &bid_order)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
         tx_ctx.set_state_entries(states)?;
         Ok(())
     }
@@ -831,19 +1587,7 @@ impl CCTransaction for AddOffer {
             );
         }
 
-        let ask_fee = Integer::try_parse(&ask_order.fee)?;
-        let bid_fee = Integer::try_parse(&bid_order.fee)?;
-
-        let ask_interest = Integer::try_parse(&ask_order.interest)?;
-        let ask_maturity = Integer::try_parse(&ask_order.maturity)?;
-
-        let bid_interest = Integer::try_parse(&bid_order.interest)?;
-        let bid_maturity = Integer::try_parse(&bid_order.maturity)?;
-
-        if ask_order.amount != bid_order.amount
-            || ask_fee > bid_fee
-            || (ask_interest / ask_maturity) > (bid_interest / bid_maturity)
-        {
+        if !orders_match(&ask_order, &bid_order, exact_rates(request))? {
             bail_transaction!(
                 "The ask and bid orders do not match",
                 context = "Cannot add offer, the parameters of the ask and bid orders are invalid"
@@ -861,8 +1605,25 @@ impl CCTransaction for AddOffer {
 
         let mut states = vec![];
 
+        add_expiry_index(
+            request,
+            tx_ctx,
+            ctx,
+            &mut states,
+            &id,
+            &offer.block,
+            offer.expiration,
+        )?;
         add_state(&mut states, id.into(), &offer)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
         tx_ctx.set_state_entries(states)?;
         Ok(())
     }
@@ -952,9 +1713,19 @@ impl CCTransaction for AddDealOrder {
 
         let mut states = StateVec::new();
 
+        add_expiry_index(
+            request,
+            tx_ctx,
+            ctx,
+            &mut states,
+            &id,
+            &deal_order.block,
+            deal_order.expiration,
+        )?;
         add_state(&mut states, id.into(), &deal_order)?;
         add_fee_state(
             ctx,
+            tx_ctx,
             request,
             &my_sighash,
             &mut states,
@@ -1082,6 +1853,7 @@ impl CCTransaction for CompleteDealOrder {
         add_state(&mut states, self.transfer_id, &transfer)?;
         add_fee_state(
             ctx,
+            tx_ctx,
             request,
             &my_sighash,
             &mut states,
@@ -1137,7 +1909,15 @@ impl CCTransaction for LockDealOrder {
 
         let mut states = StateVec::new();
         add_state(&mut states, self.deal_order_id, &deal_order)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1222,13 +2002,12 @@ impl CCTransaction for CloseDealOrder {
 
         let head = last_block(request);
         let start = Integer::try_parse(&loan_transfer.block)?;
-        let maturity = Integer::try_parse(&deal_order.maturity)?;
+        let rate = deal_order.rate()?;
 
-        let ticks = ((head - start) + &maturity) / maturity;
+        let ticks = rate.ticks(&(head - start));
 
         let deal_amount = Integer::try_parse(&deal_order.amount)?;
-        let deal_interest = Integer::try_parse(&deal_order.interest)?;
-        let amount = calc_interest(&deal_amount, &ticks, &deal_interest);
+        let amount = calc_interest(&deal_amount, &ticks, &rate);
 
         let repay_amount = Integer::try_parse(&repayment_transfer.amount)?;
 
@@ -1242,7 +2021,15 @@ impl CCTransaction for CloseDealOrder {
 
         add_state(&mut states, self.deal_order_id, &deal_order)?;
         add_state(&mut states, self.transfer_id, &repayment_transfer)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1314,7 +2101,15 @@ impl CCTransaction for Exempt {
 
         add_state(&mut states, self.deal_order_id, &deal_order)?;
         add_state(&mut states, self.transfer_id, &transfer)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1432,8 +2227,25 @@ impl CCTransaction for AddRepaymentOrder {
         };
 
         let mut states = vec![];
+        add_expiry_index(
+            request,
+            tx_ctx,
+            ctx,
+            &mut states,
+            &id,
+            &repayment_order.block,
+            repayment_order.expiration,
+        )?;
         add_state(&mut states, id.into(), &repayment_order)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1486,7 +2298,15 @@ impl CCTransaction for CompleteRepaymentOrder {
         let mut states = vec![];
         add_state(&mut states, self.repayment_order_id, &repayment_order)?;
         add_state(&mut states, repayment_order.deal, &deal_order)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1580,7 +2400,15 @@ impl CCTransaction for CloseRepaymentOrder {
         add_state(&mut states, self.repayment_order_id, &repayment_order)?;
         add_state(&mut states, repayment_order.deal, &deal_order)?;
         add_state(&mut states, self.transfer_id, &transfer)?;
-        add_fee_state(ctx, request, &my_sighash, &mut states, &wallet_id, &wallet)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1588,6 +2416,64 @@ impl CCTransaction for CloseRepaymentOrder {
     }
 }
 
+struct Bridge {
+    blockchain: String,
+    network: String,
+    contract: String,
+}
+
+impl Bridge {
+    fn from_settings(ctx: &HandlerContext) -> TxnResult<Self> {
+        let setting = |key: &str, default: &str| -> TxnResult<String> {
+            Ok(ctx.get_setting(key)?.unwrap_or_else(|| default.to_owned()))
+        };
+        Ok(Self {
+            blockchain: setting(BRIDGE_BLOCKCHAIN_KEY, DEFAULT_BRIDGE_BLOCKCHAIN)?,
+            network: setting(BRIDGE_NETWORK_KEY, DEFAULT_BRIDGE_NETWORK)?,
+            contract: setting(BRIDGE_CONTRACT_KEY, DEFAULT_BRIDGE_CONTRACT)?,
+        })
+    }
+}
+
+/// Collection records are full `CollectedCoins` entries once `Feature::CollectedCoinsRecords`
+/// is active; earlier blocks store the bare amount string so that replays produce the same state.
+fn collected_coins_records(request: &TpProcessRequest, ctx: &HandlerContext) -> TxnResult<bool> {
+    let forks = ctx.forks(request)?;
+    Ok(forks.is_active(Feature::CollectedCoinsRecords, request.get_tip()))
+}
+
+fn parse_collected_coins(state_data: &[u8]) -> TxnResult<protos::CollectedCoins> {
+    if !state_data.is_empty() && state_data.iter().all(u8::is_ascii_digit) {
+        // legacy entries only carry the amount
+        let amount = str::from_utf8(state_data).unwrap_or_default();
+        return Ok(protos::CollectedCoins {
+            amount: amount.to_owned(),
+            ..Default::default()
+        });
+    }
+    protos::CollectedCoins::try_parse(state_data)
+}
+
+#[cfg(feature = "dev-bypass-verification")]
+fn bypass_verification(
+    ctx: &HandlerContext,
+    sighash: &SigHash,
+    eth_address: &str,
+) -> TxnResult<bool> {
+    if eth_address == DEV_BYPASS_ETH_ADDRESS {
+        return Ok(true);
+    }
+    let allowlist = ctx
+        .get_setting(DEV_BYPASS_SIGHASHES_KEY)?
+        .unwrap_or_default();
+    Ok(allowlist.split(',').any(|s| s.trim() == sighash.as_str()))
+}
+
+#[cfg(not(feature = "dev-bypass-verification"))]
+fn bypass_verification(_: &HandlerContext, _: &SigHash, _: &str) -> TxnResult<bool> {
+    Ok(false)
+}
+
 impl CCTransaction for CollectCoins {
     fn execute(
         self,
@@ -1595,32 +2481,36 @@ impl CCTransaction for CollectCoins {
         tx_ctx: &dyn TransactionContext,
         ctx: &mut HandlerContext,
     ) -> TxnResult<()> {
+        let bridge = Bridge::from_settings(ctx)?;
+        // keyed by the tx id alone, so moving the bridge can't make a collected tx collectable again
         let id = Address::with_prefix_key(ERC20, &self.blockchain_tx_id);
         let state_data = try_get_state_data(tx_ctx, &id)?;
 
-        if state_data.is_some() {
+        if let Some(state_data) = state_data {
+            let collected = parse_collected_coins(&state_data)?;
             bail_transaction!(
                 "Already collected",
-                context = "There is existing state data at address {:?}, indicating the coins have been collected already",
-                id
+                context = "There is existing state data at address {:?}, indicating the coins have been collected already : {:?}",
+                id,
+                collected
             );
         }
 
         let my_sighash = ctx.sighash(request)?;
 
+        let verify = format!("{} verify", bridge.blockchain);
         let gateway_command = [
-            "ethereum verify",
+            verify.as_str(),
             &self.eth_address,
-            "creditcoin",
+            &bridge.contract,
             my_sighash.as_str(),
             &self.amount.to_string(),
             &self.blockchain_tx_id,
-            "unused",
+            &bridge.network,
         ]
         .join(" ");
 
-        //  FOR DEVELOPMENT, REMOVE FOR DEPLOYMENT
-        if self.eth_address != "unused_if_hacked" {
+        if !bypass_verification(ctx, &my_sighash, &self.eth_address)? {
             ctx.verify(&gateway_command)?;
         }
 
@@ -1645,7 +2535,18 @@ impl CCTransaction for CollectCoins {
 
         let mut states = vec![];
         add_state(&mut states, wallet_id.into(), &wallet)?;
-        states.push((id.into(), self.amount.to_string().as_bytes().to_owned()));
+        if collected_coins_records(request, ctx)? {
+            let collected = protos::CollectedCoins {
+                eth_address: self.eth_address,
+                sighash: my_sighash.into(),
+                amount: self.amount.to_string(),
+                blockchain_tx_id: self.blockchain_tx_id,
+                block: last_block(request).to_string(),
+            };
+            add_state(&mut states, id.into(), &collected)?;
+        } else {
+            states.push((id.into(), self.amount.to_string().as_bytes().to_owned()));
+        }
 
         tx_ctx.set_state_entries(states)?;
 
@@ -1653,71 +2554,108 @@ impl CCTransaction for CollectCoins {
     }
 }
 
+/// The update1 block reward: 28 CTC scaled by (19/20)^period, rounded half up to six decimals.
+/// Period 0 pays nothing, as the original decimal formatting of 1.000000 lost its integer digit.
+fn decayed_reward(period: i32) -> Integer {
+    if period <= 0 || period >= REWARD_DECAY_CUTOFF_PERIOD {
+        return Integer::new();
+    }
+    let period = period as u32;
+    let numerator = Integer::from(Integer::u_pow_u(19, period)) * 2_000_000u32;
+    let denominator = Integer::from(Integer::u_pow_u(20, period));
+    let fraction = (numerator + &denominator) / (denominator * 2u32);
+    fraction * 28u32 * 1_000_000_000_000u64
+}
+
+/// The block reward formula in effect for a housekeeping run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RewardFormula {
+    /// `reward_amount` per block.
+    Flat,
+    /// The update1 formula as deployed. It assigned the decayed reward to a shadowed local, so
+    /// these blocks were never paid.
+    Update1,
+    /// The update1 formula paying `decayed_reward`, from `Feature::RewardFormulaFix` on.
+    Update1Fixed,
+}
+
 fn award(
-    tx_ctx: &dyn TransactionContext,
-    new_formula: bool,
+    rewards: &mut BTreeMap<String, Integer>,
+    params: &ProcessorParams,
+    formula: RewardFormula,
+    schedule: Option<&ActiveSchedule>,
+    minted: &mut Integer,
     block_idx: &Integer,
     signer: &str,
 ) -> TxnResult<()> {
     let mut buf = Integer::new();
     let mut reward = Integer::new();
 
-    if new_formula {
-        let mut reward = Integer::new();
-        buf.assign(block_idx / BLOCKS_IN_PERIOD_UPDATE1);
+    let scheduled = schedule.and_then(|active| {
+        let elapsed = block_idx.to_u64()?.checked_sub(active.activation)?;
+        Some(active.schedule.reward(elapsed, minted))
+    });
+
+    if let Some(scheduled) = scheduled {
+        reward.assign(scheduled);
+    } else if formula != RewardFormula::Flat {
+        buf.assign(block_idx / params.blocks_in_period_update1);
 
         let period = buf.to_i32().ok_or_else(|| {
             InvalidTransaction("Block number is too large to fit in an i32".into())
         })?;
-        let fraction = (19.0f64 / 20.0f64).powi(period);
-        let fraction_str = format!("{:.6}", fraction);
-        let pos = fraction_str.find('.').unwrap();
-        assert!(pos > 0);
-
-        let fraction_in_wei_str = if fraction_str.starts_with('0') {
-            format!("{}{:0<18}", &fraction_str[..pos], &fraction_str[pos + 1..])
-        } else {
-            let mut pos = 2;
-            for c in fraction_str.bytes().skip(pos) {
-                if c == b'0' {
-                    pos += 1;
-                } else {
-                    break;
-                }
-            }
-            format!("{:0<width$}", &fraction_str[pos..], width = 20 - pos)
-        };
-
-        reward.assign(28 * Integer::try_parse(&fraction_in_wei_str)?);
+        if formula == RewardFormula::Update1Fixed {
+            reward.assign(decayed_reward(period));
+        }
     } else {
-        reward.assign(&*REWARD_AMOUNT);
+        reward.assign(&params.reward_amount);
+    }
+    // once a schedule is configured every reward counts towards the supply its cap bounds
+    if schedule.is_some() {
+        *minted += &reward;
     }
 
-    let reward_str = reward.to_string();
+    // the treasury share rounds down, the remainder stays with the signer
+    if let Some(treasury) = &params.treasury_sighash {
+        let share = Integer::from(&reward * params.treasury_share) / BASIS_POINTS;
+        if share > 0 {
+            reward -= &share;
+            let wallet_id = string!(NAMESPACE_PREFIX.as_str(), WALLET, treasury);
+            credit_reward(rewards, wallet_id, share);
+        }
+    }
 
     if reward > 0 {
         let signer_sighash = utils::sha512_id(signer.as_bytes());
         let wallet_id = string!(NAMESPACE_PREFIX.as_str(), WALLET, &signer_sighash);
+        credit_reward(rewards, wallet_id, reward);
+    }
+    Ok(())
+}
+
+fn credit_reward(rewards: &mut BTreeMap<String, Integer>, wallet_id: String, reward: Integer) {
+    *rewards.entry(wallet_id).or_default() += reward;
+}
+
+/// Adds the wallet updates crediting `rewards` to `states`, reading each wallet once.
+fn add_reward_states(
+    tx_ctx: &dyn TransactionContext,
+    states: &mut StateVec,
+    rewards: BTreeMap<String, Integer>,
+) -> TxnResult<()> {
+    for (wallet_id, reward) in rewards {
         info!("checking wallet with id {}", wallet_id);
         let state_data = try_get_state_data(tx_ctx, &wallet_id)?.unwrap_or_default();
-        info!("got state data");
-        let wallet = if state_data.is_empty() {
-            Wallet { amount: reward_str }
+        let balance = if state_data.is_empty() {
+            reward
         } else {
             let wallet = Wallet::try_parse(&state_data)?;
-            let balance = Integer::try_parse(&wallet.amount)? + reward;
-            Wallet {
-                amount: balance.to_string(),
-            }
+            Integer::try_parse(&wallet.amount)? + reward
         };
-
-        let mut buf = Vec::with_capacity(wallet.encoded_len());
-        wallet
-            .encode(&mut buf)
-            .map_err(|e| InvalidTransaction(format!("Failed to add state : {}", e)))?;
-        info!("parsed proto");
-        tx_ctx.set_state_entry(wallet_id, buf)?;
-        info!("set entry");
+        let wallet = Wallet {
+            amount: balance.to_string(),
+        };
+        add_state(states, wallet_id, &wallet)?;
     }
     Ok(())
 }
@@ -1726,26 +2664,53 @@ fn reward(
     request: &TpProcessRequest,
     tx_ctx: &dyn TransactionContext,
     ctx: &mut HandlerContext,
+    params: &ProcessorParams,
     processed_block_idx: &Integer,
     up_to_block_idx: &Integer,
 ) -> TxnResult<()> {
     info!("rewarding!");
     assert!(up_to_block_idx == &0 || up_to_block_idx > processed_block_idx);
 
-    let mut new_formula = false;
-
+    let height = processed_block_idx
+        .to_u64()
+        .ok_or_else(|| InvalidTransaction("Block number is too large to fit in a u64".into()))?;
     // TODO: transitioning
-    let s = ctx.get_setting("sawtooth.validator.update1")?;
-    if let Some(val) = s {
-        let update_block = Integer::try_parse(&*val)?;
-        if update_block + 500 < *processed_block_idx {
-            new_formula = true;
+    let forks = ctx.forks(request)?;
+    let formula = if !forks.is_active(Feature::RewardFormulaUpdate1, height) {
+        RewardFormula::Flat
+    } else if forks.is_active(Feature::RewardFormulaFix, height) {
+        RewardFormula::Update1Fixed
+    } else {
+        RewardFormula::Update1
+    };
+
+    let schedule = RewardSchedule::resolve(|key| ctx.get_setting(key))?;
+    let supply = Address::with_prefix_key(SUPPLY, MINTED_SUPPLY_KEY);
+    let mut minted = Integer::new();
+    if schedule.is_some() {
+        match try_get_state_data(tx_ctx, &supply)? {
+            Some(state_data) => {
+                minted = str::from_utf8(&state_data)
+                    .ok()
+                    .and_then(|s| Integer::from_str_radix(s, 10).ok())
+                    .ok_or_else(|| {
+                        InvalidTransaction(format!("Invalid minted supply at {:?}", supply))
+                    })?;
+            }
+            // the first scheduled reward counts from the supply issued before any schedule
+            None => {
+                if let Some(initial) = ctx.get_setting(INITIAL_SUPPLY_KEY)? {
+                    minted = Integer::try_parse(initial.trim())?;
+                }
+            }
         }
     }
+    let minted_before = minted.clone();
+    let mut rewards = BTreeMap::new();
 
     let mut last_block_idx = Integer::new();
     if *up_to_block_idx == 0 {
-        last_block_idx.assign(processed_block_idx + BLOCK_REWARD_PROCESSING_COUNT)
+        last_block_idx.assign(processed_block_idx + params.block_reward_processing_count)
     } else {
         last_block_idx.assign(up_to_block_idx)
     }
@@ -1764,7 +2729,15 @@ fn reward(
 
             info!("rewarding signer {} for block {}", signer, height);
 
-            award(tx_ctx, new_formula, &i, &signer)?;
+            award(
+                &mut rewards,
+                params,
+                formula,
+                schedule.as_ref(),
+                &mut minted,
+                &i,
+                &signer,
+            )?;
             i += 1;
         }
     } else {
@@ -1781,190 +2754,1262 @@ fn reward(
 
         info!("Rewarding {} signatures", signatures.len());
 
-        let mut i = last_block_idx;
-        for signature in &signatures {
-            award(tx_ctx, new_formula, &i, signature)?;
-            i -= 1;
+        // the signatures run from the last block down, award them in block order like the range
+        // above so a capped schedule pays the same blocks either way
+        let mut i = Integer::from(&last_block_idx - signatures.len() as u64);
+        for signature in signatures.iter().rev() {
+            i += 1;
+            award(
+                &mut rewards,
+                params,
+                formula,
+                schedule.as_ref(),
+                &mut minted,
+                &i,
+                signature,
+            )?;
         }
     }
 
+    let mut states = StateVec::new();
+    add_reward_states(tx_ctx, &mut states, rewards)?;
+    if minted != minted_before {
+        states.push((supply.into(), minted.to_string().into_bytes()));
+    }
+    if !states.is_empty() {
+        tx_ctx.set_state_entries(states)?;
+    }
+
     Ok(())
 }
 
-fn filter(
-    tx_ctx: &dyn TransactionContext,
-    prefix: &str,
-    mut lister: impl FnMut(&str, &[u8]) -> TxnResult<()>,
-) -> TxnResult<()> {
-    // TODO: Transitioning
+fn expiry_bucket_id(height: u64) -> Address {
+    Address::with_prefix_key(EXPIRY_INDEX, &height.to_string())
+}
 
-    let states = tx_ctx.get_state_entries_by_prefix(prefix)?;
-    for (address, data) in states {
-        lister(&address, &data)?;
+/// The last height whose expiry index bucket housekeeping has processed, absent until the index
+/// has been filled with the orders that predate it.
+fn expiry_cursor(tx_ctx: &dyn TransactionContext) -> TxnResult<Option<u64>> {
+    let cursor_id = Address::with_prefix_key(EXPIRY_INDEX, EXPIRY_CURSOR_KEY);
+    match try_get_state_data(tx_ctx, &cursor_id)? {
+        Some(state_data) => {
+            let cursor = str::from_utf8(&state_data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| {
+                    InvalidTransaction(format!("Invalid expiry index cursor at {:?}", cursor_id))
+                })?;
+            Ok(Some(cursor))
+        }
+        None => Ok(None),
     }
+}
 
-    Ok(())
+/// The first housekeeping block index at which an order created at `block` has expired.
+fn expiry_height(block: &str, expiration: u64) -> TxnResult<Option<u64>> {
+    let start = Integer::try_parse(block)?;
+    Ok((start + expiration + 1u32).to_u64())
 }
 
-impl CCTransaction for Housekeeping {
-    fn execute(
-        self,
-        request: &TpProcessRequest,
-        tx_ctx: &dyn TransactionContext,
-        ctx: &mut HandlerContext,
-    ) -> TxnResult<()> {
-        let Housekeeping { block_idx } = self;
+/// Adds `ids` to the expiry index bucket at `height`.
+fn add_expiry_bucket_state(
+    tx_ctx: &dyn TransactionContext,
+    states: &mut StateVec,
+    height: u64,
+    ids: impl IntoIterator<Item = String>,
+) -> TxnResult<()> {
+    let bucket_id = expiry_bucket_id(height);
+    let mut bucket = match try_get_state_data(tx_ctx, &bucket_id)? {
+        Some(state_data) => protos::ExpiryBucket::try_parse(&state_data)?,
+        None => protos::ExpiryBucket::default(),
+    };
+    bucket.ids.extend(ids);
+    bucket.ids.sort();
+    bucket.ids.dedup();
+    add_state(states, bucket_id.into(), &bucket)
+}
 
-        let processed_block_idx = string!(
-            NAMESPACE_PREFIX.as_str(),
-            PROCESSED_BLOCK,
-            PROCESSED_BLOCK_ID,
-        );
-        let state_data = try_get_state_data(tx_ctx, &processed_block_idx)?.unwrap_or_default();
-        let mut last_processed_block_idx = Integer::new();
+/// Records the order at `id` in the expiry index, once the index is active.
+fn add_expiry_index(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &HandlerContext,
+    states: &mut StateVec,
+    id: &str,
+    block: &str,
+    expiration: u64,
+) -> TxnResult<()> {
+    let active = match Integer::try_parse(block)?.to_u64() {
+        Some(created) => ctx.forks(request)?.is_active(Feature::ExpiryIndex, created),
+        None => false,
+    };
+    if !active {
+        return Ok(());
+    }
+    let height = match expiry_height(block, expiration)? {
+        Some(height) => height,
+        None => return Ok(()),
+    };
+    // a bucket housekeeping already processed would never be read again
+    let height = match expiry_cursor(tx_ctx)? {
+        Some(swept) => height.max(swept + 1),
+        None => height,
+    };
+    add_expiry_bucket_state(tx_ctx, states, height, Some(id.to_owned()))
+}
 
-        if !state_data.is_empty() {
-            last_processed_block_idx.assign(Integer::try_parse(
-                str::from_utf8(&state_data).map_err(|e| {
-                    InvalidTransaction(format!("State data is not valid UTF-8 : {}", e))
-                })?,
-            )?);
+/// Deletes the order at `addr` if it has expired by `block_idx`, refunding the fee of an unfunded
+/// deal order. Returns the height the order expires at if it can still expire later.
+fn expire_order(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &HandlerContext,
+    block_idx: &Integer,
+    addr: &str,
+    proto: &[u8],
+) -> TxnResult<Option<u64>> {
+    let prefix_start = NAMESPACE_PREFIX.len();
+    let prefix = addr.get(prefix_start..prefix_start + 4).unwrap_or_default();
+    let mut deal_order = None;
+    let (block, expiration, pending) = match prefix {
+        ASK_ORDER => {
+            let ask_order = protos::AskOrder::try_parse(proto)?;
+            (ask_order.block, ask_order.expiration, true)
         }
-
-        if block_idx == 0 {
-            let head = last_block(request);
-
-            if last_processed_block_idx.clone()
-                + CONFIRMATION_COUNT * 2
-                + BLOCK_REWARD_PROCESSING_COUNT
-                < head
-            {
-                reward(
-                    request,
-                    tx_ctx,
-                    ctx,
-                    &last_processed_block_idx,
-                    &Integer::new(),
-                )?;
-                tx_ctx.set_state_entry(
-                    processed_block_idx,
-                    (last_processed_block_idx + BLOCK_REWARD_PROCESSING_COUNT)
-                        .to_string()
-                        .into_bytes(),
-                )?;
-            }
-            return Ok(());
+        BID_ORDER => {
+            let bid_order = protos::BidOrder::try_parse(proto)?;
+            (bid_order.block, bid_order.expiration, true)
         }
-
-        if block_idx < CONFIRMATION_COUNT * 2 || block_idx <= last_processed_block_idx {
-            return Ok(());
+        OFFER => {
+            let offer = protos::Offer::try_parse(proto)?;
+            (offer.block, offer.expiration, true)
+        }
+        DEAL_ORDER => {
+            let order = protos::DealOrder::try_parse(proto)?;
+            let pending = order.loan_transfer.is_empty();
+            let expiry = (order.block.clone(), order.expiration, pending);
+            deal_order = Some(order);
+            expiry
+        }
+        REPAYMENT_ORDER => {
+            let repayment_order = protos::RepaymentOrder::try_parse(proto)?;
+            let pending = repayment_order.previous_owner.is_empty();
+            (repayment_order.block, repayment_order.expiration, pending)
+        }
+        _ => {
+            bail_transaction!(
+                "Invalid order",
+                context = "The entry at {:?} is not an order that expires",
+                addr
+            );
         }
+    };
 
-        let tip = last_block(request);
+    let start = Integer::try_parse(&block)?;
+    let elapsed = Integer::from(block_idx - &start);
+    if expiration >= elapsed {
+        return if pending {
+            expiry_height(&block, expiration)
+        } else {
+            Ok(None)
+        };
+    }
+    if !pending {
+        return Ok(None);
+    }
 
-        if block_idx >= tip - CONFIRMATION_COUNT {
-            info!("Premature processing");
-            return Ok(());
-        }
+    if let Some(deal_order) = deal_order {
+        // an unknown tip is treated as the latest block
+        let tip = ctx.tip();
+        if tip == 0
+            || ctx
+                .forks(request)?
+                .is_active(Feature::DealExpirationRefund, tip)
+        {
+            let wallet_id = string!(NAMESPACE_PREFIX, WALLET, &deal_order.sighash);
+            let state_data = get_state_data(tx_ctx, &wallet_id)?;
+            let mut wallet = protos::Wallet::try_parse(&state_data)?;
+            let mut balance = Integer::try_parse(&wallet.amount)?;
+            balance += Integer::try_parse(&deal_order.fee)?;
+            wallet.amount = balance.to_string();
 
-        let mut elapsed_buf = Integer::new();
+            let mut states = vec![];
+            add_state(&mut states, wallet_id, &wallet)?;
+            tx_ctx.set_state_entries(states)?;
+        }
+    }
+    tx_ctx.delete_state_entry(addr)?;
+    Ok(None)
+}
 
-        let ask = string!(NAMESPACE_PREFIX, ASK_ORDER);
-        filter(tx_ctx, &ask, |addr, proto| {
-            let ask_order = protos::AskOrder::try_parse(proto)?;
-            let start = Integer::try_parse(&ask_order.block)?;
+/// Expires the orders in the expiry index buckets after `swept` up to `block_idx`.
+fn expire_indexed(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    ctx: &HandlerContext,
+    swept: u64,
+    block_idx: &Integer,
+) -> TxnResult<()> {
+    let height = block_idx
+        .to_u64()
+        .ok_or_else(|| InvalidTransaction("Block number is too large to fit in a u64".into()))?;
+    for bucket_height in swept + 1..=height {
+        let bucket_id = expiry_bucket_id(bucket_height);
+        let bucket = match try_get_state_data(tx_ctx, &bucket_id)? {
+            Some(state_data) => protos::ExpiryBucket::try_parse(&state_data)?,
+            None => continue,
+        };
+        for id in &bucket.ids {
+            // filled or cancelled orders are already gone
+            if let Some(state_data) = try_get_state_data(tx_ctx, id)? {
+                expire_order(request, tx_ctx, ctx, block_idx, id, &state_data)?;
+            }
+        }
+        tx_ctx.delete_state_entry(&bucket_id)?;
+    }
+
+    let cursor_id = Address::with_prefix_key(EXPIRY_INDEX, EXPIRY_CURSOR_KEY);
+    tx_ctx.set_state_entry(cursor_id.into(), height.max(swept).to_string().into_bytes())?;
+    Ok(())
+}
+
+/// Refunds the fee aggregate at `addr` to its payer and deletes it, once the last fee in it is
+/// more than `year_of_blocks` old at `block_idx`.
+fn refund_fee_aggregate(
+    tx_ctx: &dyn TransactionContext,
+    year_of_blocks: u64,
+    block_idx: &Integer,
+    addr: &str,
+    proto: &[u8],
+) -> TxnResult<()> {
+    let aggregate = protos::FeeAggregate::try_parse(proto)?;
+    let last = if aggregate.last_block.is_empty() {
+        &aggregate.block
+    } else {
+        &aggregate.last_block
+    };
+    let elapsed = block_idx - Integer::try_parse(last)?;
+    if elapsed <= year_of_blocks {
+        return Ok(());
+    }
+
+    let wallet_id = string!(NAMESPACE_PREFIX, WALLET, &aggregate.sighash);
+    let state_data = get_state_data(tx_ctx, &wallet_id)?;
+    let mut wallet = protos::Wallet::try_parse(&state_data)?;
+    let total = Integer::try_parse(&aggregate.total)?;
+    wallet.amount = (Integer::try_parse(&wallet.amount)? + total).to_string();
+
+    let mut states = StateVec::new();
+    add_state(&mut states, wallet_id, &wallet)?;
+    tx_ctx.set_state_entries(states)?;
+    tx_ctx.delete_state_entry(addr)?;
+    Ok(())
+}
+
+/// Passes the entries under `prefix` to `lister`. With a nonzero `limit`, only the next `limit`
+/// entries are passed, resuming from a cursor kept in state across runs.
+fn filter(
+    tx_ctx: &dyn TransactionContext,
+    prefix: &str,
+    limit: u64,
+    lister: impl FnMut(&str, &[u8]) -> TxnResult<()>,
+) -> TxnResult<()> {
+    // TODO: Transitioning
+    let cursor_id = Address::with_prefix_key(HOUSEKEEPING_CURSOR, prefix);
+    scan(tx_ctx, prefix, limit, &cursor_id, lister)
+}
+
+/// Like `filter`, keeping the resume cursor at `cursor_id`.
+fn scan(
+    tx_ctx: &dyn TransactionContext,
+    prefix: &str,
+    limit: u64,
+    cursor_id: &Address,
+    mut lister: impl FnMut(&str, &[u8]) -> TxnResult<()>,
+) -> TxnResult<()> {
+    if limit == 0 {
+        let states = tx_ctx.get_state_entries_by_prefix(prefix)?;
+        for (address, data) in states {
+            lister(&address, &data)?;
+        }
+        return Ok(());
+    }
+
+    let cursor = read_cursor(tx_ctx, cursor_id)?;
+    let (states, next) = scan_page(tx_ctx, prefix, limit, cursor.as_deref())?;
+    for (address, data) in &states {
+        lister(address, data)?;
+    }
+    move_cursor(tx_ctx, cursor_id, cursor.as_deref(), next)
+}
+
+fn read_cursor(tx_ctx: &dyn TransactionContext, cursor_id: &Address) -> TxnResult<Option<String>> {
+    match try_get_state_data(tx_ctx, cursor_id)? {
+        Some(state_data) => Ok(Some(String::from_utf8(state_data.0).map_err(|_| {
+            InvalidTransaction(format!("Invalid housekeeping cursor at {:?}", cursor_id))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// The next `limit` entries under `prefix` after `cursor`, and the cursor to resume from.
+/// A short page means the scan reached the end of the prefix, so the next run starts over.
+fn scan_page(
+    tx_ctx: &dyn TransactionContext,
+    prefix: &str,
+    limit: u64,
+    cursor: Option<&str>,
+) -> TxnResult<(Vec<(String, Vec<u8>)>, Option<String>)> {
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    let states = tx_ctx.scan_prefix(prefix, cursor, limit)?;
+    let next = match states.last() {
+        Some((address, _)) if states.len() == limit => Some(address.clone()),
+        _ => None,
+    };
+    Ok((states, next))
+}
+
+fn move_cursor(
+    tx_ctx: &dyn TransactionContext,
+    cursor_id: &Address,
+    cursor: Option<&str>,
+    next: Option<String>,
+) -> TxnResult<()> {
+    match next {
+        Some(next) => {
+            tx_ctx.set_state_entry(cursor_id.to_string(), next.into_bytes())?;
+        }
+        None if cursor.is_some() => {
+            tx_ctx.delete_state_entry(cursor_id)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+impl CCTransaction for Housekeeping {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let Housekeeping { block_idx } = self;
+        let params = ctx.params(request)?.clone();
+
+        let processed_block_idx = string!(
+            NAMESPACE_PREFIX.as_str(),
+            PROCESSED_BLOCK,
+            PROCESSED_BLOCK_ID,
+        );
+        let state_data = try_get_state_data(tx_ctx, &processed_block_idx)?.unwrap_or_default();
+        let mut last_processed_block_idx = Integer::new();
+
+        if !state_data.is_empty() {
+            last_processed_block_idx.assign(Integer::try_parse(
+                str::from_utf8(&state_data).map_err(|e| {
+                    InvalidTransaction(format!("State data is not valid UTF-8 : {}", e))
+                })?,
+            )?);
+        }
+
+        if block_idx == 0 {
+            let head = last_block(request);
+
+            if last_processed_block_idx.clone()
+                + params.confirmation_count * 2
+                + params.block_reward_processing_count
+                < head
+            {
+                reward(
+                    request,
+                    tx_ctx,
+                    ctx,
+                    &params,
+                    &last_processed_block_idx,
+                    &Integer::new(),
+                )?;
+                tx_ctx.set_state_entry(
+                    processed_block_idx,
+                    (last_processed_block_idx + params.block_reward_processing_count)
+                        .to_string()
+                        .into_bytes(),
+                )?;
+            }
+            return Ok(());
+        }
+
+        if block_idx < params.confirmation_count * 2 || block_idx <= last_processed_block_idx {
+            return Ok(());
+        }
+
+        let tip = last_block(request);
+
+        if block_idx >= tip - params.confirmation_count {
+            info!("Premature processing");
+            return Ok(());
+        }
+
+        let height = block_idx.to_u64().ok_or_else(|| {
+            InvalidTransaction("Block number is too large to fit in a u64".into())
+        })?;
+
+        if ctx.forks(request)?.is_active(Feature::AutoAccept, height) {
+            match_orders(request, tx_ctx, ctx, params.housekeeping_scan_limit)?;
+        }
+        let indexed = ctx.forks(request)?.is_active(Feature::ExpiryIndex, height);
+        let swept = if indexed {
+            expiry_cursor(tx_ctx)?
+        } else {
+            None
+        };
+
+        if let Some(swept) = swept {
+            expire_indexed(request, tx_ctx, ctx, swept, &block_idx)?;
+        } else {
+            // the first indexed run scans every order once to index the ones that predate the index
+            let limit = if indexed {
+                0
+            } else {
+                params.housekeeping_scan_limit
+            };
+            let mut pending = BTreeMap::<u64, Vec<String>>::new();
+            for order_prefix in &[ASK_ORDER, BID_ORDER, OFFER, DEAL_ORDER, REPAYMENT_ORDER] {
+                let orders = string!(NAMESPACE_PREFIX, order_prefix);
+                filter(tx_ctx, &orders, limit, |addr, proto| {
+                    let expiry = expire_order(request, tx_ctx, ctx, &block_idx, addr, proto)?;
+                    match expiry {
+                        Some(expiry) if indexed => {
+                            pending.entry(expiry).or_default().push(addr.to_owned())
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+            }
+
+            if indexed {
+                let mut states = StateVec::new();
+                for (expiry, ids) in pending {
+                    add_expiry_bucket_state(tx_ctx, &mut states, expiry, ids)?;
+                }
+                let cursor_id = Address::with_prefix_key(EXPIRY_INDEX, EXPIRY_CURSOR_KEY);
+                states.push((cursor_id.into(), height.to_string().into_bytes()));
+                tx_ctx.set_state_entries(states)?;
+            }
+        }
+
+        let limit = params.housekeeping_scan_limit;
+        let mut elapsed_buf = Integer::new();
+
+        let proposals = string!(NAMESPACE_PREFIX, SPEND_PROPOSAL);
+        filter(tx_ctx, &proposals, limit, |addr, proto| {
+            let proposal = protos::SpendProposal::try_parse(proto)?;
+            let start = Integer::try_parse(&proposal.block)?;
             elapsed_buf.assign(&block_idx - &start);
-            if ask_order.expiration < elapsed_buf {
+            if proposal.expiration < elapsed_buf {
+                tx_ctx.delete_state_entry(addr)?;
+            }
+            Ok(())
+        })?;
+
+        let fee = string!(NAMESPACE_PREFIX, FEE);
+        filter(tx_ctx, &fee, limit, |addr, proto| {
+            let fee = protos::FeeV2::try_parse(proto)?;
+            elapsed_buf.assign(&block_idx - fee.block);
+
+            if elapsed_buf > params.year_of_blocks {
+                let wallet_id = string!(NAMESPACE_PREFIX, WALLET, &fee.sighash);
+                let state_data = get_state_data(tx_ctx, &wallet_id)?;
+                let mut wallet = protos::Wallet::try_parse(&state_data)?;
+                let paid = match &fee.paid {
+                    Some(protos::fee_v2::Paid::Amount(amount)) => encoding::decode_integer(amount)?,
+                    None => ctx.tx_fee()?.clone(),
+                };
+                wallet.amount = (Integer::try_parse(&wallet.amount)? + paid).to_string();
+
+                let mut state_data = state_data.0;
+                state_data.clear();
+                state_data.reserve(wallet.encoded_len());
+                wallet
+                    .encode(&mut state_data)
+                    .map_err(|e| InvalidTransaction(format!("Failed to encode wallet : {}", e)))?;
+
+                tx_ctx.set_state_entry(wallet_id, state_data)?;
                 tx_ctx.delete_state_entry(addr)?;
             }
             Ok(())
         })?;
 
-        let bid = string!(NAMESPACE_PREFIX, BID_ORDER);
-        filter(tx_ctx, &bid, |addr, proto| {
-            let bid_order = protos::BidOrder::try_parse(proto)?;
-            let start = Integer::try_parse(&bid_order.block)?;
-            elapsed_buf.assign(&block_idx - &start);
-            if bid_order.expiration < elapsed_buf {
-                tx_ctx.delete_state_entry(addr)?;
-            }
-            Ok(())
-        })?;
+        let fee_aggregates = string!(NAMESPACE_PREFIX, FEE_AGGREGATE);
+        filter(tx_ctx, &fee_aggregates, limit, |addr, proto| {
+            refund_fee_aggregate(tx_ctx, params.year_of_blocks, &block_idx, addr, proto)
+        })?;
+
+        reward(
+            request,
+            tx_ctx,
+            ctx,
+            &params,
+            &last_processed_block_idx,
+            &block_idx,
+        )?;
+        tx_ctx.set_state_entry(processed_block_idx, block_idx.to_string().into_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl CCTransaction for MigrateState {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        if !ctx
+            .forks(request)?
+            .is_active(Feature::StateV2, request.get_tip())
+        {
+            bail_transaction!(
+                "State migration is not active",
+                context = "The v2 state encoding is not active at block {}",
+                request.get_tip()
+            );
+        }
+
+        let my_sighash = ctx.sighash(request)?;
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        // every run migrates the next batch of each prefix, resuming where the last run stopped
+        let mut states = StateVec::new();
+        for &prefix in &encoding::MIGRATED_PREFIXES {
+            let prefix_address = string!(NAMESPACE_PREFIX, prefix);
+            let cursor_id = Address::with_prefix_key(MIGRATION, prefix);
+            scan(
+                tx_ctx,
+                &prefix_address,
+                MIGRATION_BATCH_SIZE,
+                &cursor_id,
+                |addr, proto| {
+                    // the submitter's wallet is rewritten below with the fee deducted
+                    if addr == wallet_id.as_str() {
+                        return Ok(());
+                    }
+                    if let Some(migrated) = encoding::migrate_entry(prefix, addr, proto) {
+                        states.push((addr.to_owned(), migrated));
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        info!("Migrated {} state entries to v2", states.len());
+
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+fn multisig_id(sighash: &SigHash) -> String {
+    string!(NAMESPACE_PREFIX, MULTISIG, sighash)
+}
+
+fn get_multisig(tx_ctx: &dyn TransactionContext, sighash: &SigHash) -> TxnResult<protos::Multisig> {
+    let state_data = match try_get_state_data(tx_ctx, multisig_id(sighash))? {
+        Some(state_data) => state_data,
+        None => {
+            bail_transaction!(
+                "The multisig wallet doesn't exist",
+                context = "No multisig wallet was found for {:?}",
+                sighash
+            );
+        }
+    };
+    protos::Multisig::try_parse(&state_data)
+}
+
+fn check_member(
+    multisig: &protos::Multisig,
+    multisig_sighash: &str,
+    sighash: &SigHash,
+) -> TxnResult<()> {
+    if !multisig.members.contains(sighash.deref()) {
+        bail_transaction!(
+            "The party is not a member of the multisig wallet",
+            context = "{:?} is not a member of the multisig wallet {:?}",
+            sighash,
+            multisig_sighash
+        );
+    }
+    Ok(())
+}
+
+/// Loads a spend proposal along with its multisig wallet, checking that the party is a member
+/// and that the proposal is still open.
+fn get_open_proposal(
+    request: &TpProcessRequest,
+    tx_ctx: &dyn TransactionContext,
+    proposal_id: &str,
+    my_sighash: &SigHash,
+) -> TxnResult<(protos::SpendProposal, protos::Multisig)> {
+    let state_data = get_state_data(tx_ctx, proposal_id)?;
+    let proposal = protos::SpendProposal::try_parse(&state_data)?;
+    let multisig = get_multisig(tx_ctx, &SigHash(proposal.multisig.clone()))?;
+    check_member(&multisig, &proposal.multisig, my_sighash)?;
+
+    if has_expired(&last_block(request), &proposal.block, proposal.expiration)? {
+        bail_transaction!(
+            "The proposal has expired",
+            context = "Cannot use the spend proposal {:?}",
+            proposal_id
+        );
+    }
+
+    Ok((proposal, multisig))
+}
+
+impl CCTransaction for CreateMultisig {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let CreateMultisig { members, threshold } = self;
+        let my_sighash = ctx.sighash(request)?;
+
+        if threshold == 0 || threshold > members.len() as u64 {
+            bail_transaction!(
+                "Invalid threshold",
+                context =
+                    "The threshold must be between 1 and the number of members ({}), found {}",
+                { members.len() },
+                threshold
+            );
+        }
+        let mut unique = HashSet::with_capacity(members.len());
+        if let Some(member) = members.iter().find(|&member| !unique.insert(member)) {
+            bail_transaction!(
+                "Duplicate member",
+                context = "{:?} is listed more than once",
+                member
+            );
+        }
+
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        // derived from the transaction nonce, so there is no key that can sign for the multisig
+        let guid = ctx.guid(request);
+        let multisig_sighash = SigHash(utils::sha512_id(string!(MULTISIG, guid.as_str())));
+        let id = multisig_id(&multisig_sighash);
+        if try_get_state_data(tx_ctx, &id)?.is_some() {
+            bail_transaction!(
+                "Duplicate id",
+                context = "There is existing state data at address {:?}",
+                id
+            );
+        }
+
+        let multisig = protos::Multisig {
+            members: members.into_iter().map(Into::into).collect(),
+            threshold,
+            block: last_block(request).to_string(),
+            sighash: my_sighash.clone().into(),
+        };
+
+        let mut states = StateVec::new();
+        add_state(&mut states, id, &multisig)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+impl CCTransaction for ProposeSpend {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+        if self.sighash == self.multisig {
+            bail_transaction!(
+                "Invalid destination",
+                context =
+                    "Cannot propose a spend, the multisig wallet and receiver must be different"
+            );
+        }
+
+        let multisig = get_multisig(tx_ctx, &self.multisig)?;
+        check_member(&multisig, &self.multisig, &my_sighash)?;
+
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        let guid = ctx.guid(request);
+        let id = Address::with_prefix_key(SPEND_PROPOSAL, guid.as_str());
+        if try_get_state_data(tx_ctx, &id)?.is_some() {
+            bail_transaction!(
+                "Duplicate id",
+                context = "There is existing state data at address {:?}",
+                id
+            );
+        }
+
+        let proposal = protos::SpendProposal {
+            multisig: self.multisig.into(),
+            sighash: self.sighash.into(),
+            amount: self.amount.to_string(),
+            approvals: vec![my_sighash.clone().into()],
+            expiration: self.expiration,
+            block: last_block(request).to_string(),
+        };
+
+        let mut states = StateVec::new();
+        add_state(&mut states, id.into(), &proposal)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+impl CCTransaction for ApproveSpend {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+        let (mut proposal, _) = get_open_proposal(request, tx_ctx, &self.proposal_id, &my_sighash)?;
+
+        if proposal.approvals.contains(my_sighash.deref()) {
+            bail_transaction!(
+                "The spend was already approved by the party",
+                context = "{:?} has already approved the spend proposal {:?}",
+                my_sighash,
+                { self.proposal_id }
+            );
+        }
+
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+        proposal.approvals.push(my_sighash.clone().into());
+
+        let mut states = StateVec::new();
+        add_state(&mut states, self.proposal_id, &proposal)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+impl CCTransaction for ExecuteSpend {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+        let (proposal, multisig) =
+            get_open_proposal(request, tx_ctx, &self.proposal_id, &my_sighash)?;
+
+        let approvals = proposal.approvals.len() as u64;
+        if approvals < multisig.threshold {
+            bail_transaction!(
+                "Not enough approvals",
+                context = "The spend proposal {:?} has {} approvals, {} are required",
+                { self.proposal_id },
+                approvals,
+                { multisig.threshold }
+            );
+        }
+
+        let (wallet_id, mut wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        let amount = Integer::try_parse(&proposal.amount)?;
+        let multisig_wallet_id = SigHash(proposal.multisig).to_wallet_id();
+        let multisig_wallet = debit(tx_ctx, &multisig_wallet_id, &amount)?;
+
+        let mut states = StateVec::new();
+        add_state(&mut states, multisig_wallet_id.into(), &multisig_wallet)?;
+
+        let dest_wallet_id = SigHash(proposal.sighash).to_wallet_id();
+        if dest_wallet_id == wallet_id {
+            // the receiver is the member executing the spend, credit the wallet that was just charged
+            let balance = Integer::try_parse(&wallet.amount)? + amount;
+            wallet.amount = balance.to_string();
+        } else {
+            let dest_wallet = credit(tx_ctx, &dest_wallet_id, &amount)?;
+            add_state(&mut states, dest_wallet_id.into(), &dest_wallet)?;
+        }
+
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        tx_ctx.delete_state_entries(&[self.proposal_id])?;
+        Ok(())
+    }
+}
+
+/// The part of a vesting entry released by block `head`: nothing before the cliff, then a
+/// linear share of the amount until `duration` blocks have passed.
+fn vested_amount(vesting: &protos::Vesting, head: &Integer) -> TxnResult<Integer> {
+    let amount = Integer::try_parse(&vesting.amount)?;
+    let start = Integer::try_parse(&vesting.block)?;
+    let elapsed = Integer::from(head - &start);
+
+    if elapsed < vesting.cliff {
+        return Ok(Integer::new());
+    }
+    if elapsed >= vesting.duration {
+        return Ok(amount);
+    }
+    Ok(amount * elapsed / vesting.duration)
+}
+
+impl CCTransaction for CreateVesting {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+
+        if self.duration == 0 || self.cliff > self.duration {
+            bail_transaction!(
+                "Invalid vesting schedule",
+                context = "The duration must be positive and at least the cliff, found cliff {} and duration {}",
+                { self.cliff },
+                { self.duration }
+            );
+        }
+        if self.amount == 0 {
+            bail_transaction!(
+                "Invalid amount",
+                context = "Cannot create a vesting entry without funds"
+            );
+        }
 
-        let offer = string!(NAMESPACE_PREFIX, OFFER);
-        filter(tx_ctx, &offer, |addr, proto| {
-            let offer = protos::Offer::try_parse(proto)?;
-            let start = Integer::try_parse(&offer.block)?;
-            elapsed_buf.assign(&block_idx - &start);
-            if offer.expiration < elapsed_buf {
-                tx_ctx.delete_state_entry(addr)?;
-            }
-            Ok(())
-        })?;
+        let src_wallet_id = my_sighash.to_wallet_id();
+        let amount_plus_fee = self.amount.clone() + ctx.tx_fee()?;
+        let src_wallet = debit(tx_ctx, &src_wallet_id, &amount_plus_fee)?;
 
-        let deal = string!(NAMESPACE_PREFIX, DEAL_ORDER);
-        filter(tx_ctx, &deal, |addr, proto| {
-            let deal_order = protos::DealOrder::try_parse(proto)?;
-            let start = Integer::try_parse(&deal_order.block)?;
-            elapsed_buf.assign(&block_idx - &start);
-            if deal_order.expiration < elapsed_buf && deal_order.loan_transfer.is_empty() {
-                if ctx.tip() == 0 || ctx.tip() > DEAL_EXP_FIX_BLOCK {
-                    let wallet_id = string!(NAMESPACE_PREFIX, WALLET, &deal_order.sighash);
-                    let state_data = get_state_data(tx_ctx, &wallet_id)?;
-                    let mut wallet = protos::Wallet::try_parse(&state_data)?;
-                    let mut balance = Integer::try_parse(&wallet.amount)?;
-                    balance += Integer::try_parse(&deal_order.fee)?;
-                    wallet.amount = balance.to_string();
-
-                    let mut states = vec![];
-                    add_state(&mut states, wallet_id, &wallet)?;
-                    tx_ctx.set_state_entries(states)?;
-                }
-                tx_ctx.delete_state_entry(addr)?;
-            }
-            Ok(())
-        })?;
+        let guid = ctx.guid(request);
+        let id = Address::with_prefix_key(VESTING, guid.as_str());
+        if try_get_state_data(tx_ctx, &id)?.is_some() {
+            bail_transaction!(
+                "Duplicate id",
+                context = "There is existing state data at address {:?}",
+                id
+            );
+        }
 
-        let repay = string!(NAMESPACE_PREFIX, REPAYMENT_ORDER);
-        filter(tx_ctx, &repay, |addr, proto| {
-            let repayment_order = protos::RepaymentOrder::try_parse(proto)?;
-            let start = Integer::try_parse(&repayment_order.block)?;
-            elapsed_buf.assign(&block_idx - &start);
-            if repayment_order.expiration < elapsed_buf && repayment_order.previous_owner.is_empty()
-            {
-                tx_ctx.delete_state_entry(addr)?;
+        let vesting = protos::Vesting {
+            sighash: self.sighash.into(),
+            amount: self.amount.to_string(),
+            claimed: Integer::new().to_string(),
+            block: last_block(request).to_string(),
+            cliff: self.cliff,
+            duration: self.duration,
+            owner: my_sighash.clone().into(),
+        };
+
+        let mut states = StateVec::new();
+        add_state(&mut states, id.into(), &vesting)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &src_wallet_id,
+            &src_wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+impl CCTransaction for ClaimVested {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+
+        let state_data = get_state_data(tx_ctx, &self.vesting_id)?;
+        let mut vesting = protos::Vesting::try_parse(&state_data)?;
+        if vesting.sighash != my_sighash.as_str() {
+            bail_transaction!(
+                "Only the beneficiary can claim vested funds",
+                context = "The beneficiary is {:?}, not {:?}",
+                { vesting.sighash },
+                my_sighash
+            );
+        }
+
+        let vested = vested_amount(&vesting, &last_block(request))?;
+        let claimed = Integer::try_parse(&vesting.claimed)?;
+        let claimable = Integer::from(&vested - &claimed);
+        if claimable <= 0 {
+            bail_transaction!(
+                "Nothing to claim",
+                context = "{} of the vesting entry {:?} is released and all of it was claimed",
+                vested,
+                { self.vesting_id }
+            );
+        }
+
+        // the fee comes out of the claimed funds, so an empty wallet can still claim
+        let wallet_id = my_sighash.to_wallet_id();
+        let mut wallet = credit(tx_ctx, &wallet_id, &claimable)?;
+        let tx_fee = ctx.tx_fee()?;
+        let balance = Integer::try_parse(&wallet.amount)?;
+        if balance < tx_fee {
+            bail_transaction!(
+                "Insufficient funds",
+                context = "Wallet balance at {:?} does not cover transaction fee",
+                wallet_id
+            );
+        }
+        wallet.amount = (balance - tx_fee).to_string();
+
+        let mut states = StateVec::new();
+        let fully_claimed = vested == Integer::try_parse(&vesting.amount)?;
+        if !fully_claimed {
+            vesting.claimed = vested.to_string();
+            add_state(&mut states, self.vesting_id.clone(), &vesting)?;
+        }
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        if fully_claimed {
+            tx_ctx.delete_state_entries(&[self.vesting_id])?;
+        }
+        Ok(())
+    }
+}
+
+fn allowance_id(owner: &SigHash, spender: &SigHash) -> Address {
+    Address::with_prefix_key(ALLOWANCE, &string!(owner, spender))
+}
+
+impl CCTransaction for Approve {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+        if self.spender == my_sighash {
+            bail_transaction!(
+                "Invalid spender",
+                context = "Cannot approve an allowance, the owner and spender must be different"
+            );
+        }
+
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        let id = allowance_id(&my_sighash, &self.spender);
+        let mut states = StateVec::new();
+        // approving zero revokes the allowance
+        if self.amount != 0 {
+            let allowance = protos::Allowance {
+                owner: my_sighash.clone().into(),
+                spender: self.spender.into(),
+                amount: self.amount.to_string(),
+                block: last_block(request).to_string(),
+            };
+            add_state(&mut states, id.clone().into(), &allowance)?;
+        }
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        if self.amount == 0 {
+            tx_ctx.delete_state_entries(&[id.into()])?;
+        }
+        Ok(())
+    }
+}
+
+impl CCTransaction for SendFundsFrom {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+        if self.owner == my_sighash {
+            bail_transaction!(
+                "Invalid owner",
+                context = "Cannot send funds from an allowance, use SendFunds to spend from the party's own wallet"
+            );
+        }
+        if self.sighash == self.owner {
+            bail_transaction!(
+                "Invalid destination",
+                context = "Cannot send funds, the owner and receiver must be different"
+            );
+        }
+
+        let id = allowance_id(&self.owner, &my_sighash);
+        let mut allowance = match try_get_state_data(tx_ctx, &id)? {
+            Some(state_data) => protos::Allowance::try_parse(&state_data)?,
+            None => {
+                bail_transaction!(
+                    "Insufficient allowance",
+                    context = "{:?} has not approved an allowance for {:?}",
+                    { self.owner },
+                    my_sighash
+                );
             }
-            Ok(())
-        })?;
+        };
+        let remaining = Integer::try_parse(&allowance.amount)?;
+        if remaining < self.amount {
+            bail_transaction!(
+                "Insufficient allowance",
+                context = "The allowance is {}, but {} was requested",
+                remaining,
+                { self.amount }
+            );
+        }
 
-        let fee = string!(NAMESPACE_PREFIX, FEE);
-        filter(tx_ctx, &fee, |addr, proto| {
-            let fee = protos::Fee::try_parse(proto)?;
-            let start = Integer::try_parse(&fee.block)?;
-            elapsed_buf.assign(&block_idx - &start);
+        let (wallet_id, mut wallet) = charge(ctx, tx_ctx, &my_sighash)?;
 
-            if elapsed_buf > YEAR_OF_BLOCKS {
-                let wallet_id = string!(NAMESPACE_PREFIX, WALLET, &fee.sighash);
-                let state_data = get_state_data(tx_ctx, &wallet_id)?;
-                let mut wallet = protos::Wallet::try_parse(&state_data)?;
-                wallet.amount = (Integer::try_parse(&wallet.amount)? + ctx.tx_fee()?).to_string();
+        let owner_wallet_id = self.owner.to_wallet_id();
+        let owner_wallet = debit(tx_ctx, &owner_wallet_id, &self.amount)?;
 
-                let mut state_data = state_data.0;
-                state_data.clear();
-                state_data.reserve(wallet.encoded_len());
-                wallet
-                    .encode(&mut state_data)
-                    .map_err(|e| InvalidTransaction(format!("Failed to encode wallet : {}", e)))?;
+        let mut states = StateVec::new();
+        add_state(&mut states, owner_wallet_id.into(), &owner_wallet)?;
 
-                tx_ctx.set_state_entry(wallet_id, state_data)?;
-                tx_ctx.delete_state_entry(addr)?;
+        let dest_wallet_id = self.sighash.to_wallet_id();
+        if dest_wallet_id == wallet_id {
+            // the spender pays itself, credit the wallet that was just charged
+            let balance = Integer::try_parse(&wallet.amount)? + &self.amount;
+            wallet.amount = balance.to_string();
+        } else {
+            let dest_wallet = credit(tx_ctx, &dest_wallet_id, &self.amount)?;
+            add_state(&mut states, dest_wallet_id.into(), &dest_wallet)?;
+        }
+
+        let remaining = remaining - self.amount;
+        if remaining != 0 {
+            allowance.amount = remaining.to_string();
+            add_state(&mut states, id.clone().into(), &allowance)?;
+        }
+
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        if remaining == 0 {
+            tx_ctx.delete_state_entries(&[id.into()])?;
+        }
+        Ok(())
+    }
+}
+
+fn burn_id(nonce: u64) -> Address {
+    Address::with_prefix_key(BURN, &nonce.to_string())
+}
+
+fn next_burn_nonce(tx_ctx: &dyn TransactionContext, counter: &Address) -> TxnResult<u64> {
+    let nonce = match try_get_state_data(tx_ctx, counter)? {
+        Some(state_data) => str::from_utf8(&state_data)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| InvalidTransaction(format!("Invalid burn nonce at {:?}", counter)))?,
+        None => 0,
+    };
+    Ok(nonce)
+}
+
+fn is_eth_address(address: &str) -> bool {
+    address.len() == 42
+        && address.starts_with("0x")
+        && address[2..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl CCTransaction for BurnCoins {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let my_sighash = ctx.sighash(request)?;
+
+        if self.amount == 0 {
+            bail_transaction!("Invalid amount", context = "Cannot burn zero coins");
+        }
+        if !is_eth_address(&self.eth_address) {
+            bail_transaction!(
+                "Invalid Ethereum address",
+                context = "{:?} is not a hex encoded 20 byte address",
+                { self.eth_address }
+            );
+        }
+
+        let wallet_id = my_sighash.to_wallet_id();
+        let amount_plus_fee = self.amount.clone() + ctx.tx_fee()?;
+        let wallet = debit(tx_ctx, &wallet_id, &amount_plus_fee)?;
+
+        let counter = Address::with_prefix_key(BURN, BURN_NONCE_KEY);
+        let nonce = next_burn_nonce(tx_ctx, &counter)?;
+        let id = burn_id(nonce);
+
+        let burn = protos::Burn {
+            sighash: my_sighash.clone().into(),
+            eth_address: self.eth_address,
+            amount: self.amount.to_string(),
+            nonce,
+            block: last_block(request).to_string(),
+            processed: false,
+            blockchain_tx_id: String::new(),
+        };
+
+        let mut states = StateVec::new();
+        add_state(&mut states, id.into(), &burn)?;
+        states.push((counter.into(), (nonce + 1).to_string().into_bytes()));
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
+        Ok(())
+    }
+}
+
+impl CCTransaction for ConfirmBurn {
+    fn execute(
+        self,
+        request: &TpProcessRequest,
+        tx_ctx: &dyn TransactionContext,
+        ctx: &mut HandlerContext,
+    ) -> TxnResult<()> {
+        let mut burn = match try_get_state_data(tx_ctx, &self.burn_id)? {
+            Some(state_data) => protos::Burn::try_parse(&state_data)?,
+            None => {
+                bail_transaction!(
+                    "The burn doesn't exist",
+                    context = "No burn entry was found at {:?}",
+                    { self.burn_id }
+                );
             }
-            Ok(())
-        })?;
+        };
+        if burn.processed {
+            bail_transaction!(
+                "Already confirmed",
+                context = "The burn {:?} was confirmed by {:?}",
+                { self.burn_id },
+                { burn.blockchain_tx_id }
+            );
+        }
 
-        reward(request, tx_ctx, ctx, &last_processed_block_idx, &block_idx)?;
-        tx_ctx.set_state_entry(processed_block_idx, block_idx.to_string().into_bytes())?;
+        let my_sighash = ctx.sighash(request)?;
+        let (wallet_id, wallet) = charge(ctx, tx_ctx, &my_sighash)?;
+
+        let bridge = Bridge::from_settings(ctx)?;
+        let burn_command = format!("{} burn", bridge.blockchain);
+        let gateway_command = [
+            burn_command.as_str(),
+            &burn.sighash,
+            &bridge.contract,
+            &burn.eth_address,
+            &burn.amount,
+            &self.blockchain_tx_id,
+            &burn.nonce.to_string(),
+            &bridge.network,
+        ]
+        .join(" ");
+        ctx.verify(&gateway_command)?;
 
+        burn.processed = true;
+        burn.blockchain_tx_id = self.blockchain_tx_id;
+
+        let mut states = StateVec::new();
+        add_state(&mut states, self.burn_id, &burn)?;
+        add_fee_state(
+            ctx,
+            tx_ctx,
+            request,
+            &my_sighash,
+            &mut states,
+            &wallet_id,
+            &wallet,
+        )?;
+        tx_ctx.set_state_entries(states)?;
         Ok(())
     }
 }
@@ -2001,6 +4046,8 @@ impl TransactionHandler for CCTransactionHandler {
             "1.5".into(),
             "1.6".into(),
             "1.7".into(),
+            "1.8".into(),
+            "1.9".into(),
         ]
     }
 
@@ -2025,10 +4072,19 @@ impl TransactionHandler for CCTransactionHandler {
             &*context,
         )
         .log_err()
-        .to_apply_error()?;
+        .to_apply_error()?
+        .with_tip(request.get_tip());
+
+        let state_v2 = handler_context
+            .forks(request)
+            .log_err()
+            .to_apply_error()?
+            .is_active(Feature::StateV2, request.get_tip());
+        let upgrading = encoding::StateV2Context(&*context);
+        let tx_ctx: &dyn TransactionContext = if state_v2 { &upgrading } else { &*context };
 
         command
-            .execute(request, context, &mut handler_context)
+            .execute(request, tx_ctx, &mut handler_context)
             .log_err()
             .to_apply_error()?;
         Ok(())
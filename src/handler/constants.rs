@@ -18,17 +18,35 @@ pub const OFFER: &str = "7000";
 pub const ERC20: &str = "8000";
 pub const PROCESSED_BLOCK: &str = "9000";
 pub const FEE: &str = "0100";
+pub const MIGRATION: &str = "0200";
+pub const MULTISIG: &str = "0300";
+pub const SPEND_PROPOSAL: &str = "0400";
+pub const VESTING: &str = "0500";
+pub const ALLOWANCE: &str = "0600";
+pub const BURN: &str = "0700";
+pub const SUPPLY: &str = "0800";
+pub const HOUSEKEEPING_CURSOR: &str = "0900";
+pub const EXPIRY_INDEX: &str = "0a00";
+pub const FEE_AGGREGATE: &str = "0b00";
+pub const AUTO_ACCEPT_INDEX: &str = "0c00";
 pub const SETTINGS_NAMESPACE: &str = "000000";
 
 pub const PROCESSED_BLOCK_ID: &str = "000000000000000000000000000000000000000000000000000000000000";
+pub const BURN_NONCE_KEY: &str = "nonce";
+pub const MINTED_SUPPLY_KEY: &str = "minted";
+pub const EXPIRY_CURSOR_KEY: &str = "swept";
 
 pub const INTEREST_MULTIPLIER: u64 = 1000000;
+pub const BASIS_POINTS: u64 = 10000;
 pub const CONFIRMATION_COUNT: u64 = 30;
 pub const YEAR_OF_BLOCKS: u64 = 60 * 24 * 365;
 
 pub const BLOCKS_IN_PERIOD_UPDATE1: u64 = 2500000;
+/// First period in which (19/20)^period rounds to zero at six decimals.
+pub const REWARD_DECAY_CUTOFF_PERIOD: i32 = 283;
 
 pub const BLOCK_REWARD_PROCESSING_COUNT: u64 = 10;
+pub const MIGRATION_BATCH_SIZE: u64 = 1000;
 
 pub const SKIP_TO_GET_60: usize = 512 / 8 * 2 - 60; // 512 - hash size in bits, 8 - bits in byte, 2 - hex digits for byte, 60 - merkle address length (70) without namespace length (6) and prexix length (4)
 
@@ -60,6 +78,35 @@ pub static REPAYMENT_ORDER_PREFIX: Lazy<String> = Lazy::new(|| {
 });
 
 pub const TX_FEE_KEY: &str = "sawtooth.validator.fee";
+pub const UPDATE1_KEY: &str = "sawtooth.validator.update1";
+pub const CONFIRMATION_COUNT_KEY: &str = "sawtooth.validator.confirmation_count";
+pub const YEAR_OF_BLOCKS_KEY: &str = "sawtooth.validator.year_of_blocks";
+pub const BLOCK_REWARD_PROCESSING_COUNT_KEY: &str =
+    "sawtooth.validator.block_reward_processing_count";
+pub const BLOCKS_IN_PERIOD_UPDATE1_KEY: &str = "sawtooth.validator.blocks_in_period_update1";
+pub const REWARD_AMOUNT_KEY: &str = "sawtooth.validator.reward_amount";
+pub const DEAL_EXP_FIX_BLOCK_KEY: &str = "sawtooth.validator.deal_exp_fix_block";
+pub const REWARD_SCHEDULE_KEY: &str = "sawtooth.validator.reward_schedule";
+pub const INITIAL_SUPPLY_KEY: &str = "sawtooth.validator.initial_supply";
+pub const TREASURY_SIGHASH_KEY: &str = "sawtooth.validator.treasury_sighash";
+pub const TREASURY_SHARE_KEY: &str = "sawtooth.validator.treasury_share";
+pub const HOUSEKEEPING_SCAN_LIMIT_KEY: &str = "sawtooth.validator.housekeeping_scan_limit";
+pub const EXPIRY_INDEX_BLOCK_KEY: &str = "sawtooth.validator.expiry_index_block";
+pub const FEE_BUCKET_BLOCKS_KEY: &str = "sawtooth.validator.fee_bucket_blocks";
+pub const STATE_V2_BLOCK_KEY: &str = "sawtooth.validator.state_v2_block";
+pub const COLLECTED_COINS_RECORDS_BLOCK_KEY: &str =
+    "sawtooth.validator.collected_coins_records_block";
+pub const REWARD_FORMULA_FIX_BLOCK_KEY: &str = "sawtooth.validator.reward_formula_fix_block";
+pub const FEE_AMOUNTS_BLOCK_KEY: &str = "sawtooth.validator.fee_amounts_block";
+pub const AUTO_ACCEPT_BLOCK_KEY: &str = "sawtooth.validator.auto_accept_block";
+pub const BRIDGE_BLOCKCHAIN_KEY: &str = "sawtooth.validator.bridge_blockchain";
+pub const BRIDGE_NETWORK_KEY: &str = "sawtooth.validator.bridge_network";
+pub const BRIDGE_CONTRACT_KEY: &str = "sawtooth.validator.bridge_contract";
+pub const DEFAULT_BRIDGE_BLOCKCHAIN: &str = "ethereum";
+pub const DEFAULT_BRIDGE_NETWORK: &str = "unused";
+pub const DEFAULT_BRIDGE_CONTRACT: &str = "creditcoin";
+pub const DEV_BYPASS_SIGHASHES_KEY: &str = "sawtooth.validator.dev_bypass_sighashes";
+pub const DEV_BYPASS_ETH_ADDRESS: &str = "unused_if_hacked";
 pub const TX_FEE_STRING: &str = "10000000000000000";
 
 pub static TX_FEE: Lazy<Integer> =
@@ -7,6 +7,8 @@ use crate::{
 
 use super::{
     constants::{EXTERNAL_GATEWAY_TIMEOUT, GATEWAY_TIMEOUT, TX_FEE, TX_FEE_KEY},
+    forks::ForkSchedule,
+    params::ProcessorParams,
     types::{
         CCApplyError::{InternalError, InvalidTransaction},
         Guid, SigHash, TxnResult,
@@ -24,9 +26,12 @@ use sha2::{Digest, Sha256};
 pub struct HandlerContext<'tx> {
     // sighash: Option<SigHash>,
     // guid: Option<Guid>,
+    // current_state: BTreeMap<State, State>,
+    // Replay and transition modes are not supported: every height is derived from the request
+    // tip, which already reproduces historical behavior when syncing from genesis. The places
+    // they would change are marked `TODO: transitioning`.
     // replaying: bool,
     // transitioning: bool,
-    // current_state: BTreeMap<State, State>,
     tip: u64,
     gateway_context: zmq::Context,
     #[cfg(not(all(test, feature = "mock")))]
@@ -34,6 +39,8 @@ pub struct HandlerContext<'tx> {
     gateway_endpoint: String,
     tx_ctx: &'tx dyn TransactionContext,
     tx_fee: OnceCell<Integer>,
+    params: OnceCell<ProcessorParams>,
+    forks: OnceCell<ForkSchedule>,
 }
 
 const MAX_KEY_PARTS: usize = 4;
@@ -79,9 +86,16 @@ impl<'tx> HandlerContext<'tx> {
             tx_ctx,
             tip: 0,
             tx_fee: OnceCell::new(),
+            params: OnceCell::new(),
+            forks: OnceCell::new(),
         })
     }
 
+    pub fn with_tip(mut self, tip: u64) -> Self {
+        self.tip = tip;
+        self
+    }
+
     pub fn tip(&self) -> u64 {
         self.tip
     }
@@ -111,6 +125,9 @@ impl<'tx> HandlerContext<'tx> {
         Ok(None)
     }
 
+    /// Reads `key` from the settings state. Values are not cached across transactions: the
+    /// settings family can change them between two transactions of a block without this
+    /// processor seeing the write.
     pub fn get_setting(&self, key: &str) -> TxnResult<Option<String>> {
         log::debug!("getting setting for key {:?}", key);
         let k = make_settings_key(key);
@@ -150,6 +167,19 @@ impl<'tx> HandlerContext<'tx> {
             })
     }
 
+    pub fn params(&self, request: &TpProcessRequest) -> TxnResult<&ProcessorParams> {
+        self.params.get_or_try_init(|| {
+            let height = request.get_tip().saturating_sub(1);
+            ProcessorParams::resolve(|key| self.get_setting(key), height)
+        })
+    }
+
+    pub fn forks(&self, request: &TpProcessRequest) -> TxnResult<&ForkSchedule> {
+        self.forks.get_or_try_init(|| {
+            ForkSchedule::resolve(self.params(request)?, |key| self.get_setting(key))
+        })
+    }
+
     #[cfg(not(all(test, feature = "mock")))]
     fn try_verify_external(&mut self, gateway_command: &str) -> TxnResult<Option<String>> {
         log::warn!("Falling back to external gateway");
@@ -235,6 +265,13 @@ impl<'tx> HandlerContext<'tx> {
 #[cfg(all(test, feature = "mock"))]
 pub mod mocked {
     use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        // tests run on their own threads, so each test sees only the params it set
+        static PARAMS: Cell<Option<&'static ProcessorParams>> = Cell::new(None);
+    }
+
     mockall::mock! {
         pub HandlerContext {
             pub fn create(
@@ -258,6 +295,27 @@ pub mod mocked {
         pub fn tx_fee(&self) -> TxnResult<Integer> {
             Ok(TX_FEE.clone())
         }
+
+        pub fn with_tip(self, _: u64) -> Self {
+            self
+        }
+
+        /// Makes `params` return `params` instead of the defaults for the rest of the test.
+        pub fn with_params(self, params: ProcessorParams) -> Self {
+            let params: &'static ProcessorParams = Box::leak(Box::new(params));
+            PARAMS.with(|cell| cell.set(Some(params)));
+            self
+        }
+
+        pub fn params(&self, _: &TpProcessRequest) -> TxnResult<&ProcessorParams> {
+            static DEFAULT_PARAMS: once_cell::sync::Lazy<ProcessorParams> =
+                once_cell::sync::Lazy::new(ProcessorParams::default);
+            Ok(PARAMS.with(Cell::get).unwrap_or(&DEFAULT_PARAMS))
+        }
+
+        pub fn forks(&self, request: &TpProcessRequest) -> TxnResult<ForkSchedule> {
+            ForkSchedule::resolve(self.params(request)?, |key| self.get_setting(key))
+        }
     }
 }
 
@@ -305,4 +363,14 @@ mod tests {
         let result = context.tx_fee().unwrap().clone();
         assert_eq!(&result, &*TX_FEE);
     }
+
+    #[test]
+    fn tip_comes_from_the_request() {
+        let zmq_context = zmq::Context::new();
+        let mock_tx_ctx = MockTransactionContext::default();
+        let context =
+            HandlerContext::create(zmq_context, "tcp://dummy:8080".into(), &mock_tx_ctx).unwrap();
+        assert_eq!(context.tip(), 0);
+        assert_eq!(context.with_tip(42).tip(), 42);
+    }
 }
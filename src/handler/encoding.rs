@@ -0,0 +1,539 @@
+//! Conversions between the v1 encoding of state entries, which stores every number as a
+//! decimal string, and the v2 encoding, which stores big integers as canonical big-endian
+//! bytes and bounded values as `uint64`.
+//!
+//! Reading through `MessageExt::try_parse` accepts either encoding, into either the v1 or the
+//! native v2 message, and rejects versions this processor doesn't know. Once
+//! `Feature::StateV2` is active, `StateV2Context` re-encodes every entry a transaction writes
+//! under a migrated prefix as v2, so entries migrate lazily as they are touched.
+
+use std::convert::TryFrom;
+
+use anyhow::Context;
+use rug::integer::Order;
+use rug::Integer;
+use sawtooth_sdk::processor::handler::{ContextError, TransactionContext};
+
+use crate::ext::{decode_message, IntegerExt, MessageExt, Versioned};
+use crate::{bail_transaction, protos};
+
+use super::constants::*;
+use super::types::{CCApplyError, TxnResult};
+
+/// Entries written before versioning have no version field, which decodes as 0.
+pub const STATE_V1: u32 = 0;
+pub const STATE_V2: u32 = 2;
+
+/// State prefixes whose entries have a v2 encoding.
+pub const MIGRATED_PREFIXES: [&str; 8] = [
+    WALLET,
+    TRANSFER,
+    ASK_ORDER,
+    BID_ORDER,
+    DEAL_ORDER,
+    REPAYMENT_ORDER,
+    OFFER,
+    FEE,
+];
+
+pub fn state_version(buf: &[u8]) -> TxnResult<u32> {
+    Ok(decode_message::<protos::StateVersion>(buf)?.version)
+}
+
+fn unsupported<T>(version: u32) -> TxnResult<T> {
+    bail_transaction!("Unsupported state version {}", version)
+}
+
+pub fn integer_to_bytes(value: &str) -> TxnResult<Vec<u8>> {
+    Ok(Integer::try_parse(value)?.to_digits::<u8>(Order::Msf))
+}
+
+pub fn integer_from_bytes(bytes: &[u8]) -> TxnResult<String> {
+    Ok(decode_integer(bytes)?.to_string())
+}
+
+pub fn decode_integer(bytes: &[u8]) -> TxnResult<Integer> {
+    if bytes.first() == Some(&0) {
+        bail_transaction!("Integer bytes are not in canonical form");
+    }
+    Ok(Integer::from_digits(bytes, Order::Msf))
+}
+
+fn bounded(value: &str) -> TxnResult<u64> {
+    Integer::try_parse(value)?.to_u64().ok_or_else(|| {
+        CCApplyError::InvalidTransaction(format!("The number {} does not fit in 64 bits", value))
+            .into()
+    })
+}
+
+macro_rules! dual_encoding {
+    ($v1: ident, $v2: ident) => {
+        impl Versioned for protos::$v1 {
+            fn decode_version(version: u32, buf: &[u8]) -> TxnResult<Self> {
+                match version {
+                    STATE_V1 => decode_message(buf),
+                    STATE_V2 => protos::$v1::try_from(decode_message::<protos::$v2>(buf)?),
+                    version => unsupported(version),
+                }
+            }
+        }
+
+        impl Versioned for protos::$v2 {
+            fn decode_version(version: u32, buf: &[u8]) -> TxnResult<Self> {
+                match version {
+                    STATE_V1 => protos::$v2::try_from(decode_message::<protos::$v1>(buf)?),
+                    STATE_V2 => decode_message(buf),
+                    version => unsupported(version),
+                }
+            }
+        }
+    };
+}
+
+dual_encoding!(Wallet, WalletV2);
+dual_encoding!(AskOrder, AskOrderV2);
+dual_encoding!(BidOrder, BidOrderV2);
+dual_encoding!(DealOrder, DealOrderV2);
+dual_encoding!(Fee, FeeV2);
+dual_encoding!(Offer, OfferV2);
+dual_encoding!(RepaymentOrder, RepaymentOrderV2);
+dual_encoding!(Transfer, TransferV2);
+
+macro_rules! single_encoding {
+    ($v1: ident) => {
+        impl Versioned for protos::$v1 {
+            fn decode_version(version: u32, buf: &[u8]) -> TxnResult<Self> {
+                match version {
+                    STATE_V1 => decode_message(buf),
+                    version => unsupported(version),
+                }
+            }
+        }
+    };
+}
+
+single_encoding!(Address);
+single_encoding!(Multisig);
+single_encoding!(SpendProposal);
+single_encoding!(Vesting);
+single_encoding!(Allowance);
+single_encoding!(Burn);
+single_encoding!(CollectedCoins);
+single_encoding!(ExpiryBucket);
+single_encoding!(FeeAggregate);
+single_encoding!(AutoAcceptBucket);
+
+impl TryFrom<protos::Wallet> for protos::WalletV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(wallet: protos::Wallet) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&wallet.amount)?,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::WalletV2> for protos::Wallet {
+    type Error = anyhow::Error;
+
+    fn try_from(wallet: protos::WalletV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&wallet.amount)?,
+        })
+    }
+}
+
+impl TryFrom<protos::AskOrder> for protos::AskOrderV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::AskOrder) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&order.amount)?,
+            interest: integer_to_bytes(&order.interest)?,
+            maturity: bounded(&order.maturity)?,
+            fee: integer_to_bytes(&order.fee)?,
+            block: bounded(&order.block)?,
+            blockchain: order.blockchain,
+            address: order.address,
+            expiration: order.expiration,
+            sighash: order.sighash,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::AskOrderV2> for protos::AskOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::AskOrderV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&order.amount)?,
+            interest: integer_from_bytes(&order.interest)?,
+            maturity: order.maturity.to_string(),
+            fee: integer_from_bytes(&order.fee)?,
+            block: order.block.to_string(),
+            blockchain: order.blockchain,
+            address: order.address,
+            expiration: order.expiration,
+            sighash: order.sighash,
+        })
+    }
+}
+
+impl TryFrom<protos::BidOrder> for protos::BidOrderV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::BidOrder) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&order.amount)?,
+            interest: integer_to_bytes(&order.interest)?,
+            maturity: bounded(&order.maturity)?,
+            fee: integer_to_bytes(&order.fee)?,
+            block: bounded(&order.block)?,
+            blockchain: order.blockchain,
+            address: order.address,
+            expiration: order.expiration,
+            sighash: order.sighash,
+            auto_accept: order.auto_accept,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::BidOrderV2> for protos::BidOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::BidOrderV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&order.amount)?,
+            interest: integer_from_bytes(&order.interest)?,
+            maturity: order.maturity.to_string(),
+            fee: integer_from_bytes(&order.fee)?,
+            block: order.block.to_string(),
+            blockchain: order.blockchain,
+            address: order.address,
+            expiration: order.expiration,
+            sighash: order.sighash,
+            auto_accept: order.auto_accept,
+        })
+    }
+}
+
+impl TryFrom<protos::DealOrder> for protos::DealOrderV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::DealOrder) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&order.amount)?,
+            interest: integer_to_bytes(&order.interest)?,
+            maturity: bounded(&order.maturity)?,
+            fee: integer_to_bytes(&order.fee)?,
+            block: bounded(&order.block)?,
+            blockchain: order.blockchain,
+            src_address: order.src_address,
+            dst_address: order.dst_address,
+            expiration: order.expiration,
+            loan_transfer: order.loan_transfer,
+            repayment_transfer: order.repayment_transfer,
+            lock: order.lock,
+            sighash: order.sighash,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::DealOrderV2> for protos::DealOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::DealOrderV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&order.amount)?,
+            interest: integer_from_bytes(&order.interest)?,
+            maturity: order.maturity.to_string(),
+            fee: integer_from_bytes(&order.fee)?,
+            block: order.block.to_string(),
+            blockchain: order.blockchain,
+            src_address: order.src_address,
+            dst_address: order.dst_address,
+            expiration: order.expiration,
+            loan_transfer: order.loan_transfer,
+            repayment_transfer: order.repayment_transfer,
+            lock: order.lock,
+            sighash: order.sighash,
+        })
+    }
+}
+
+impl TryFrom<protos::Fee> for protos::FeeV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(fee: protos::Fee) -> TxnResult<Self> {
+        let paid = if fee.amount.is_empty() {
+            None
+        } else {
+            Some(protos::fee_v2::Paid::Amount(integer_to_bytes(&fee.amount)?))
+        };
+        Ok(Self {
+            block: bounded(&fee.block)?,
+            sighash: fee.sighash,
+            paid,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::FeeV2> for protos::Fee {
+    type Error = anyhow::Error;
+
+    fn try_from(fee: protos::FeeV2) -> TxnResult<Self> {
+        let amount = match fee.paid {
+            Some(protos::fee_v2::Paid::Amount(amount)) => integer_from_bytes(&amount)?,
+            None => String::new(),
+        };
+        Ok(Self {
+            block: fee.block.to_string(),
+            sighash: fee.sighash,
+            amount,
+        })
+    }
+}
+
+impl TryFrom<protos::Offer> for protos::OfferV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(offer: protos::Offer) -> TxnResult<Self> {
+        Ok(Self {
+            block: bounded(&offer.block)?,
+            blockchain: offer.blockchain,
+            ask_order: offer.ask_order,
+            bid_order: offer.bid_order,
+            expiration: offer.expiration,
+            sighash: offer.sighash,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::OfferV2> for protos::Offer {
+    type Error = anyhow::Error;
+
+    fn try_from(offer: protos::OfferV2) -> TxnResult<Self> {
+        Ok(Self {
+            block: offer.block.to_string(),
+            blockchain: offer.blockchain,
+            ask_order: offer.ask_order,
+            bid_order: offer.bid_order,
+            expiration: offer.expiration,
+            sighash: offer.sighash,
+        })
+    }
+}
+
+impl TryFrom<protos::RepaymentOrder> for protos::RepaymentOrderV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::RepaymentOrder) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&order.amount)?,
+            block: bounded(&order.block)?,
+            blockchain: order.blockchain,
+            src_address: order.src_address,
+            dst_address: order.dst_address,
+            expiration: order.expiration,
+            deal: order.deal,
+            previous_owner: order.previous_owner,
+            transfer: order.transfer,
+            sighash: order.sighash,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::RepaymentOrderV2> for protos::RepaymentOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(order: protos::RepaymentOrderV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&order.amount)?,
+            block: order.block.to_string(),
+            blockchain: order.blockchain,
+            src_address: order.src_address,
+            dst_address: order.dst_address,
+            expiration: order.expiration,
+            deal: order.deal,
+            previous_owner: order.previous_owner,
+            transfer: order.transfer,
+            sighash: order.sighash,
+        })
+    }
+}
+
+impl TryFrom<protos::Transfer> for protos::TransferV2 {
+    type Error = anyhow::Error;
+
+    fn try_from(transfer: protos::Transfer) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_to_bytes(&transfer.amount)?,
+            block: bounded(&transfer.block)?,
+            blockchain: transfer.blockchain,
+            src_address: transfer.src_address,
+            dst_address: transfer.dst_address,
+            order: transfer.order,
+            tx: transfer.tx,
+            processed: transfer.processed,
+            sighash: transfer.sighash,
+            version: STATE_V2,
+        })
+    }
+}
+
+impl TryFrom<protos::TransferV2> for protos::Transfer {
+    type Error = anyhow::Error;
+
+    fn try_from(transfer: protos::TransferV2) -> TxnResult<Self> {
+        Ok(Self {
+            amount: integer_from_bytes(&transfer.amount)?,
+            block: transfer.block.to_string(),
+            blockchain: transfer.blockchain,
+            src_address: transfer.src_address,
+            dst_address: transfer.dst_address,
+            order: transfer.order,
+            tx: transfer.tx,
+            processed: transfer.processed,
+            sighash: transfer.sighash,
+        })
+    }
+}
+
+fn upgrade<V1, V2>(buf: &[u8]) -> TxnResult<Option<Vec<u8>>>
+where
+    V1: Versioned,
+    V2: Versioned + TryFrom<V1, Error = anyhow::Error>,
+{
+    if state_version(buf)? != STATE_V1 {
+        return Ok(None);
+    }
+    let v1 = decode_message::<V1>(buf)?;
+    Ok(Some(V2::try_from(v1)?.to_bytes()))
+}
+
+/// Re-encodes a v1 entry stored under `prefix` as v2, or returns `None` if the entry is
+/// already migrated or the prefix has no v2 encoding.
+fn upgrade_entry(prefix: &str, buf: &[u8]) -> TxnResult<Option<Vec<u8>>> {
+    match prefix {
+        WALLET => upgrade::<protos::Wallet, protos::WalletV2>(buf),
+        ASK_ORDER => upgrade::<protos::AskOrder, protos::AskOrderV2>(buf),
+        BID_ORDER => upgrade::<protos::BidOrder, protos::BidOrderV2>(buf),
+        DEAL_ORDER => upgrade::<protos::DealOrder, protos::DealOrderV2>(buf),
+        FEE => upgrade::<protos::Fee, protos::FeeV2>(buf),
+        OFFER => upgrade::<protos::Offer, protos::OfferV2>(buf),
+        REPAYMENT_ORDER => upgrade::<protos::RepaymentOrder, protos::RepaymentOrderV2>(buf),
+        TRANSFER => upgrade::<protos::Transfer, protos::TransferV2>(buf),
+        _ => Ok(None),
+    }
+}
+
+/// Re-encodes a v1 entry stored under `prefix` as v2. Entries that are already migrated, or
+/// that hold numbers v2 can't represent (malformed, negative or out of range), are left alone.
+pub fn migrate_entry(prefix: &str, address: &str, buf: &[u8]) -> Option<Vec<u8>> {
+    match upgrade_entry(prefix, buf) {
+        Ok(migrated) => migrated,
+        Err(e) => {
+            log::warn!("Leaving {} in the v1 encoding : {:#}", address, e);
+            None
+        }
+    }
+}
+
+/// The bytes to store at `address` once `Feature::StateV2` is active: v1 entries under a
+/// migrated prefix are re-encoded as v2, and entries v2 can't represent are rejected.
+pub fn encode_entry(address: &str, data: Vec<u8>) -> TxnResult<Vec<u8>> {
+    if !address.starts_with(NAMESPACE_PREFIX.as_str()) {
+        return Ok(data);
+    }
+    let prefix_start = NAMESPACE_PREFIX.len();
+    let prefix = address
+        .get(prefix_start..prefix_start + PREFIX_LENGTH)
+        .unwrap_or_default();
+    let upgraded = upgrade_entry(prefix, &data)
+        .with_context(|| format!("The state entry for {:?} has no v2 encoding", address))?;
+    Ok(upgraded.unwrap_or(data))
+}
+
+fn encoding_error(e: anyhow::Error) -> ContextError {
+    ContextError::ResponseAttributeError(format!("{:#}", e))
+}
+
+/// Passes every call through to the validator, re-encoding the entries written with
+/// `encode_entry`.
+pub struct StateV2Context<'a>(pub &'a dyn TransactionContext);
+
+impl TransactionContext for StateV2Context<'_> {
+    fn get_state_entry(&self, address: &str) -> Result<Option<Vec<u8>>, ContextError> {
+        self.0.get_state_entry(address)
+    }
+
+    fn get_state_entries(
+        &self,
+        addresses: &[String],
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        self.0.get_state_entries(addresses)
+    }
+
+    fn set_state_entry(&self, address: String, data: Vec<u8>) -> Result<(), ContextError> {
+        let data = encode_entry(&address, data).map_err(encoding_error)?;
+        self.0.set_state_entry(address, data)
+    }
+
+    fn set_state_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), ContextError> {
+        let entries = entries
+            .into_iter()
+            .map(|(address, data)| Ok((address.clone(), encode_entry(&address, data)?)))
+            .collect::<TxnResult<Vec<_>>>()
+            .map_err(encoding_error)?;
+        self.0.set_state_entries(entries)
+    }
+
+    fn delete_state_entry(&self, address: &str) -> Result<Option<String>, ContextError> {
+        self.0.delete_state_entry(address)
+    }
+
+    fn delete_state_entries(&self, addresses: &[String]) -> Result<Vec<String>, ContextError> {
+        self.0.delete_state_entries(addresses)
+    }
+
+    fn add_receipt_data(&self, data: &[u8]) -> Result<(), ContextError> {
+        self.0.add_receipt_data(data)
+    }
+
+    fn add_event(
+        &self,
+        event_type: String,
+        attributes: Vec<(String, String)>,
+        data: &[u8],
+    ) -> Result<(), ContextError> {
+        self.0.add_event(event_type, attributes, data)
+    }
+
+    fn get_sig_by_num(&self, block_num: u64) -> Result<String, ContextError> {
+        self.0.get_sig_by_num(block_num)
+    }
+
+    fn get_reward_block_signatures(
+        &self,
+        block_id: &str,
+        first_pred: u64,
+        last_pred: u64,
+    ) -> Result<Vec<String>, ContextError> {
+        self.0
+            .get_reward_block_signatures(block_id, first_pred, last_pred)
+    }
+
+    fn get_state_entries_by_prefix(
+        &self,
+        address: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        self.0.get_state_entries_by_prefix(address)
+    }
+}
@@ -0,0 +1,120 @@
+use super::{constants::*, params::ProcessorParams, types::TxnResult};
+
+/// Number of blocks after the configured `update1` height before the new reward formula applies.
+const UPDATE1_DELAY: u64 = 500;
+
+/// Consensus changes that activate at a block height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Expired, unfunded deal orders refund their fee to the fundraiser.
+    DealExpirationRefund,
+    /// Block rewards decay by period instead of paying a flat amount.
+    RewardFormulaUpdate1,
+    /// Orders are tracked by expiry height, and housekeeping expires them from that index
+    /// instead of scanning every order.
+    ExpiryIndex,
+    /// Entries under the prefixes with a v2 encoding are written in that encoding.
+    StateV2,
+    /// `CollectCoins` stores a full `CollectedCoins` record instead of the bare amount.
+    CollectedCoinsRecords,
+    /// Blocks under the update1 formula are paid its decayed reward instead of nothing.
+    RewardFormulaFix,
+    /// Fees record the amount paid, and housekeeping refunds that amount.
+    FeeAmounts,
+    /// Bid orders can be auto-accepted, and ask orders are matched against them.
+    AutoAccept,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 8] = [
+        Feature::DealExpirationRefund,
+        Feature::RewardFormulaUpdate1,
+        Feature::ExpiryIndex,
+        Feature::StateV2,
+        Feature::CollectedCoinsRecords,
+        Feature::RewardFormulaFix,
+        Feature::FeeAmounts,
+        Feature::AutoAccept,
+    ];
+}
+
+/// Settings read directly by `ForkSchedule::resolve`, each holding an activation height.
+pub const SETTING_KEYS: [&str; 5] = [
+    UPDATE1_KEY,
+    COLLECTED_COINS_RECORDS_BLOCK_KEY,
+    REWARD_FORMULA_FIX_BLOCK_KEY,
+    FEE_AMOUNTS_BLOCK_KEY,
+    AUTO_ACCEPT_BLOCK_KEY,
+];
+
+/// The activation height held by the setting `key`. A malformed height leaves the feature
+/// inactive rather than failing every transaction that checks it.
+fn activation_height(
+    key: &str,
+    get_setting: &impl Fn(&str) -> TxnResult<Option<String>>,
+) -> TxnResult<Option<u64>> {
+    let raw = match get_setting(key)? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let height = raw.trim().parse().ok();
+    if height.is_none() {
+        log::warn!("Ignoring setting {}, not a block number : {:?}", key, raw);
+    }
+    Ok(height)
+}
+
+/// Activation heights of every `Feature`, resolved from constants and on-chain settings. A
+/// feature without an activation height is never active.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForkSchedule {
+    deal_expiration_refund: Option<u64>,
+    reward_formula_update1: Option<u64>,
+    expiry_index: Option<u64>,
+    state_v2: Option<u64>,
+    collected_coins_records: Option<u64>,
+    reward_formula_fix: Option<u64>,
+    fee_amounts: Option<u64>,
+    auto_accept: Option<u64>,
+}
+
+impl ForkSchedule {
+    pub fn resolve(
+        params: &ProcessorParams,
+        get_setting: impl Fn(&str) -> TxnResult<Option<String>>,
+    ) -> TxnResult<Self> {
+        let reward_formula_update1 = activation_height(UPDATE1_KEY, &get_setting)?
+            .and_then(|update_block| update_block.checked_add(UPDATE1_DELAY + 1));
+        Ok(Self {
+            deal_expiration_refund: params.deal_exp_fix_block.checked_add(1),
+            reward_formula_update1,
+            expiry_index: params.expiry_index_block,
+            state_v2: params.state_v2_block,
+            collected_coins_records: activation_height(
+                COLLECTED_COINS_RECORDS_BLOCK_KEY,
+                &get_setting,
+            )?,
+            reward_formula_fix: activation_height(REWARD_FORMULA_FIX_BLOCK_KEY, &get_setting)?,
+            fee_amounts: activation_height(FEE_AMOUNTS_BLOCK_KEY, &get_setting)?,
+            auto_accept: activation_height(AUTO_ACCEPT_BLOCK_KEY, &get_setting)?,
+        })
+    }
+
+    pub fn activation(&self, feature: Feature) -> Option<u64> {
+        match feature {
+            Feature::DealExpirationRefund => self.deal_expiration_refund,
+            Feature::RewardFormulaUpdate1 => self.reward_formula_update1,
+            Feature::ExpiryIndex => self.expiry_index,
+            Feature::StateV2 => self.state_v2,
+            Feature::CollectedCoinsRecords => self.collected_coins_records,
+            Feature::RewardFormulaFix => self.reward_formula_fix,
+            Feature::FeeAmounts => self.fee_amounts,
+            Feature::AutoAccept => self.auto_accept,
+        }
+    }
+
+    pub fn is_active(&self, feature: Feature, height: u64) -> bool {
+        self.activation(feature)
+            .map_or(false, |activation| height >= activation)
+    }
+}
@@ -0,0 +1,164 @@
+use rug::Integer;
+
+use super::{constants::*, types::TxnResult};
+use crate::ext::IntegerExt;
+
+/// Processor parameters that governance can override through on-chain settings. A setting is
+/// a history of `value` or `value@height` entries, each effective from its block on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessorParams {
+    pub confirmation_count: u64,
+    pub year_of_blocks: u64,
+    pub block_reward_processing_count: u64,
+    pub blocks_in_period_update1: u64,
+    pub reward_amount: Integer,
+    pub deal_exp_fix_block: u64,
+    /// Sighash of the wallet receiving `treasury_share` basis points of every block reward.
+    pub treasury_sighash: Option<String>,
+    pub treasury_share: u64,
+    /// Entries housekeeping processes per prefix and run, or 0 to process every entry.
+    pub housekeeping_scan_limit: u64,
+    /// First block from which orders are tracked in the expiry index.
+    pub expiry_index_block: Option<u64>,
+    /// Blocks per fee aggregate, or 0 to record every fee in its own entry.
+    pub fee_bucket_blocks: u64,
+    /// First block from which state entries are written in the v2 encoding.
+    pub state_v2_block: Option<u64>,
+}
+
+impl Default for ProcessorParams {
+    fn default() -> Self {
+        Self {
+            confirmation_count: CONFIRMATION_COUNT,
+            year_of_blocks: YEAR_OF_BLOCKS,
+            block_reward_processing_count: BLOCK_REWARD_PROCESSING_COUNT,
+            blocks_in_period_update1: BLOCKS_IN_PERIOD_UPDATE1,
+            reward_amount: REWARD_AMOUNT.clone(),
+            deal_exp_fix_block: DEAL_EXP_FIX_BLOCK,
+            treasury_sighash: None,
+            treasury_share: 0,
+            housekeeping_scan_limit: 0,
+            expiry_index_block: None,
+            fee_bucket_blocks: 0,
+            state_v2_block: None,
+        }
+    }
+}
+
+impl ProcessorParams {
+    /// Resolves every parameter at `height`. A malformed setting entry is logged and skipped, so
+    /// a bad governance write leaves its parameter at the value before it instead of stalling
+    /// housekeeping.
+    pub fn resolve(
+        get_setting: impl Fn(&str) -> TxnResult<Option<String>>,
+        height: u64,
+    ) -> TxnResult<Self> {
+        let mut params = Self::default();
+
+        let number = |key: &str, value: &mut u64, min: u64| -> TxnResult<()> {
+            let parse = |raw: &str| raw.parse::<u64>().ok().filter(|&v| v >= min);
+            if let Some(parsed) = active_value(key, get_setting(key)?, height, parse) {
+                *value = parsed;
+            }
+            Ok(())
+        };
+        number(CONFIRMATION_COUNT_KEY, &mut params.confirmation_count, 1)?;
+        number(YEAR_OF_BLOCKS_KEY, &mut params.year_of_blocks, 1)?;
+        number(
+            BLOCK_REWARD_PROCESSING_COUNT_KEY,
+            &mut params.block_reward_processing_count,
+            1,
+        )?;
+        number(
+            BLOCKS_IN_PERIOD_UPDATE1_KEY,
+            &mut params.blocks_in_period_update1,
+            1,
+        )?;
+        number(DEAL_EXP_FIX_BLOCK_KEY, &mut params.deal_exp_fix_block, 0)?;
+        number(
+            HOUSEKEEPING_SCAN_LIMIT_KEY,
+            &mut params.housekeeping_scan_limit,
+            0,
+        )?;
+        number(FEE_BUCKET_BLOCKS_KEY, &mut params.fee_bucket_blocks, 0)?;
+
+        let reward_amount = get_setting(REWARD_AMOUNT_KEY)?;
+        if let Some(parsed) = active_value(REWARD_AMOUNT_KEY, reward_amount, height, |raw| {
+            Integer::try_parse(raw).ok()
+        }) {
+            params.reward_amount = parsed;
+        }
+
+        let treasury_share = get_setting(TREASURY_SHARE_KEY)?;
+        if let Some(parsed) = active_value(TREASURY_SHARE_KEY, treasury_share, height, |raw| {
+            raw.parse::<u64>().ok().filter(|&v| v <= BASIS_POINTS)
+        }) {
+            params.treasury_share = parsed;
+        }
+
+        let block = |key: &str, value: &mut Option<u64>| -> TxnResult<()> {
+            let parse = |raw: &str| raw.parse::<u64>().ok();
+            if let Some(parsed) = active_value(key, get_setting(key)?, height, parse) {
+                *value = Some(parsed);
+            }
+            Ok(())
+        };
+        block(EXPIRY_INDEX_BLOCK_KEY, &mut params.expiry_index_block)?;
+        block(STATE_V2_BLOCK_KEY, &mut params.state_v2_block)?;
+
+        let treasury_sighash = get_setting(TREASURY_SIGHASH_KEY)?;
+        params.treasury_sighash =
+            active_value(TREASURY_SIGHASH_KEY, treasury_sighash, height, |raw| {
+                let valid = raw.len() == 60
+                    && raw
+                        .bytes()
+                        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+                Some(raw.to_owned()).filter(|_| valid)
+            });
+
+        Ok(params)
+    }
+}
+
+/// The value of `setting` in effect at `height`. A setting is a comma separated history of
+/// `value` or `value@height` entries, and the entry with the latest activation at or before
+/// `height` wins. Entries with a malformed activation height, or a value `parse` rejects, are
+/// logged and skipped, leaving the entry before them in effect.
+fn active_value<T>(
+    key: &str,
+    setting: Option<String>,
+    height: u64,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<T> {
+    let setting = setting?;
+    let mut entries = vec![];
+    for entry in setting.split(',') {
+        let mut parts = entry.splitn(2, '@');
+        let value = parts.next().unwrap_or_default().trim();
+        let activation = match parts.next() {
+            Some(activation) => match activation.trim().parse() {
+                Ok(activation) => activation,
+                Err(_) => {
+                    log::warn!(
+                        "Ignoring entry of setting {} with an invalid activation height, found : {:?}",
+                        key, entry
+                    );
+                    continue;
+                }
+            },
+            None => 0,
+        };
+        if activation <= height {
+            entries.push((activation, value));
+        }
+    }
+    // the sort is stable, so of two entries with the same activation the later one wins
+    entries.sort_by_key(|&(activation, _)| activation);
+    entries.into_iter().rev().find_map(|(_, value)| {
+        let parsed = parse(value);
+        if parsed.is_none() {
+            log::warn!("Ignoring invalid setting {}, found : {:?}", key, value);
+        }
+        parsed
+    })
+}
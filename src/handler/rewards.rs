@@ -0,0 +1,168 @@
+use std::convert::TryFrom;
+
+use rug::Integer;
+
+use super::{
+    constants::REWARD_SCHEDULE_KEY,
+    types::{CCApplyError, TxnResult},
+};
+use crate::{bail_transaction, ext::IntegerExt};
+
+/// A block reward curve, configured through the `REWARD_SCHEDULE_KEY` setting as one of
+///
+/// * `flat:<amount>`
+/// * `decay:<initial>:<numerator>/<denominator>:<period>`
+/// * `halving:<initial>:<interval>`
+/// * `tail:<initial>:<interval>:<tail>:<cap>`
+///
+/// optionally followed by `@<height>`, the first rewarded block the schedule applies to. Blocks
+/// before it keep the legacy rewards.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RewardSchedule {
+    Flat {
+        amount: Integer,
+    },
+    /// Multiplies the reward by `numerator / denominator` every `period` blocks.
+    GeometricDecay {
+        initial: Integer,
+        numerator: u32,
+        denominator: u32,
+        period: u64,
+    },
+    /// Halves the reward every `interval` blocks.
+    StepHalving {
+        initial: Integer,
+        interval: u64,
+    },
+    /// Halves the reward every `interval` blocks down to `tail`, and stops once the minted supply
+    /// reaches `cap`. The minted supply starts from the `INITIAL_SUPPLY_KEY` setting, the coins
+    /// issued before a schedule was first configured.
+    TailEmission {
+        initial: Integer,
+        interval: u64,
+        tail: Integer,
+        cap: Integer,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveSchedule {
+    pub schedule: RewardSchedule,
+    pub activation: u64,
+}
+
+fn invalid(setting: &str) -> CCApplyError {
+    CCApplyError::InvalidTransaction(format!("Invalid reward schedule : {:?}", setting))
+}
+
+impl RewardSchedule {
+    pub fn resolve(
+        get_setting: impl Fn(&str) -> TxnResult<Option<String>>,
+    ) -> TxnResult<Option<ActiveSchedule>> {
+        let setting = match get_setting(REWARD_SCHEDULE_KEY)? {
+            Some(setting) => setting,
+            None => return Ok(None),
+        };
+        let mut parts = setting.splitn(2, '@');
+        let schedule =
+            Self::parse(parts.next().unwrap_or_default()).map_err(|_| invalid(&setting))?;
+        let activation = match parts.next() {
+            Some(height) => height.trim().parse().map_err(|_| invalid(&setting))?,
+            None => 0,
+        };
+        Ok(Some(ActiveSchedule {
+            schedule,
+            activation,
+        }))
+    }
+
+    pub fn parse(s: &str) -> TxnResult<Self> {
+        let fields: Vec<_> = s.trim().split(':').collect();
+        let integer = |i: usize| -> TxnResult<Integer> {
+            Integer::try_parse(fields.get(i).ok_or_else(|| invalid(s))?)
+        };
+        let positive = |i: usize| -> TxnResult<u64> {
+            let value = fields.get(i).ok_or_else(|| invalid(s))?;
+            let value = value.parse().ok().filter(|&v| v > 0);
+            Ok(value.ok_or_else(|| invalid(s))?)
+        };
+
+        let schedule = match (fields[0], fields.len()) {
+            ("flat", 2) => RewardSchedule::Flat {
+                amount: integer(1)?,
+            },
+            ("decay", 4) => {
+                let mut ratio = fields[2].splitn(2, '/').map(str::parse::<u32>);
+                let (numerator, denominator) = match (ratio.next(), ratio.next()) {
+                    (Some(Ok(n)), Some(Ok(d))) if n < d => (n, d),
+                    _ => bail_transaction!("Invalid reward schedule : {:?}", s),
+                };
+                RewardSchedule::GeometricDecay {
+                    initial: integer(1)?,
+                    numerator,
+                    denominator,
+                    period: positive(3)?,
+                }
+            }
+            ("halving", 3) => RewardSchedule::StepHalving {
+                initial: integer(1)?,
+                interval: positive(2)?,
+            },
+            ("tail", 5) => RewardSchedule::TailEmission {
+                initial: integer(1)?,
+                interval: positive(2)?,
+                tail: integer(3)?,
+                cap: integer(4)?,
+            },
+            _ => bail_transaction!("Invalid reward schedule : {:?}", s),
+        };
+        Ok(schedule)
+    }
+
+    /// The reward for a block `elapsed` blocks after the schedule activated, when the minted
+    /// supply is `minted`.
+    pub fn reward(&self, elapsed: u64, minted: &Integer) -> Integer {
+        match self {
+            RewardSchedule::Flat { amount } => amount.clone(),
+            RewardSchedule::GeometricDecay {
+                initial,
+                numerator,
+                denominator,
+                period,
+            } => decay(initial, *numerator, *denominator, elapsed / period),
+            RewardSchedule::StepHalving { initial, interval } => halve(initial, elapsed / interval),
+            RewardSchedule::TailEmission {
+                initial,
+                interval,
+                tail,
+                cap,
+            } => {
+                let reward = halve(initial, elapsed / interval).max(tail.clone());
+                let remaining = Integer::from(cap - minted).max(Integer::new());
+                reward.min(remaining)
+            }
+        }
+    }
+}
+
+/// `initial * (numerator / denominator)^periods`, rounded down.
+fn decay(initial: &Integer, numerator: u32, denominator: u32, periods: u64) -> Integer {
+    // (1 + 1/numerator)^numerator is at least 2, so every `numerator` periods at least halve the
+    // reward, and it is zero once the initial reward has been halved below one
+    let cutoff = u64::from(numerator) * u64::from(initial.significant_bits());
+    match u32::try_from(periods) {
+        Ok(0) => initial.clone(),
+        Ok(periods) if u64::from(periods) < cutoff => {
+            let numerator = Integer::from(Integer::u_pow_u(numerator, periods)) * initial;
+            numerator / Integer::from(Integer::u_pow_u(denominator, periods))
+        }
+        _ => Integer::new(),
+    }
+}
+
+fn halve(initial: &Integer, halvings: u64) -> Integer {
+    match u32::try_from(halvings) {
+        Ok(halvings) if halvings < initial.significant_bits() => Integer::from(initial >> halvings),
+        _ => Integer::new(),
+    }
+}
@@ -1,12 +1,14 @@
 #![cfg(all(test, feature = "mock"))]
 #![allow(non_snake_case, non_upper_case_globals)]
 
+mod golden;
 pub mod mocked;
 
 use mocked::{MockSettings, MockTransactionContext};
 use sawtooth_sdk::processor::handler::ApplyError;
 use serde_cbor::Value;
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::sync::Once;
 
@@ -18,7 +20,7 @@ use rug::Integer;
 use sawtooth_sdk::messages::processor::TpProcessRequest;
 use sawtooth_sdk::processor::handler::TransactionContext;
 
-use crate::ext::{IntegerExt, MessageExt};
+use crate::ext::{IntegerExt, MessageExt, Versioned};
 use crate::handler::constants::*;
 use crate::handler::types::{CCApplyError, SigHash};
 use crate::handler::types::{Guid, WalletId};
@@ -26,7 +28,11 @@ use crate::handler::utils::{self, calc_interest};
 use crate::{protos, string};
 
 use super::context::mocked::MockHandlerContext;
-use super::types::{Address, TxnResult};
+use super::encoding::{self, STATE_V2};
+use super::forks::{self, Feature, ForkSchedule};
+use super::params::ProcessorParams;
+use super::rewards::{ActiveSchedule, RewardSchedule};
+use super::types::{Address, Rate, TxnResult};
 use super::AddAskOrder;
 use super::AddBidOrder;
 use super::AddDealOrder;
@@ -42,7 +48,12 @@ use super::LockDealOrder;
 use super::RegisterAddress;
 use super::RegisterTransfer;
 use super::SendFunds;
-use super::{CCTransaction, Housekeeping};
+use super::SendFundsBatch;
+use super::{Approve, SendFundsFrom};
+use super::{ApproveSpend, CreateMultisig, ExecuteSpend, ProposeSpend};
+use super::{BurnCoins, ConfirmBurn};
+use super::{CCTransaction, Housekeeping, MigrateState};
+use super::{ClaimVested, CreateVesting};
 
 use once_cell::sync::Lazy;
 
@@ -233,6 +244,7 @@ command!(Three, P1, P2, P3);
 command!(Four, P1, P2, P3, P4);
 command!(Five, P1, P2, P3, P4, P5);
 command!(Six, P1, P2, P3, P4, P5, P6);
+command!(Seven, P1, P2, P3, P4, P5, P6, P7);
 
 #[track_caller]
 fn deserialize_success(value: impl Serialize, expected: impl Into<CCCommand>) {
@@ -300,6 +312,60 @@ fn send_funds_rejects_missing_arg() {
     deserialize_failure(ZeroArgCommand::new("SendFunds"), "Expecting amount");
 }
 
+// SendFundsBatch
+
+fn send_funds_batch_value(transfers: Vec<Value>) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert(
+        Value::Text("v".into()),
+        Value::Text("SendFundsBatch".into()),
+    );
+    map.insert(Value::Text("p1".into()), Value::Array(transfers));
+    Value::Map(map)
+}
+
+fn transfer_pair(sighash: &str, amount: &str) -> Value {
+    Value::Array(vec![
+        Value::Text(sighash.into()),
+        Value::Text(amount.into()),
+    ])
+}
+
+#[test]
+fn send_funds_batch_accept() {
+    deserialize_success(
+        send_funds_batch_value(vec![transfer_pair("foo", "1"), transfer_pair("bar", "2")]),
+        SendFundsBatch {
+            transfers: vec![("foo".into(), 1.into()), ("bar".into(), 2.into())],
+        },
+    )
+}
+
+#[test]
+fn send_funds_batch_rejects_negative() {
+    deserialize_failure(
+        send_funds_batch_value(vec![transfer_pair("foo", "-1")]),
+        NEGATIVE_NUMBER_ERR,
+    );
+}
+
+#[test]
+fn send_funds_batch_rejects_malformed_entry() {
+    deserialize_failure(
+        send_funds_batch_value(vec![Value::Text("foo".into())]),
+        "Entries of transfers must be [sighash, amount] pairs, found : Text(\"foo\")",
+    );
+}
+
+#[test]
+fn send_funds_batch_rejects_empty() {
+    deserialize_failure(
+        send_funds_batch_value(vec![]),
+        "Expecting at least one entry in transfers",
+    );
+    deserialize_failure(ZeroArgCommand::new("SendFundsBatch"), "Expecting transfers");
+}
+
 // RegisterAddress
 
 #[test]
@@ -557,6 +623,7 @@ fn add_bid_order_accept() {
         maturity: 3.to_string(),
         fee: 4.to_string(),
         expiration: 5,
+        auto_accept: false,
     };
     deserialize_success(args, expected.clone());
     deserialize_success(args_uppercase, expected.clone());
@@ -572,6 +639,7 @@ fn add_bid_order_case_insensitive() {
         maturity: 3.to_string(),
         fee: 4.to_string(),
         expiration: 5,
+        auto_accept: false,
     };
     deserialize_success(args, expected);
 }
@@ -681,6 +749,35 @@ fn add_bid_order_missing_arg() {
     deserialize_failure(ZeroArgCommand::new("AddBidOrder"), "Expecting addressId");
 }
 
+#[test]
+fn add_bid_order_auto_accept() {
+    let expected = AddBidOrder {
+        address_id: "addressid".into(),
+        amount_str: 1.to_string(),
+        interest: 2.to_string(),
+        maturity: 3.to_string(),
+        fee: 4.to_string(),
+        expiration: 5,
+        auto_accept: true,
+    };
+    deserialize_success(
+        SevenArgCommand::new("AddBidOrder", "addressid", 1, 2, 3, 4, 5, "true"),
+        expected.clone(),
+    );
+    deserialize_success(
+        SevenArgCommand::new("AddBidOrder", "addressid", 1, 2, 3, 4, 5, 1),
+        expected,
+    );
+}
+
+#[test]
+fn add_bid_order_invalid_auto_accept() {
+    deserialize_failure(
+        SevenArgCommand::new("AddBidOrder", "addressid", 1, 2, 3, 4, 5, "maybe"),
+        "Value for autoAccept was not a boolean, found : \"maybe\"",
+    );
+}
+
 // AddOffer
 
 #[test]
@@ -1161,1914 +1258,5554 @@ fn collect_coins_missing_arg() {
     deserialize_failure(ZeroArgCommand::new("CollectCoins"), "Expecting ethAddress");
 }
 
-// Housekeeping
-
-#[test]
-fn housekeeping_accept() {
-    deserialize_success(
-        OneArgCommand::new("Housekeeping", 1),
-        CCCommand::Housekeeping(Housekeeping {
-            block_idx: 1.into(),
-        }),
-    )
-}
-
 #[test]
-fn housekeeping_case_insensitive() {
+fn burn_coins_accept() {
     deserialize_success(
-        OneArgCommand::new("HoUsEkEePiNg", 1),
-        CCCommand::Housekeeping(Housekeeping {
-            block_idx: 1.into(),
-        }),
-    )
-}
-
-#[test]
-fn housekeeping_negative_block_idx() {
-    deserialize_failure(OneArgCommand::new("Housekeeping", -1), NEGATIVE_NUMBER_ERR);
+        TwoArgCommand::new("BurnCoins", "ETHADDRESS", 1),
+        BurnCoins {
+            eth_address: "ethaddress".into(),
+            amount: 1.into(),
+        },
+    );
 }
 
 #[test]
-fn housekeeping_invalid_block_idx() {
+fn burn_coins_negative_amount() {
     deserialize_failure(
-        OneArgCommand::new("Housekeeping", "BAD"),
-        INVALID_NUMBER_FORMAT_ERR,
+        TwoArgCommand::new("BurnCoins", "ethaddress", -1),
+        NEGATIVE_NUMBER_ERR,
     );
 }
 
 #[test]
-fn housekeeping_rejects_missing_arg() {
-    deserialize_failure(ZeroArgCommand::new("Housekeeping"), "Expecting blockIdx");
-}
-
-fn make_fee(guid: &Guid, sighash: &SigHash, block: Option<u64>) -> (String, Vec<u8>) {
-    let fee_id = Address::with_prefix_key(super::constants::FEE, guid.as_str());
-    let fee = crate::protos::Fee {
-        sighash: sighash.clone().into(),
-        block: block.unwrap_or_default().to_string(),
-    };
-    (fee_id.to_string(), fee.to_bytes())
-}
-
-fn expect_set_state_entries(tx_ctx: &mut MockTransactionContext, entries: Vec<(String, Vec<u8>)>) {
-    expect!(tx_ctx, set_state_entries where {
-        let entries = entries.into_iter().sorted().collect_vec();
-        move |e| {
-            let s = itertools::sorted(e.clone()).collect_vec();
-            for (entry, other) in entries.iter().zip(&s) {
-                if entry != other {
-                    println!("Not equal! Expected {:?} -- Found {:?}", entry, other);
-                    return false;
-                }
-            }
-            if entries.len() != s.len() {
-                println!("Unequal lengths! Expected {:?} -- Found {:?}", entries.len(), s.len());
-                return false;
-            }
-            true
-        }
-    }, returning |_| Ok(()));
-}
-
-fn expect_delete_state_entries(tx_ctx: &mut MockTransactionContext, entries: Vec<String>) {
-    tx_ctx
-        .expect_delete_state_entries()
-        .once()
-        .withf({
-            let entries = entries.into_iter().sorted().collect_vec();
-            move |e| {
-                let s = itertools::sorted(e.clone()).collect_vec();
-                for (entry, &other) in entries.iter().zip(&s) {
-                    if entry != other {
-                        println!("Not equal! Expected {:?} -- Found {:?}", entry, other);
-                        return false;
-                    }
-                }
-                if entries.len() != s.len() {
-                    println!(
-                        "Unequal lengths! Expected {:?} -- Found {:?}",
-                        entries.len(),
-                        s.len()
-                    );
-                    return false;
-                }
-                true
-            }
-        })
-        .returning(|_| Ok(Vec::new()));
-}
-
-// ----- COMMAND EXECUTION TESTS -----
-#[track_caller]
-fn execute_success(
-    command: impl CCTransaction,
-    request: &TpProcessRequest,
-    tx_ctx: &MockTransactionContext,
-    ctx: &mut MockHandlerContext,
-) {
-    command.execute(request, tx_ctx, ctx).unwrap();
+fn confirm_burn_accept() {
+    deserialize_success(
+        TwoArgCommand::new("ConfirmBurn", "BURNID", "BLOCKCHAINID"),
+        ConfirmBurn {
+            burn_id: "burnid".into(),
+            blockchain_tx_id: "blockchainid".into(),
+        },
+    );
+    deserialize_failure(
+        OneArgCommand::new("ConfirmBurn", "burnid"),
+        "Expecting blockchainTxId",
+    );
 }
 
-#[track_caller]
-fn execute_failure(
-    command: impl CCTransaction,
-    request: &TpProcessRequest,
-    tx_ctx: &MockTransactionContext,
-    ctx: &mut MockHandlerContext,
-    expected_err: &str,
-) {
-    let result = command.execute(request, tx_ctx, ctx).unwrap_err();
-    match result.downcast_ref::<CCApplyError>() {
-        Some(CCApplyError::InvalidTransaction(s)) => {
-            assert_eq!(s, expected_err);
-        }
-        _ => panic!("Expected an InvalidTransaction error"),
-    };
+fn expect_bridge_settings(ctx: &mut MockHandlerContext, bridge: Option<[&'static str; 3]>) {
+    let keys = [
+        BRIDGE_BLOCKCHAIN_KEY,
+        BRIDGE_NETWORK_KEY,
+        BRIDGE_CONTRACT_KEY,
+    ];
+    for (i, &key) in keys.iter().enumerate() {
+        let value = bridge.map(|b| b[i].to_owned());
+        expect!(ctx, get_setting where move |k| k == key, returning move |_| Ok(value));
+    }
 }
 
-// --- SendFunds ---
-
 #[test]
-fn send_funds_success() {
+fn collect_coins_success() {
     init_logs();
-    let destination = SigHash("destination".into());
-    let command = SendFunds {
+
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
         amount: 1.into(),
-        sighash: destination.clone(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
     let request = TpProcessRequest::default();
-
     let mut tx_ctx = MockTransactionContext::default();
-
-    let my_sighash = SigHash("mysighash".into());
-    let my_wallet_id = WalletId::from(&my_sighash);
-    let dest_wallet_id = WalletId::from(&destination);
-
     let mut ctx = MockHandlerContext::default();
-    expect!(ctx, sighash -> my_sighash);
 
-    let amount_needed = command.amount.clone() + &*TX_FEE;
-
-    expect!(tx_ctx, get balance at my_wallet_id -> Some(amount_needed));
-    expect!(tx_ctx, get balance at dest_wallet_id -> Some(0));
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+    expect_bridge_settings(&mut ctx, None);
+    expect!(ctx, verify where |c| c == "ethereum verify ethaddress creditcoin mysighash 1 blockchainid unused", returning |_| Ok(()));
+    expect_forks_unset(&mut ctx);
 
-    let guid = Guid("txnguid".into());
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(None));
 
-    expect!(ctx, guid -> guid);
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(2));
 
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (my_wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            (dest_wallet_id.to_string(), wallet_with(Some(1)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
+            (wallet_id.to_string(), wallet_with(Some(3)).unwrap()),
+            (erc20_id.to_string(), b"1".to_vec()),
         ],
     );
 
-    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+    execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
 #[test]
-fn send_funds_cannot_afford_fee() {
+fn collect_coins_configured_bridge() {
     init_logs();
 
-    let destination = SigHash::from("destination");
-    let command = SendFunds {
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
         amount: 1.into(),
-        sighash: destination.clone(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
     let request = TpProcessRequest::default();
-
     let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
 
     let my_sighash = SigHash::from("mysighash");
-    let my_wallet_id = WalletId::from(&my_sighash);
-
-    let mut ctx = MockHandlerContext::default();
     expect!(ctx, sighash -> my_sighash);
+    expect_bridge_settings(&mut ctx, Some(["ethereum", "goerli", "0xbridge"]));
+    expect!(ctx, verify where |c| c == "ethereum verify ethaddress 0xbridge mysighash 1 blockchainid goerli", returning |_| Ok(()));
+    expect_forks_unset(&mut ctx);
 
-    expect!(tx_ctx, get balance at my_wallet_id -> Some(1));
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(None));
 
-    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id, returning |_| Ok(None));
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(1)).unwrap()),
+            (erc20_id.to_string(), b"1".to_vec()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
 #[test]
-fn send_funds_cannot_afford_amount() {
+fn collect_coins_stores_record() {
     init_logs();
 
-    let destination = SigHash::from("destination");
-    let command = SendFunds {
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
         amount: 1.into(),
-        sighash: destination.clone(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
-    let request = TpProcessRequest::default();
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
+    };
 
     let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
 
     let my_sighash = SigHash::from("mysighash");
-    let my_wallet_id = WalletId::from(&my_sighash);
-
-    let mut ctx = MockHandlerContext::default();
     expect!(ctx, sighash -> my_sighash);
+    expect_bridge_settings(&mut ctx, None);
+    expect!(ctx, verify(_) -> Ok(()));
+    expect_fork_settings(&mut ctx, &[(COLLECTED_COINS_RECORDS_BLOCK_KEY, "11")]);
 
-    expect!(tx_ctx, get balance at my_wallet_id -> Some(TX_FEE.clone()));
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(None));
 
-    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id, returning |_| Ok(None));
+
+    let collected = protos::CollectedCoins {
+        eth_address: "ethaddress".into(),
+        sighash: "mysighash".into(),
+        amount: "1".into(),
+        blockchain_tx_id: "blockchainid".into(),
+        block: "10".into(),
+    };
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(1)).unwrap()),
+            (erc20_id.to_string(), collected.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
 #[test]
-fn send_funds_to_self() {
-    init_logs();
+fn parse_collected_coins_legacy_and_record() {
+    let legacy = super::parse_collected_coins(b"1000").unwrap();
+    assert_eq!(legacy.amount, "1000");
+    assert_eq!(legacy.sighash, "");
 
-    let destination = SigHash::from("mysighash");
-    let command = SendFunds {
-        amount: 1.into(),
-        sighash: destination.clone(),
+    let record = protos::CollectedCoins {
+        eth_address: "ethaddress".into(),
+        sighash: "mysighash".into(),
+        amount: "1000".into(),
+        blockchain_tx_id: "blockchainid".into(),
+        block: "10".into(),
     };
-
-    let request = TpProcessRequest::default();
-
-    let tx_ctx = MockTransactionContext::default();
-
-    let my_sighash = SigHash::from("mysighash");
-
-    let mut ctx = MockHandlerContext::default();
-    expect!(ctx, sighash -> my_sighash);
-
-    execute_failure(command, &request, &tx_ctx, &mut ctx, "Invalid destination");
-}
-
-// --- RegisterAddress ---
-
-fn charge_fee(tx_ctx: &mut MockTransactionContext, sighash: &SigHash) {
-    let wallet_id = WalletId::from(sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+    assert_eq!(
+        super::parse_collected_coins(&record.to_bytes()).unwrap(),
+        record
+    );
 }
 
 #[test]
-fn register_address_success() {
+fn collect_coins_already_collected() {
     init_logs();
 
-    let command = RegisterAddress {
-        blockchain: "ethereum".into(),
-        address: "myaddress".into(),
-        network: "rinkeby".into(),
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
+        amount: 1.into(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
     let request = TpProcessRequest::default();
-
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let my_sighash = SigHash::from("mysighash");
-    let guid = Guid::from("myguid");
-
-    expect!(ctx, sighash -> my_sighash);
-    expect!(ctx, guid -> guid);
-
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
-
-    let register_id = string!("ethereum", "myaddress", "rinkeby");
-    let address = Address::with_prefix_key(ADDR, &register_id);
-
-    // check if there is an existing address, in this case we will pretend that there is not one
-    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| &a == &address.as_str()), returning |_| Ok(None));
-
-    let address_proto = crate::protos::Address {
-        blockchain: command.blockchain.clone(),
-        value: command.address.clone(),
-        network: command.network.clone(),
-        sighash: my_sighash.to_string(),
-    };
+    expect_bridge_settings(&mut ctx, None);
 
-    expect_set_state_entries(
-        &mut tx_ctx,
-        vec![
-            (address.to_string(), address_proto.to_bytes()),
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
-        ],
-    );
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(Some(b"1".to_vec())));
 
-    execute_success(command, &request, &tx_ctx, &mut ctx);
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Already collected");
 }
 
 #[test]
-fn register_address_taken() {
+fn collect_coins_already_collected_before_bridge_moved() {
     init_logs();
 
-    let command = RegisterAddress {
-        blockchain: "ethereum".into(),
-        address: "myaddress".into(),
-        network: "rinkeby".into(),
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
+        amount: 1.into(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
     let request = TpProcessRequest::default();
-
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let my_sighash = SigHash::from("mysighash");
-
-    expect!(ctx, sighash -> my_sighash);
-
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
-
-    let register_id = string!("ethereum", "myaddress", "rinkeby");
-    let address = Address::with_prefix_key(ADDR, &register_id);
+    expect_bridge_settings(&mut ctx, Some(["ethereum", "goerli", "0xbridge"]));
 
-    let existing_address = protos::Address {
-        blockchain: command.blockchain.clone(),
-        value: command.address.clone(),
-        network: command.network.clone(),
-        sighash: my_sighash.to_string(),
-    };
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(Some(b"1".to_vec())));
 
-    // check if there is an existing address, in this case we will pretend that there IS one
-    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| &a == &address.as_str()), returning move |_| Ok(Some(existing_address.to_bytes())));
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Already collected");
+}
 
-    execute_failure(
-        command,
-        &request,
-        &tx_ctx,
-        &mut ctx,
-        "The address has been already registered",
+#[cfg(not(feature = "dev-bypass-verification"))]
+#[test]
+fn collect_coins_rejects_bypass_address() {
+    deserialize_failure(
+        ThreeArgCommand::new("CollectCoins", "UNUSED_IF_HACKED", 1, "blockchainid"),
+        "Invalid ethAddress",
     );
 }
 
+#[cfg(feature = "dev-bypass-verification")]
 #[test]
-fn register_transfer_success() {
+fn collect_coins_bypasses_allowlisted_sighash() {
     init_logs();
 
-    let command = RegisterTransfer {
-        gain: 1.into(),
-        order_id: string!(DEAL_ORDER_PREFIX, "orderid"),
-        blockchain_tx_id: "blockchaintxid".into(),
+    let command = CollectCoins {
+        eth_address: "ethaddress".into(),
+        amount: 1.into(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
     let request = TpProcessRequest::default();
-
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let my_sighash = SigHash::from("mysighash");
-    let other_sighash = SigHash::from("othersighash");
-
+    let my_sighash = SigHash::from("tester");
     expect!(ctx, sighash -> my_sighash);
+    expect_bridge_settings(&mut ctx, None);
+    expect!(ctx, get_setting where |k| k == DEV_BYPASS_SIGHASHES_KEY, returning |_| Ok(Some("other, tester".into())));
+    expect_forks_unset(&mut ctx);
 
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
-
-    let src_address_id = "srcaddress".to_string();
-    let dst_address_id = "destaddress".to_string();
-
-    let order_address = command.order_id.clone();
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: src_address_id.clone(),
-        dst_address: dst_address_id.clone(),
-        amount: 1.to_string(),
-        sighash: my_sighash.to_string(),
-        ..Default::default()
-    };
-
-    expect!(tx_ctx, get_state_entry where enclose!((order_address => address) move |a| &a == &address.as_str()), returning enclose!((deal_order) move |_| Ok(Some(deal_order.to_bytes()))));
-
-    let src_address = protos::Address {
-        blockchain: deal_order.blockchain.clone(),
-        value: "aaaaaaaaaa".into(),
-        network: "rinkeby".into(),
-        sighash: other_sighash.to_string(),
-    };
-    let dst_address = protos::Address {
-        blockchain: deal_order.blockchain.clone(),
-        value: "aaaaaaaaaa".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.to_string(),
-    };
-
-    expect!(tx_ctx, get_state_entry where enclose!((dst_address_id => address) move |a| &a == &address), returning enclose!((dst_address) move |_| Ok(Some(dst_address.to_bytes()))));
-    expect!(tx_ctx, get_state_entry where enclose!((src_address_id => address) move |a| &a == &address), returning enclose!((src_address) move |_| Ok(Some(src_address.to_bytes()))));
-
-    let transfer_id = Address::with_prefix_key(
-        TRANSFER,
-        &string!(
-            &src_address.blockchain,
-            &command.blockchain_tx_id,
-            &src_address.network
-        ),
-    );
-
-    let transfer = protos::Transfer {
-        blockchain: src_address.blockchain.clone(),
-        src_address: dst_address_id.clone(),
-        dst_address: src_address_id.clone(),
-        order: command.order_id.clone(),
-        amount: (command.gain.clone() + 1u64).to_string(),
-        tx: command.blockchain_tx_id.clone(),
-        sighash: my_sighash.to_string(),
-        block: 0.to_string(),
-        processed: false,
-    };
-
-    expect!(tx_ctx, get_state_entry where enclose!((transfer_id => tf_id) move |a| &a == &tf_id.as_str()), returning move |_| Ok(None));
-
-    expect!(ctx, verify(_) -> Ok(()));
-
-    let guid = Guid::from("guid");
+    let erc20_id = Address::with_prefix_key(ERC20, "blockchainid");
+    expect!(tx_ctx, get_state_entry where enclose!((erc20_id) move |a| a == erc20_id.as_str()), returning |_| Ok(None));
 
-    expect!(ctx, guid -> guid);
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id, returning |_| Ok(None));
 
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (transfer_id.to_string(), transfer.to_bytes()),
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
+            (wallet_id.to_string(), wallet_with(Some(1)).unwrap()),
+            (erc20_id.to_string(), b"1".to_vec()),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- AddAskOrder ---
+// Housekeeping
 
 #[test]
-fn add_ask_order_success() {
-    init_logs();
-
-    let command = AddAskOrder {
-        address_id: "addressid".into(),
-        amount_str: "1000".into(),
-        interest: "10000".into(),
-        maturity: "100".into(),
-        fee: "1".into(),
-        expiration: 10000,
-    };
+fn housekeeping_accept() {
+    deserialize_success(
+        OneArgCommand::new("Housekeeping", 1),
+        CCCommand::Housekeeping(Housekeeping {
+            block_idx: 1.into(),
+        }),
+    )
+}
 
-    let request = TpProcessRequest {
-        tip: 1,
-        ..Default::default()
-    };
+#[test]
+fn housekeeping_case_insensitive() {
+    deserialize_success(
+        OneArgCommand::new("HoUsEkEePiNg", 1),
+        CCCommand::Housekeeping(Housekeeping {
+            block_idx: 1.into(),
+        }),
+    )
+}
 
-    let mut tx_ctx = MockTransactionContext::default();
-    let mut ctx = MockHandlerContext::default();
+#[test]
+fn housekeeping_negative_block_idx() {
+    deserialize_failure(OneArgCommand::new("Housekeeping", -1), NEGATIVE_NUMBER_ERR);
+}
 
-    let my_sighash = SigHash::from("mysighash");
-    expect!(ctx, sighash -> my_sighash);
+#[test]
+fn housekeeping_invalid_block_idx() {
+    deserialize_failure(
+        OneArgCommand::new("Housekeeping", "BAD"),
+        INVALID_NUMBER_FORMAT_ERR,
+    );
+}
 
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
-    expect!(ctx, guid -> guid);
+#[test]
+fn housekeeping_rejects_missing_arg() {
+    deserialize_failure(ZeroArgCommand::new("Housekeeping"), "Expecting blockIdx");
+}
 
-    let address = Address::with_prefix_key(ASK_ORDER, guid.as_str());
+// MigrateState
 
-    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| a == address.as_str()), returning |_| Ok(None));
+#[test]
+fn migrate_state_accept() {
+    deserialize_success(
+        ZeroArgCommand::new("MigrateState"),
+        CCCommand::MigrateState(MigrateState),
+    )
+}
 
-    let address_proto = protos::Address {
-        blockchain: "ethereum".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.clone().into(),
-        value: "somevalue".into(),
-    };
+// CreateMultisig
 
-    expect!(tx_ctx,
-        get_state_entry
-            where enclose!((command.address_id => address_id) move |a| a == &address_id),
-        returning enclose!((my_sighash, address_proto) move |_| Ok(Some(
-            address_proto.to_bytes()
-        )))
+#[test]
+fn create_multisig_accept() {
+    let mut map = BTreeMap::new();
+    map.insert(
+        Value::Text("v".into()),
+        Value::Text("CreateMultisig".into()),
     );
+    map.insert(
+        Value::Text("p1".into()),
+        Value::Array(vec![Value::Text("foo".into()), Value::Text("bar".into())]),
+    );
+    map.insert(Value::Text("p2".into()), Value::Text("2".into()));
+    deserialize_success(
+        Value::Map(map),
+        CreateMultisig {
+            members: vec!["foo".into(), "bar".into()],
+            threshold: 2,
+        },
+    )
+}
+
+#[test]
+fn create_multisig_rejects_non_list() {
+    deserialize_failure(
+        TwoArgCommand::new("CreateMultisig", "foo", 1),
+        "Value for members was not a list, found : Text(\"foo\")",
+    );
+}
+
+// ProposeSpend
+
+#[test]
+fn propose_spend_accept() {
+    deserialize_success(
+        FourArgCommand::new("ProposeSpend", "multisig", "destination", 1, 100),
+        ProposeSpend {
+            multisig: "multisig".into(),
+            sighash: "destination".into(),
+            amount: 1.into(),
+            expiration: 100,
+        },
+    )
+}
+
+#[test]
+fn propose_spend_rejects_negative() {
+    deserialize_failure(
+        FourArgCommand::new("ProposeSpend", "multisig", "destination", -1, 100),
+        NEGATIVE_NUMBER_ERR,
+    );
+}
+
+// ApproveSpend / ExecuteSpend
+
+#[test]
+fn approve_spend_accept() {
+    deserialize_success(
+        OneArgCommand::new("ApproveSpend", "PROPOSALID"),
+        ApproveSpend {
+            proposal_id: "proposalid".into(),
+        },
+    )
+}
+
+#[test]
+fn create_vesting_accept() {
+    deserialize_success(
+        FourArgCommand::new("CreateVesting", "beneficiary", 1000, 10, 100),
+        CreateVesting {
+            sighash: "beneficiary".into(),
+            amount: 1000.into(),
+            cliff: 10,
+            duration: 100,
+        },
+    )
+}
+
+#[test]
+fn create_vesting_rejects_missing_arg() {
+    deserialize_failure(
+        ThreeArgCommand::new("CreateVesting", "beneficiary", 1000, 10),
+        "Expecting duration",
+    );
+}
+
+#[test]
+fn claim_vested_accept() {
+    deserialize_success(
+        OneArgCommand::new("ClaimVested", "VESTINGID"),
+        ClaimVested {
+            vesting_id: "vestingid".into(),
+        },
+    )
+}
+
+#[test]
+fn approve_accept() {
+    deserialize_success(
+        TwoArgCommand::new("Approve", "spender", 1000),
+        Approve {
+            spender: "spender".into(),
+            amount: 1000.into(),
+        },
+    )
+}
+
+#[test]
+fn send_funds_from_accept() {
+    deserialize_success(
+        ThreeArgCommand::new("SendFundsFrom", "owner", "receiver", 1000),
+        SendFundsFrom {
+            owner: "owner".into(),
+            sighash: "receiver".into(),
+            amount: 1000.into(),
+        },
+    )
+}
+
+#[test]
+fn send_funds_from_rejects_missing_arg() {
+    deserialize_failure(
+        TwoArgCommand::new("SendFundsFrom", "owner", "receiver"),
+        "Expecting amount",
+    );
+}
+
+#[test]
+fn execute_spend_accept() {
+    deserialize_success(
+        OneArgCommand::new("ExecuteSpend", "PROPOSALID"),
+        ExecuteSpend {
+            proposal_id: "proposalid".into(),
+        },
+    )
+}
+
+fn make_fee(guid: &Guid, sighash: &SigHash, block: Option<u64>) -> (String, Vec<u8>) {
+    let fee_id = Address::with_prefix_key(super::constants::FEE, guid.as_str());
+    let fee = crate::protos::Fee {
+        sighash: sighash.clone().into(),
+        block: block.unwrap_or_default().to_string(),
+        ..Default::default()
+    };
+    (fee_id.to_string(), fee.to_bytes())
+}
+
+fn expect_set_state_entries(tx_ctx: &mut MockTransactionContext, entries: Vec<(String, Vec<u8>)>) {
+    expect!(tx_ctx, set_state_entries where {
+        let entries = entries.into_iter().sorted().collect_vec();
+        move |e| {
+            let s = itertools::sorted(e.clone()).collect_vec();
+            for (entry, other) in entries.iter().zip(&s) {
+                if entry != other {
+                    println!("Not equal! Expected {:?} -- Found {:?}", entry, other);
+                    return false;
+                }
+            }
+            if entries.len() != s.len() {
+                println!("Unequal lengths! Expected {:?} -- Found {:?}", entries.len(), s.len());
+                return false;
+            }
+            true
+        }
+    }, returning |_| Ok(()));
+}
+
+fn expect_delete_state_entries(tx_ctx: &mut MockTransactionContext, entries: Vec<String>) {
+    tx_ctx
+        .expect_delete_state_entries()
+        .once()
+        .withf({
+            let entries = entries.into_iter().sorted().collect_vec();
+            move |e| {
+                let s = itertools::sorted(e.clone()).collect_vec();
+                for (entry, &other) in entries.iter().zip(&s) {
+                    if entry != other {
+                        println!("Not equal! Expected {:?} -- Found {:?}", entry, other);
+                        return false;
+                    }
+                }
+                if entries.len() != s.len() {
+                    println!(
+                        "Unequal lengths! Expected {:?} -- Found {:?}",
+                        entries.len(),
+                        s.len()
+                    );
+                    return false;
+                }
+                true
+            }
+        })
+        .returning(|_| Ok(Vec::new()));
+}
+
+// ----- COMMAND EXECUTION TESTS -----
+#[track_caller]
+fn execute_success(
+    command: impl CCTransaction,
+    request: &TpProcessRequest,
+    tx_ctx: &MockTransactionContext,
+    ctx: &mut MockHandlerContext,
+) {
+    command.execute(request, tx_ctx, ctx).unwrap();
+}
+
+#[track_caller]
+fn execute_failure(
+    command: impl CCTransaction,
+    request: &TpProcessRequest,
+    tx_ctx: &MockTransactionContext,
+    ctx: &mut MockHandlerContext,
+    expected_err: &str,
+) {
+    let result = command.execute(request, tx_ctx, ctx).unwrap_err();
+    match result.downcast_ref::<CCApplyError>() {
+        Some(CCApplyError::InvalidTransaction(s)) => {
+            assert_eq!(s, expected_err);
+        }
+        _ => panic!("Expected an InvalidTransaction error"),
+    };
+}
+
+// --- SendFunds ---
+
+#[test]
+fn send_funds_success() {
+    init_logs();
+    let destination = SigHash("destination".into());
+    let command = SendFunds {
+        amount: 1.into(),
+        sighash: destination.clone(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash("mysighash".into());
+    let my_wallet_id = WalletId::from(&my_sighash);
+    let dest_wallet_id = WalletId::from(&destination);
+
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx, sighash -> my_sighash);
+
+    let amount_needed = command.amount.clone() + &*TX_FEE;
+
+    expect!(tx_ctx, get balance at my_wallet_id -> Some(amount_needed));
+    expect!(tx_ctx, get balance at dest_wallet_id -> Some(0));
+
+    let guid = Guid("txnguid".into());
+
+    expect!(ctx, guid -> guid);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (my_wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            (dest_wallet_id.to_string(), wallet_with(Some(1)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+}
+
+#[test]
+fn send_funds_cannot_afford_fee() {
+    init_logs();
+
+    let destination = SigHash::from("destination");
+    let command = SendFunds {
+        amount: 1.into(),
+        sighash: destination.clone(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+    let my_wallet_id = WalletId::from(&my_sighash);
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    expect!(tx_ctx, get balance at my_wallet_id -> Some(1));
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+}
+
+#[test]
+fn send_funds_cannot_afford_amount() {
+    init_logs();
+
+    let destination = SigHash::from("destination");
+    let command = SendFunds {
+        amount: 1.into(),
+        sighash: destination.clone(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+    let my_wallet_id = WalletId::from(&my_sighash);
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    expect!(tx_ctx, get balance at my_wallet_id -> Some(TX_FEE.clone()));
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+}
+
+#[test]
+fn send_funds_to_self() {
+    init_logs();
+
+    let destination = SigHash::from("mysighash");
+    let command = SendFunds {
+        amount: 1.into(),
+        sighash: destination.clone(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Invalid destination");
+}
+
+// --- SendFundsBatch ---
+
+#[test]
+fn send_funds_batch_success() {
+    init_logs();
+    let existing = SigHash::from("existing");
+    let created = SigHash::from("created");
+    let command = SendFundsBatch {
+        transfers: vec![(existing.clone(), 2.into()), (created.clone(), 3.into())],
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+    let my_wallet_id = WalletId::from(&my_sighash);
+    let existing_wallet_id = WalletId::from(&existing);
+    let created_wallet_id = WalletId::from(&created);
+
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx, sighash -> my_sighash);
+
+    let amount_needed = Integer::from(5) + &*TX_FEE;
+
+    expect!(tx_ctx, get balance at my_wallet_id -> Some(amount_needed));
+    expect!(tx_ctx, get balance at existing_wallet_id -> Some(10));
+    expect!(tx_ctx, get balance at created_wallet_id, returning |_| Ok(None));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (my_wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            (
+                existing_wallet_id.to_string(),
+                wallet_with(Some(12)).unwrap(),
+            ),
+            (created_wallet_id.to_string(), wallet_with(Some(3)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn send_funds_batch_cannot_afford_total() {
+    init_logs();
+
+    let command = SendFundsBatch {
+        transfers: vec![
+            (SigHash::from("first"), 1.into()),
+            (SigHash::from("second"), 1.into()),
+        ],
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+    let my_wallet_id = WalletId::from(&my_sighash);
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    let balance = Integer::from(1) + &*TX_FEE;
+    expect!(tx_ctx, get balance at my_wallet_id -> Some(balance));
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+}
+
+#[test]
+fn send_funds_batch_duplicate_destination() {
+    init_logs();
+
+    let command = SendFundsBatch {
+        transfers: vec![
+            (SigHash::from("destination"), 1.into()),
+            (SigHash::from("other"), 1.into()),
+            (SigHash::from("destination"), 2.into()),
+        ],
+    };
+
+    let request = TpProcessRequest::default();
+
+    let tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "Duplicate destination",
+    );
+}
+
+#[test]
+fn send_funds_batch_to_self() {
+    init_logs();
+
+    let command = SendFundsBatch {
+        transfers: vec![
+            (SigHash::from("destination"), 1.into()),
+            (SigHash::from("mysighash"), 1.into()),
+        ],
+    };
+
+    let request = TpProcessRequest::default();
+
+    let tx_ctx = MockTransactionContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> my_sighash);
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Invalid destination");
+}
+
+// --- RegisterAddress ---
+
+fn charge_fee(tx_ctx: &mut MockTransactionContext, sighash: &SigHash) {
+    let wallet_id = WalletId::from(sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+}
+
+#[test]
+fn register_address_success() {
+    init_logs();
+
+    let command = RegisterAddress {
+        blockchain: "ethereum".into(),
+        address: "myaddress".into(),
+        network: "rinkeby".into(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let register_id = string!("ethereum", "myaddress", "rinkeby");
+    let address = Address::with_prefix_key(ADDR, &register_id);
+
+    // check if there is an existing address, in this case we will pretend that there is not one
+    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| &a == &address.as_str()), returning |_| Ok(None));
+
+    let address_proto = crate::protos::Address {
+        blockchain: command.blockchain.clone(),
+        value: command.address.clone(),
+        network: command.network.clone(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (address.to_string(), address_proto.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn register_address_taken() {
+    init_logs();
+
+    let command = RegisterAddress {
+        blockchain: "ethereum".into(),
+        address: "myaddress".into(),
+        network: "rinkeby".into(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    let my_sighash = SigHash::from("mysighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let register_id = string!("ethereum", "myaddress", "rinkeby");
+    let address = Address::with_prefix_key(ADDR, &register_id);
+
+    let existing_address = protos::Address {
+        blockchain: command.blockchain.clone(),
+        value: command.address.clone(),
+        network: command.network.clone(),
+        sighash: my_sighash.to_string(),
+    };
+
+    // check if there is an existing address, in this case we will pretend that there IS one
+    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| &a == &address.as_str()), returning move |_| Ok(Some(existing_address.to_bytes())));
+
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "The address has been already registered",
+    );
+}
+
+#[test]
+fn register_transfer_success() {
+    init_logs();
+
+    let command = RegisterTransfer {
+        gain: 1.into(),
+        order_id: string!(DEAL_ORDER_PREFIX, "orderid"),
+        blockchain_tx_id: "blockchaintxid".into(),
+    };
+
+    let request = TpProcessRequest::default();
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let other_sighash = SigHash::from("othersighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let src_address_id = "srcaddress".to_string();
+    let dst_address_id = "destaddress".to_string();
+
+    let order_address = command.order_id.clone();
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: src_address_id.clone(),
+        dst_address: dst_address_id.clone(),
+        amount: 1.to_string(),
+        sighash: my_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose!((order_address => address) move |a| &a == &address.as_str()), returning enclose!((deal_order) move |_| Ok(Some(deal_order.to_bytes()))));
+
+    let src_address = protos::Address {
+        blockchain: deal_order.blockchain.clone(),
+        value: "aaaaaaaaaa".into(),
+        network: "rinkeby".into(),
+        sighash: other_sighash.to_string(),
+    };
+    let dst_address = protos::Address {
+        blockchain: deal_order.blockchain.clone(),
+        value: "aaaaaaaaaa".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose!((dst_address_id => address) move |a| &a == &address), returning enclose!((dst_address) move |_| Ok(Some(dst_address.to_bytes()))));
+    expect!(tx_ctx, get_state_entry where enclose!((src_address_id => address) move |a| &a == &address), returning enclose!((src_address) move |_| Ok(Some(src_address.to_bytes()))));
+
+    let transfer_id = Address::with_prefix_key(
+        TRANSFER,
+        &string!(
+            &src_address.blockchain,
+            &command.blockchain_tx_id,
+            &src_address.network
+        ),
+    );
+
+    let transfer = protos::Transfer {
+        blockchain: src_address.blockchain.clone(),
+        src_address: dst_address_id.clone(),
+        dst_address: src_address_id.clone(),
+        order: command.order_id.clone(),
+        amount: (command.gain.clone() + 1u64).to_string(),
+        tx: command.blockchain_tx_id.clone(),
+        sighash: my_sighash.to_string(),
+        block: 0.to_string(),
+        processed: false,
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose!((transfer_id => tf_id) move |a| &a == &tf_id.as_str()), returning move |_| Ok(None));
+
+    expect!(ctx, verify(_) -> Ok(()));
+
+    let guid = Guid::from("guid");
+
+    expect!(ctx, guid -> guid);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (transfer_id.to_string(), transfer.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- AddAskOrder ---
+
+/// Lets the fork schedule resolve any number of times from `settings`, leaving other forks unset.
+fn expect_fork_settings(
+    ctx: &mut MockHandlerContext,
+    settings: &'static [(&'static str, &'static str)],
+) {
+    ctx.expect_get_setting()
+        .withf(|k| forks::SETTING_KEYS.contains(&k))
+        .returning(move |k| {
+            let setting = settings.iter().find(|&&(key, _)| key == k);
+            Ok(setting.map(|&(_, value)| value.to_owned()))
+        });
+}
+
+fn expect_forks_unset(ctx: &mut MockHandlerContext) {
+    expect_fork_settings(ctx, &[]);
+}
+
+#[test]
+fn add_ask_order_success() {
+    init_logs();
+
+    let command = AddAskOrder {
+        address_id: "addressid".into(),
+        amount_str: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let address = Address::with_prefix_key(ASK_ORDER, guid.as_str());
+
+    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| a == address.as_str()), returning |_| Ok(None));
+
+    let address_proto = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+
+    expect!(tx_ctx,
+        get_state_entry
+            where enclose!((command.address_id => address_id) move |a| a == &address_id),
+        returning enclose!((my_sighash, address_proto) move |_| Ok(Some(
+            address_proto.to_bytes()
+        )))
+    );
+
+    let ask_order = protos::AskOrder {
+        blockchain: address_proto.blockchain.clone(),
+        address: command.address_id.clone(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: my_sighash.to_string(),
+    };
+
+    let wallet_id = WalletId::from(&my_sighash);
+
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (address.into(), ask_order.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn add_ask_order_matches_auto_accept_bid() {
+    init_logs();
+
+    let command = AddAskOrder {
+        address_id: "addressid".into(),
+        amount_str: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_fork_settings(&mut ctx, &[(AUTO_ACCEPT_BLOCK_KEY, "1")]);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    // once for the ask order id, once for the fundraiser's fee and once for the investor's fee
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let ask_order_id = Address::with_prefix_key(ASK_ORDER, guid.as_str());
+
+    expect!(tx_ctx, get_state_entry where enclose!((ask_order_id) move |a| a == ask_order_id.as_str()), returning |_| Ok(None));
+
+    let src_address = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.address_id.clone(),
+        Some(src_address.clone()),
+        None,
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    let bid_sighash = SigHash::from("biddersighash");
+    let bid_order_id = String::from("bidorderid");
+    let bid_order = protos::BidOrder {
+        blockchain: "ethereum".into(),
+        address: "bidaddressid".into(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: 1000,
+        block: 0.to_string(),
+        sighash: bid_sighash.to_string(),
+        auto_accept: true,
+    };
+
+    // The auto-accept bid order is found through the index bucket of the ask's terms
+    let bucket_id = Address::with_prefix_key(AUTO_ACCEPT_INDEX, "1000:ethereum:rinkeby");
+    expect_get_state_entry(
+        &mut tx_ctx,
+        bucket_id.clone(),
+        Some(protos::AutoAcceptBucket {
+            ids: vec![bid_order_id.clone()],
+        }),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        bid_order_id.clone(),
+        Some(bid_order.clone()),
+        None,
+    );
+
+    // The deal order is keyed as if it had been created from an offer
+    let offer_id = Address::with_prefix_key(
+        OFFER,
+        &string!(ask_order_id.as_str(), bid_order_id.as_str()),
+    );
+    let deal_order_id = Address::with_prefix_key(DEAL_ORDER, &offer_id);
+
+    expect!(tx_ctx, get_state_entry where enclose!((deal_order_id) move |a| a == deal_order_id.as_str()), returning |_| Ok(None));
+
+    // The fundraiser pays the bid order fee plus the transaction fee, as in AddDealOrder
+    let bid_wallet_id = WalletId::from(&bid_sighash);
+    let bid_balance = Integer::try_parse(&bid_order.fee).unwrap() + &*TX_FEE;
+    expect!(tx_ctx, get balance at bid_wallet_id -> Some(bid_balance));
+
+    let deal_order = protos::DealOrder {
+        blockchain: src_address.blockchain.clone(),
+        src_address: command.address_id.clone(),
+        dst_address: bid_order.address.clone(),
+        amount: bid_order.amount.clone(),
+        interest: bid_order.interest.clone(),
+        maturity: bid_order.maturity.clone(),
+        fee: bid_order.fee.clone(),
+        expiration: bid_order.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: bid_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (deal_order_id.to_string(), deal_order.to_bytes()),
+            make_fee(
+                &Guid(string!(guid.as_str(), bid_order_id.as_str())),
+                &bid_sighash,
+                None,
+            ),
+            (bid_wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+        ],
+    );
+
+    // The bid order is consumed along with its emptied bucket, and the ask order is never written
+    expect_delete_state_entries(&mut tx_ctx, vec![bid_order_id.clone(), bucket_id.into()]);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn add_ask_order_skips_unresolvable_auto_accept_bids() {
+    init_logs();
+
+    let command = AddAskOrder {
+        address_id: "addressid".into(),
+        amount_str: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_fork_settings(&mut ctx, &[(AUTO_ACCEPT_BLOCK_KEY, "1")]);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let ask_order_id = Address::with_prefix_key(ASK_ORDER, guid.as_str());
+    expect!(tx_ctx, get_state_entry where enclose!((ask_order_id) move |a| a == ask_order_id.as_str()), returning |_| Ok(None));
+
+    let src_address = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.address_id.clone(),
+        Some(src_address.clone()),
+        None,
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    // one bid is gone, one cannot be parsed and the last belongs to the ask's owner
+    let bucket_id = Address::with_prefix_key(AUTO_ACCEPT_INDEX, "1000:ethereum:rinkeby");
+    expect_get_state_entry(
+        &mut tx_ctx,
+        bucket_id.clone(),
+        Some(protos::AutoAcceptBucket {
+            ids: vec!["gone".into(), "malformed".into(), "own".into()],
+        }),
+        None,
+    );
+    expect!(tx_ctx, get_state_entry where |a| a == "gone", returning |_| Ok(None));
+    expect!(tx_ctx, get_state_entry where |a| a == "malformed", returning |_| Ok(Some(vec![0xff; 3])));
+    let own_bid_order = protos::BidOrder {
+        blockchain: "ethereum".into(),
+        address: "ownaddressid".into(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: 1000,
+        block: 0.to_string(),
+        sighash: my_sighash.to_string(),
+        auto_accept: true,
+    };
+    expect_get_state_entry(&mut tx_ctx, "own", Some(own_bid_order), None);
+
+    let ask_order = protos::AskOrder {
+        blockchain: src_address.blockchain.clone(),
+        address: command.address_id.clone(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: my_sighash.to_string(),
+    };
+
+    // the ask order is kept, and the bucket is pruned down to the bid that still exists
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (
+                bucket_id.into(),
+                protos::AutoAcceptBucket {
+                    ids: vec!["own".into()],
+                }
+                .to_bytes(),
+            ),
+            (ask_order_id.into(), ask_order.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- AddBidOrder ---
+#[test]
+fn add_bid_order_success() {
+    init_logs();
+
+    let command = AddBidOrder {
+        address_id: "addressid".into(),
+        amount_str: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 10000,
+        auto_accept: false,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let address = Address::with_prefix_key(BID_ORDER, guid.as_str());
+
+    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| a == address.as_str()), returning |_| Ok(None));
+
+    let address_proto = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+
+    expect!(tx_ctx,
+        get_state_entry
+            where enclose!((command.address_id => address_id) move |a| a == &address_id),
+        returning enclose!((my_sighash, address_proto) move |_| Ok(Some(
+            address_proto.to_bytes()
+        )))
+    );
+
+    let ask_order = protos::BidOrder {
+        blockchain: address_proto.blockchain.clone(),
+        address: command.address_id.clone(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: my_sighash.to_string(),
+        auto_accept: false,
+    };
+
+    let wallet_id = WalletId::from(&my_sighash);
+
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (address.into(), ask_order.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+/// Runs an auto-accept `AddBidOrder` with the fork `settings`, expecting the stored order's flag
+/// and the auto-accept index bucket, if any, that goes from `bucket` to `indexed`.
+fn add_bid_order_auto_accept_case(
+    settings: &'static [(&'static str, &'static str)],
+    bucket: Option<Vec<String>>,
+    indexed: Option<Vec<String>>,
+) {
+    init_logs();
+
+    let command = AddBidOrder {
+        address_id: "addressid".into(),
+        amount_str: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 10000,
+        auto_accept: true,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_fork_settings(&mut ctx, settings);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let id = Address::with_prefix_key(BID_ORDER, guid.as_str());
+    expect!(tx_ctx, get_state_entry where enclose!((id) move |a| a == id.as_str()), returning |_| Ok(None));
+
+    let address_proto = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.address_id.clone(),
+        Some(address_proto.clone()),
+        None,
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    let bid_order = protos::BidOrder {
+        blockchain: address_proto.blockchain.clone(),
+        address: command.address_id.clone(),
+        amount: command.amount_str.clone(),
+        interest: command.interest.clone(),
+        maturity: command.maturity.clone(),
+        fee: command.fee.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: my_sighash.to_string(),
+        auto_accept: indexed.is_some(),
+    };
+
+    let mut expected = vec![
+        (id.clone().into(), bid_order.to_bytes()),
+        (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+        make_fee(&guid, &my_sighash, None),
+    ];
+    if let Some(ids) = indexed {
+        let bucket_id = Address::with_prefix_key(AUTO_ACCEPT_INDEX, "1000:ethereum:rinkeby");
+        expect_get_state_entry(
+            &mut tx_ctx,
+            bucket_id.clone(),
+            bucket.map(|ids| protos::AutoAcceptBucket { ids }),
+            None,
+        );
+        let ids = ids
+            .into_iter()
+            .map(|bid| if bid == "new" { id.to_string() } else { bid })
+            .collect();
+        expected.push((
+            bucket_id.into(),
+            protos::AutoAcceptBucket { ids }.to_bytes(),
+        ));
+    }
+    expect_set_state_entries(&mut tx_ctx, expected);
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn add_bid_order_indexes_auto_accept() {
+    add_bid_order_auto_accept_case(
+        &[(AUTO_ACCEPT_BLOCK_KEY, "1")],
+        Some(vec!["older".into()]),
+        Some(vec!["older".into(), "new".into()]),
+    );
+    add_bid_order_auto_accept_case(
+        &[(AUTO_ACCEPT_BLOCK_KEY, "1")],
+        None,
+        Some(vec!["new".into()]),
+    );
+}
+
+#[test]
+fn add_bid_order_ignores_auto_accept_before_fork() {
+    add_bid_order_auto_accept_case(&[(AUTO_ACCEPT_BLOCK_KEY, "2")], None, None);
+    add_bid_order_auto_accept_case(&[], None, None);
+}
+
+// --- AddOffer ---
+
+#[test]
+fn add_offer_success() {
+    init_logs();
+
+    let command = AddOffer {
+        ask_order_id: "askorderid".into(),
+        bid_order_id: "bidorderid".into(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 1,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    expect!(ctx, sighash -> my_sighash);
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+
+    let offer_address = Address::with_prefix_key(
+        OFFER,
+        &string!(&command.ask_order_id, &command.bid_order_id),
+    );
+
+    expect!(tx_ctx, get_state_entry where enclose!((offer_address => address_id) move |a| a == address_id.as_str()), returning |_| Ok(None));
+
+    // expect!(tx_ctx, get_state_entry where enclose!((offer_address -> address_id) move |a| a == address_id.as_str()), returning |_| Ok(None));
+
+    let ask_order = protos::AskOrder {
+        blockchain: "ethereum".into(),
+        address: "askaddressid".into(),
+        amount: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 1000,
+        block: 0.to_string(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose! { (command.ask_order_id => id) move |a|
+        a == id
+    }, returning enclose!((ask_order) move |_| Ok(Some(ask_order.to_bytes()))));
+
+    let bid_sighash = SigHash::from("biddersighash");
+
+    let bid_order = protos::BidOrder {
+        blockchain: "ethereum".into(),
+        address: "bidaddressid".into(),
+        amount: "1000".into(),
+        interest: "10000".into(),
+        maturity: "100".into(),
+        fee: "1".into(),
+        expiration: 1000,
+        block: 1.to_string(),
+        sighash: bid_sighash.to_string(),
+        auto_accept: false,
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose! { (command.bid_order_id => id) move |a|
+        a == id
+    }, returning enclose!((bid_order) move |_| Ok(Some(bid_order.to_bytes()))));
+
+    let src_address_proto = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+
+    expect!(tx_ctx, get_state_entry where enclose! { (ask_order.address => id) move |a|
+        a == id
+    }, returning enclose!((src_address_proto) move |_| Ok(Some(src_address_proto.to_bytes()))));
+
+    let dest_address_proto = protos::Address {
+        blockchain: "ethereum".into(),
+        network: "rinkeby".into(),
+        sighash: bid_sighash.clone().into(),
+        value: "somevalue".into(),
+    };
+    expect!(tx_ctx, get_state_entry where enclose! { (bid_order.address => id) move |a|
+        a == id
+    }, returning enclose!((dest_address_proto) move |_| Ok(Some(dest_address_proto.to_bytes()))));
+
+    let offer = protos::Offer {
+        blockchain: src_address_proto.blockchain.clone(),
+        ask_order: command.ask_order_id.clone(),
+        bid_order: command.bid_order_id.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (offer_address.into(), offer.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, None),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- AddDealOrder ---
+
+#[test]
+fn add_deal_order_success() {
+    init_logs();
+
+    let command = AddDealOrder {
+        offer_id: "someofferid".into(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 5,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let address_id = Address::with_prefix_key(DEAL_ORDER, &command.offer_id);
+
+    // Check for existing deal order
+    expect!(tx_ctx, get_state_entry where enclose! {(address_id) move |a| a == address_id.as_str()}, returning |_| Ok(None));
+
+    let my_sighash = SigHash::from("mysighash");
+
+    // Get the sighash of the transaction submitter
+    expect!(ctx, sighash -> my_sighash);
+
+    let offer = protos::Offer {
+        blockchain: "ethereum".into(),
+        ask_order: "askorderid".into(),
+        bid_order: "bidorderid".into(),
+        expiration: 10000,
+        block: 4.to_string(),
+        sighash: my_sighash.to_string(),
+    };
+
+    // Get the offer specified in the transaction
+    expect!(
+        tx_ctx,
+        get_state_entry
+            where enclose! { (command.offer_id => offer_id) move |a|
+                a == offer_id
+            },
+        returning enclose! { (offer) move |_|
+            Ok(Some(offer.to_bytes()))
+        }
+    );
+
+    let bid_order = protos::BidOrder {
+        blockchain: offer.blockchain.clone(),
+        address: "bidorderaddress".into(),
+        amount: 1.to_string(),
+        interest: 100.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 2.to_string(),
+        sighash: my_sighash.to_string(),
+        auto_accept: false,
+    };
+
+    // Get the bid order specified in the offer
+    expect!(
+        tx_ctx,
+        get_state_entry
+            where enclose! { (offer.bid_order => id) move|a|
+                a == id
+            },
+        returning enclose! { (bid_order) move |_|
+            Ok(Some(bid_order.to_bytes()))
+        }
+    );
+
+    let other_sighash = SigHash::from("othersighash");
+
+    let ask_order = protos::AskOrder {
+        blockchain: offer.blockchain.clone(),
+        address: "askorderaddress".into(),
+        amount: 2.to_string(),
+        interest: 100.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 1.to_string(),
+        sighash: other_sighash.to_string(),
+    };
+
+    // Get the ask order specified in the offer
+    expect!(
+        tx_ctx,
+        get_state_entry
+            where enclose! { (offer.ask_order => id) move |a|
+                a == id
+            },
+        returning enclose! { (ask_order) move|_|
+            Ok(Some(ask_order.to_bytes()))
+        }
+    );
+
+    // Make sure the fundraiser has enough wallet balance to cover the bid order fee + standard txn fee
+    let wallet_id = WalletId::from(&my_sighash);
+    let balance = Integer::try_parse(&bid_order.fee).unwrap() + &*TX_FEE;
+    expect!(tx_ctx, get balance at wallet_id -> Some(balance));
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: offer.blockchain,
+        src_address: ask_order.address,
+        dst_address: bid_order.address,
+        amount: bid_order.amount,
+        interest: bid_order.interest,
+        maturity: bid_order.maturity,
+        fee: bid_order.fee,
+        expiration: command.expiration,
+        sighash: my_sighash.to_string(),
+        block: (request.tip - 1).to_string(),
+        ..Default::default()
+    };
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    // Set new states
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            // update fundraiser wallet to balance - fee
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            // register a new fee, for return later
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            // add the new deal order to state
+            (address_id.to_string(), deal_order.to_bytes()),
+        ],
+    );
+
+    expect_delete_state_entries(
+        &mut tx_ctx,
+        vec![
+            offer.ask_order.clone(),
+            offer.bid_order.clone(),
+            command.offer_id.clone(),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+fn expect_get_state_entry(
+    tx_ctx: &mut MockTransactionContext,
+    id: impl Into<String>,
+    ret: Option<impl Versioned>,
+    times: Option<usize>,
+) {
+    let id = id.into();
+    let ret = ret.map(|m| m.to_bytes());
+    tx_ctx
+        .expect_get_state_entry()
+        .times(times.unwrap_or(1))
+        .withf(move |m| m == &id)
+        .return_once({
+            let ret = ret.clone();
+            |_| Ok(ret)
+        });
+}
+
+// --- CompleteDealOrder ---
+
+#[test]
+fn complete_deal_order_success() {
+    let command = CompleteDealOrder {
+        deal_order_id: "dealorderid".into(),
+        transfer_id: "transferid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 5,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        sighash: my_sighash.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 2.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &command.deal_order_id,
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let src_address = protos::Address {
+        blockchain: deal_order.blockchain.clone(),
+        value: "someaddressvalue".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &deal_order.src_address,
+        Some(src_address.clone()),
+        Some(1),
+    );
+
+    let transfer = protos::Transfer {
+        blockchain: deal_order.blockchain.clone(),
+        src_address: deal_order.src_address.clone(),
+        dst_address: deal_order.dst_address.clone(),
+        order: command.deal_order_id.clone(),
+        amount: deal_order.amount.clone(),
+        tx: "sometx".into(),
+        block: (request.tip - 1).to_string(),
+        processed: false,
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.transfer_id.clone(),
+        Some(transfer.clone()),
+        Some(1),
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = &*TX_FEE - Integer::try_parse(&deal_order.fee).unwrap();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let updated_transfer = protos::Transfer {
+        processed: true,
+        ..transfer
+    };
+
+    let updated_deal_order = protos::DealOrder {
+        loan_transfer: command.transfer_id.clone(),
+        block: (request.tip - 1).to_string(),
+        ..deal_order.clone()
+    };
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (command.transfer_id.clone(), updated_transfer.to_bytes()),
+            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- LockDealOrder ---
+
+#[test]
+fn lock_deal_order_success() {
+    init_logs();
+
+    let command = LockDealOrder {
+        deal_order_id: "dealorderid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 6,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        sighash: my_sighash.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.deal_order_id.clone(),
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let updated_deal_order = protos::DealOrder {
+        lock: my_sighash.to_string(),
+        ..deal_order
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- CloseDealOrder ---
+
+#[test]
+fn close_deal_order_success() {
+    init_logs();
+
+    let command = CloseDealOrder {
+        deal_order_id: "dealorderid".into(),
+        transfer_id: "repaytransferid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 7,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        lock: my_sighash.to_string(),
+        sighash: my_sighash.to_string(),
+        interest: 0.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.deal_order_id.clone(),
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let loan_transfer = protos::Transfer {
+        blockchain: deal_order.blockchain.clone(),
+        src_address: deal_order.src_address.clone(),
+        dst_address: deal_order.dst_address.clone(),
+        order: command.deal_order_id.clone(),
+        amount: deal_order.amount.clone(),
+        tx: "sometx".into(),
+        block: (request.tip - 1).to_string(),
+        processed: false,
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        deal_order.loan_transfer.clone(),
+        Some(loan_transfer.clone()),
+        Some(1),
+    );
+
+    let repayment_transfer = protos::Transfer {
+        blockchain: deal_order.blockchain.clone(),
+        src_address: deal_order.src_address.clone(),
+        dst_address: deal_order.dst_address.clone(),
+        order: command.deal_order_id.clone(),
+        amount: deal_order.amount.clone(),
+        tx: "somerepaytx".into(),
+        block: (request.tip - 1).to_string(),
+        processed: false,
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.transfer_id.clone(),
+        Some(repayment_transfer.clone()),
+        Some(1),
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let updated_deal_order = protos::DealOrder {
+        lock: my_sighash.to_string(),
+        repayment_transfer: command.transfer_id.clone(),
+        ..deal_order
+    };
+
+    let updated_repayment_transfer = protos::Transfer {
+        processed: true,
+        ..repayment_transfer
+    };
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+            (
+                command.transfer_id.clone(),
+                updated_repayment_transfer.to_bytes(),
+            ),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- Exempt ---
+
+#[test]
+fn exempt_success() {
+    init_logs();
+
+    let command = Exempt {
+        deal_order_id: "dealorderid".into(),
+        transfer_id: "repaytransferid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 7,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        sighash: my_sighash.to_string(),
+        interest: 0.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.deal_order_id.clone(),
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let repayment_transfer = protos::Transfer {
+        blockchain: deal_order.blockchain.clone(),
+        src_address: deal_order.src_address.clone(),
+        dst_address: deal_order.dst_address.clone(),
+        order: command.deal_order_id.clone(),
+        amount: deal_order.amount.clone(),
+        tx: "somerepaytx".into(),
+        block: (request.tip - 1).to_string(),
+        processed: false,
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.transfer_id.clone(),
+        Some(repayment_transfer.clone()),
+        Some(1),
+    );
+
+    let src_address = protos::Address {
+        blockchain: deal_order.blockchain.clone(),
+        value: "srcaddressvalue".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        deal_order.src_address.clone(),
+        Some(src_address.clone()),
+        Some(1),
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let updated_deal_order = protos::DealOrder {
+        repayment_transfer: command.transfer_id.clone(),
+        ..deal_order
+    };
+
+    let updated_repayment_transfer = protos::Transfer {
+        processed: true,
+        ..repayment_transfer
+    };
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+            (
+                command.transfer_id.clone(),
+                updated_repayment_transfer.to_bytes(),
+            ),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- AddRepaymentOrder ---
+
+#[test]
+fn add_repayment_order_success() {
+    init_logs();
+
+    let command = AddRepaymentOrder {
+        deal_order_id: "dealorderid".into(),
+        address_id: "buyeraddressid".into(),
+        amount: 5.to_string(),
+        expiration: 10000,
+    };
+
+    let request = TpProcessRequest {
+        tip: 7,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let fundraiser_sighash = SigHash::from("fundraisersighash");
+
+    let my_sighash = SigHash::from("loanersighash");
+
+    let other_investor_sighash = SigHash::from("otherinvestorsighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let repay_id = Address::with_prefix_key(REPAYMENT_ORDER, &guid);
+
+    expect_get_state_entry(&mut tx_ctx, &repay_id, Option::<String>::None, Some(1));
+
+    // Construct the deal order
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        sighash: fundraiser_sighash.to_string(),
+        interest: 0.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.deal_order_id.clone(),
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let src_address = protos::Address {
+        blockchain: deal_order.blockchain.clone(),
+        value: "srcaddressvalue".into(),
+        network: "rinkeby".into(),
+        sighash: other_investor_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        deal_order.src_address.clone(),
+        Some(src_address.clone()),
+        Some(1),
+    );
+
+    let new_address = protos::Address {
+        value: "newaddressvalue".into(),
+        sighash: my_sighash.to_string(),
+        ..src_address.clone()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        command.address_id.clone(),
+        Some(new_address.clone()),
+        Some(1),
+    );
+
+    expect!(ctx, guid -> guid);
+
+    let repayment_order = protos::RepaymentOrder {
+        blockchain: src_address.blockchain,
+        src_address: command.address_id.clone(),
+        dst_address: deal_order.src_address,
+        amount: command.amount.clone(),
+        expiration: command.expiration,
+        block: (request.tip - 1).to_string(),
+        deal: command.deal_order_id.clone(),
+        sighash: my_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (repay_id.to_string(), repayment_order.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- CompleteRepaymentOrder ---
+
+#[test]
+fn complete_repayment_order_success() {
+    init_logs();
+
+    let command = CompleteRepaymentOrder {
+        repayment_order_id: "repayorderid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 8,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let fundraiser_sighash = SigHash::from("fundraisersighash");
+
+    let my_sighash = SigHash::from("investorsighash");
+
+    let buyer_sighash = SigHash::from("buyersighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let deal_order_id = "dealorderid".to_string();
+
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        sighash: fundraiser_sighash.to_string(),
+        interest: 0.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &deal_order_id,
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let repayment_order = protos::RepaymentOrder {
+        blockchain: "ethereum".into(),
+        src_address: "buyeraddressid".into(),
+        dst_address: deal_order.src_address.clone(),
+        amount: 5.to_string(),
+        expiration: 10000,
+        block: (request.tip - 2).to_string(),
+        deal: deal_order_id.clone(),
+        sighash: buyer_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &command.repayment_order_id,
+        Some(repayment_order.clone()),
+        Some(1),
+    );
+
+    let dst_address = protos::Address {
+        blockchain: repayment_order.blockchain.clone(),
+        value: "oldsrcaddressvalue".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        repayment_order.dst_address.clone(),
+        Some(dst_address.clone()),
+        Some(1),
+    );
+
+    let updated_repayment_order = protos::RepaymentOrder {
+        previous_owner: my_sighash.to_string(),
+        ..repayment_order
+    };
+
+    let updated_deal_order = protos::DealOrder {
+        lock: my_sighash.to_string(),
+        ..deal_order
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (
+                command.repayment_order_id.clone(),
+                updated_repayment_order.to_bytes(),
+            ),
+            (deal_order_id.clone(), updated_deal_order.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- CloseRepaymentOrder ---
+
+#[test]
+fn close_repayment_order_success() {
+    init_logs();
+
+    let command = CloseRepaymentOrder {
+        repayment_order_id: "repayorderid".into(),
+        transfer_id: "repaytransferid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 9,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let fundraiser_sighash = SigHash::from("fundraisersighash");
+
+    let my_sighash = SigHash::from("buyersighash");
+
+    let owner_sighash = SigHash::from("investorsighash");
+
+    expect!(ctx, sighash -> my_sighash);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let guid = Guid::from("txnguid");
+    expect!(ctx, guid -> guid);
+
+    let deal_order_id = "dealorderid".to_string();
+
+    let deal_order = protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: 1.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        sighash: fundraiser_sighash.to_string(),
+        interest: 0.to_string(),
+        lock: my_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &deal_order_id,
+        Some(deal_order.clone()),
+        Some(1),
+    );
+
+    let repayment_order = protos::RepaymentOrder {
+        blockchain: "ethereum".into(),
+        src_address: "buyeraddressid".into(),
+        dst_address: deal_order.src_address.clone(),
+        amount: 5.to_string(),
+        expiration: 10000,
+        block: (request.tip - 2).to_string(),
+        deal: deal_order_id.clone(),
+        sighash: my_sighash.to_string(),
+        previous_owner: owner_sighash.to_string(),
+        ..Default::default()
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &command.repayment_order_id,
+        Some(repayment_order.clone()),
+        Some(1),
+    );
+
+    let repayment_transfer = protos::Transfer {
+        blockchain: repayment_order.blockchain.clone(),
+        src_address: repayment_order.src_address.clone(),
+        dst_address: repayment_order.dst_address.clone(),
+        order: command.repayment_order_id.clone(),
+        amount: repayment_order.amount.clone(),
+        tx: "sometx".into(),
+        block: (request.tip - 2).to_string(),
+        processed: false,
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        &command.transfer_id,
+        Some(repayment_transfer.clone()),
+        Some(1),
+    );
+
+    let dst_address = protos::Address {
+        blockchain: repayment_order.blockchain.clone(),
+        value: "oldsrcaddressvalue".into(),
+        network: "rinkeby".into(),
+        sighash: my_sighash.to_string(),
+    };
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        deal_order.src_address.clone(),
+        Some(dst_address.clone()),
+        Some(1),
+    );
+
+    let updated_repayment_order = protos::RepaymentOrder {
+        transfer: command.transfer_id.clone(),
+        ..repayment_order
+    };
+
+    let updated_deal_order = protos::DealOrder {
+        src_address: updated_repayment_order.src_address.clone(),
+        lock: "".to_string(),
+        ..deal_order
+    };
+
+    let updated_transfer = protos::Transfer {
+        processed: true,
+        ..repayment_transfer
+    };
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
+            (
+                command.repayment_order_id.clone(),
+                updated_repayment_order.to_bytes(),
+            ),
+            (deal_order_id.clone(), updated_deal_order.to_bytes()),
+            (command.transfer_id.clone(), updated_transfer.to_bytes()),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+// --- Housekeeping ---
+
+#[test]
+fn housekeeping_reward_in_chain() {
+    init_logs();
+
+    // Housekeeeping with block idx = 0
+    let command = Housekeeping {
+        block_idx: Integer::new(),
+    };
+
+    // Chain tip is far ahead
+    let request = TpProcessRequest {
+        tip: (CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT) * 4,
+        ..Default::default()
+    };
+    let mut tx_ctx = MockTransactionContext::default();
+
+    // get_state_entry should be called on the processed_block_idx address, and we will return
+    // CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT, which will force housekeeping to run
+    expect!(tx_ctx,
+        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
+        -> Ok(Some(
+            Integer::from(CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT).to_string().into_bytes()
+        ))
+    );
+
+    // pretend update1 is not set
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx,
+        get_setting(k if k == REWARD_SCHEDULE_KEY) -> Ok(None)
+    );
+
+    let height_start = CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT + 1;
+    let height_end = height_start + BLOCK_REWARD_PROCESSING_COUNT;
+
+    let mut signers = vec![];
+
+    // housekeeping tries to get the signatures for the blocks
+    // from height_start to height_end in order to issue mining rewards
+    // return a dummy signer
+    for height in height_start..height_end {
+        let signer = format!("signer{}", height);
+        signers.push(signer.clone());
+        expect!(tx_ctx,
+            get_sig_by_num(h if *h == height) -> Ok(signer)
+        );
+    }
+
+    let reward_amount = REWARD_AMOUNT.clone();
+    let mut entries = vec![];
+
+    for (idx, signer) in signers.clone().into_iter().enumerate() {
+        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+
+        // the first signer has no wallet, the rest have an existing balance of `idx`
+        let balance = if idx == 0 { None } else { Some(idx as u64) };
+
+        log::info!("starting balance = {:?}", balance);
+
+        // housekeeping should try to fetch the current wallet for each signer
+        // return the balance above
+        expect!(tx_ctx, get balance at wallet_id -> balance);
+
+        // we expect the wallet to have an updated balance of reward_amount + old balance
+        let amount_expected = reward_amount.clone() + balance.unwrap_or(0);
+
+        log::info!("expect end wallet = {:?}", amount_expected);
+        entries.push((
+            wallet_id.to_string(),
+            wallet_with(Some(amount_expected)).unwrap(),
+        ));
+    }
+
+    // housekeeping should update every rewarded wallet in a single batch
+    expect_set_state_entries(&mut tx_ctx, entries);
+
+    // housekeeping should then set the processed_block_idx to the last processed block height
+    // which in this case is height_end - 1
+    expect!(tx_ctx, set_state_entry(
+            addr if addr == PROCESSED_BLOCK_IDX.as_str(),
+            state if state == &(height_end - 1).to_string().into_bytes()
+        ) -> Ok(())
+    );
+
+    // run housekeeping
+    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+}
+
+#[test]
+fn housekeeping_reward_fork() {
+    init_logs();
+
+    // Housekeeeping with block idx = 0
+    let command = Housekeeping {
+        block_idx: Integer::new(),
+    };
+
+    let last_processed = CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT;
+
+    // Chain tip is far ahead
+    let request = TpProcessRequest {
+        tip: last_processed * 4,
+        block_signature: "headblocksig".into(),
+        ..Default::default()
+    };
+    let mut tx_ctx = MockTransactionContext::default();
+
+    // get_state_entry should be called on the processed_block_idx address, and we will return
+    // CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT, which will force housekeeping to run
+    expect!(tx_ctx,
+        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
+        -> Ok(Some(
+            Integer::from(last_processed).to_string().into_bytes()
+        ))
+    );
+
+    // pretend update1 is not set
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx,
+        get_setting(k if k == REWARD_SCHEDULE_KEY) -> Ok(None)
+    );
+
+    // the get_reward_block_signatures path iterates in reverse inclusively, so if last_processed = 5
+    // and BLOCK_REWARD_PROCESSING_COUNT = 5, then the bounds
+    // should be [10, 6] i.e. [last_processed + BLOCK_REWARD_PROCESSING_COUNT, last_processed+1]
+    let last_pred = last_processed + 1;
+    let first_pred = last_processed + BLOCK_REWARD_PROCESSING_COUNT;
+
+    log::warn!("{}..{}", last_pred, first_pred);
+
+    let signers: Vec<String> = (last_pred..first_pred)
+        .map(|i| format!("signer{}", i))
+        .collect();
+
+    let signers_ = signers.clone();
+
+    // housekeeping tries to get the signatures for the blocks
+    // iterating backwards from first_pred to last_pred
+    expect!(tx_ctx,
+        get_reward_block_signatures(id if id == "headblocksig", first if *first == first_pred, last if *last == last_pred) -> Ok(
+            signers_.clone()
+        )
+    );
+
+    let reward_amount = REWARD_AMOUNT.clone();
+    let mut entries = vec![];
+
+    for (idx, signer) in signers.clone().into_iter().enumerate() {
+        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+        let wallet_id_ = wallet_id.clone();
+
+        // the first signer has no wallet, the rest have an existing balance of `idx`
+        let balance = if idx == 0 { None } else { Some(idx as u64) };
+
+        log::info!("starting balance = {:?}", balance);
+
+        // housekeeping should try to fetch the current wallet for each signer
+        // return the balance above
+        expect!(
+            tx_ctx,
+            get_state_entry(k if k == wallet_id.as_str()) -> Ok(wallet_with(balance))
+        );
+
+        // we expect the wallet to have an updated balance of reward_amount + old balance
+        let wallet_expected = crate::protos::Wallet {
+            amount: (reward_amount.clone() + balance.unwrap_or(0)).to_string(),
+        };
+        let state_expected = wallet_expected.to_bytes();
+
+        log::info!("expect end wallet = {:?}", wallet_expected);
+        entries.push((wallet_id_.to_string(), state_expected));
+    }
+
+    // housekeeping should update every rewarded wallet in a single batch
+    expect_set_state_entries(&mut tx_ctx, entries);
+
+    // housekeeping should then set the processed_block_idx to the last processed block height
+    // which in this case is height_end - 1
+    expect!(tx_ctx, set_state_entry(
+            addr if addr == PROCESSED_BLOCK_IDX.as_str(),
+            state if state == &(first_pred).to_string().into_bytes()
+        ) -> Ok(())
+    );
+
+    // run housekeeping
+    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+}
+
+#[test]
+fn housekeeping_not_enough_confirmations() {
+    init_logs();
+
+    // Housekeeeping with block idx = 0
+    let command = Housekeeping {
+        block_idx: Integer::new(),
+    };
+
+    // no blocks have been processed
+    let last_processed = 0;
+
+    // Chain tip is not quite at the threshold for running because
+    // the blocks have not yet gotten enough confirmations
+    let request = TpProcessRequest {
+        tip: BLOCK_REWARD_PROCESSING_COUNT + 1,
+        block_signature: "headblocksig".into(),
+        ..Default::default()
+    };
+    let mut tx_ctx = MockTransactionContext::default();
+
+    expect!(tx_ctx,
+        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
+        -> Ok(Some(
+            Integer::from(last_processed).to_string().into_bytes()
+        ))
+    );
+
+    let mut ctx = MockHandlerContext::default();
+
+    // execute housekeeping
+    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+}
+
+#[test]
+fn housekeeping_within_block_reward_count() {
+    init_logs();
+
+    // Housekeeeping with block idx = 0
+    let command = Housekeeping {
+        block_idx: Integer::new(),
+    };
+
+    // pretend we've issued some rewards already
+    let last_processed = 4 * CONFIRMATION_COUNT + BLOCK_REWARD_PROCESSING_COUNT;
+
+    // Chain tip is not quite at the threshold for running because
+    // fewer than BLOCK_REWARD_PROCESSING_COUNT additional blocks have been processed
+    let request = TpProcessRequest {
+        tip: last_processed + BLOCK_REWARD_PROCESSING_COUNT - 1,
+        block_signature: "headblocksig".into(),
+        ..Default::default()
+    };
+    let mut tx_ctx = MockTransactionContext::default();
+
+    // Housekeeping should check the last processed block, then bail out
+    expect!(tx_ctx,
+        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
+        -> Ok(Some(
+            Integer::from(last_processed).to_string().into_bytes()
+        ))
+    );
+
+    let mut ctx = MockHandlerContext::default();
+
+    // execute housekeeping
+    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+}
+
+// --- Rate ---
+
+fn rate(interest: u64, maturity: u64) -> Rate {
+    Rate::try_parse(&interest.to_string(), &maturity.to_string()).unwrap()
+}
+
+#[test]
+fn rate_compares_exactly() {
+    assert!(rate(1999, 1000) > rate(1000, 1000));
+    assert_eq!(
+        rate(1999, 1000).legacy_cmp(&rate(1000, 1000)),
+        Ordering::Equal
+    );
+
+    assert!(rate(999, 1000) > rate(0, 1000));
+    assert_eq!(rate(999, 1000).legacy_cmp(&rate(0, 1000)), Ordering::Equal);
+
+    assert_eq!(rate(1, 2), rate(2, 4));
+    assert!(rate(1, 3) < rate(1, 2));
+}
+
+#[test]
+fn rate_zero_maturity() {
+    let err = Rate::try_parse("1000", "0").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CCApplyError>(),
+        Some(CCApplyError::InvalidTransaction(s)) if s == "The maturity must be greater than zero"
+    ));
+}
+
+#[test]
+fn rate_ticks() {
+    let rate = rate(1000, 10);
+    assert_eq!(rate.ticks(&Integer::from(0)), 1);
+    assert_eq!(rate.ticks(&Integer::from(9)), 1);
+    assert_eq!(rate.ticks(&Integer::from(10)), 2);
+}
+
+#[test]
+fn calc_interest_compounds_per_tick() {
+    let rate = rate(100_000, 10);
+    let amount = Integer::from(1_000_000);
+    assert_eq!(calc_interest(&amount, &Integer::from(0), &rate), 1_000_000);
+    assert_eq!(calc_interest(&amount, &Integer::from(2), &rate), 1_210_000);
+}
+
+#[test]
+fn orders_match_rate_gate() {
+    let ask_order = protos::AskOrder {
+        amount: 100.to_string(),
+        interest: 1999.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        ..Default::default()
+    };
+    let bid_order = protos::BidOrder {
+        amount: 100.to_string(),
+        interest: 1000.to_string(),
+        maturity: 1000.to_string(),
+        fee: 1.to_string(),
+        ..Default::default()
+    };
+
+    assert!(super::orders_match(&ask_order, &bid_order, false).unwrap());
+    assert!(!super::orders_match(&ask_order, &bid_order, true).unwrap());
+
+    let bid_order = protos::BidOrder {
+        maturity: 0.to_string(),
+        ..bid_order
+    };
+    assert!(!super::orders_match(&ask_order, &bid_order, true).unwrap());
+}
+
+#[test]
+fn add_ask_order_zero_maturity() {
+    init_logs();
+
+    let command = AddAskOrder {
+        address_id: "addressid".into(),
+        amount_str: 100.to_string(),
+        interest: 1000.to_string(),
+        maturity: 0.to_string(),
+        fee: 1.to_string(),
+        expiration: 10000,
+    };
 
-    let ask_order = protos::AskOrder {
-        blockchain: address_proto.blockchain.clone(),
-        address: command.address_id.clone(),
-        amount: command.amount_str.clone(),
-        interest: command.interest.clone(),
-        maturity: command.maturity.clone(),
-        fee: command.fee.clone(),
-        expiration: command.expiration,
-        block: (request.tip - 1).to_string(),
-        sighash: my_sighash.to_string(),
+    let mut request = TpProcessRequest::default();
+    request.mut_header().set_family_version("1.8".into());
+
+    let tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "The maturity must be greater than zero",
+    );
+}
+
+// --- State encoding ---
+
+fn deal_order_v1() -> protos::DealOrder {
+    protos::DealOrder {
+        blockchain: "ethereum".into(),
+        src_address: "srcaddressid".into(),
+        dst_address: "dstaddressid".into(),
+        amount: "123456789012345678901234567890".into(),
+        interest: 1000.to_string(),
+        maturity: 100.to_string(),
+        fee: 0.to_string(),
+        expiration: 10000,
+        block: 4.to_string(),
+        loan_transfer: "transferid".into(),
+        sighash: "sighash".into(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn state_v2_round_trip() {
+    let deal_order = deal_order_v1();
+    let v2 = protos::DealOrderV2::try_from(deal_order.clone()).unwrap();
+    assert_eq!(v2.version, STATE_V2);
+    assert_eq!(v2.maturity, 100);
+    assert!(v2.fee.is_empty());
+
+    let parsed = protos::DealOrder::try_parse(v2.to_bytes()).unwrap();
+    assert_eq!(parsed, deal_order);
+
+    let parsed = protos::DealOrder::try_parse(deal_order.to_bytes()).unwrap();
+    assert_eq!(parsed, deal_order);
+}
+
+#[test]
+fn state_v2_rejects_non_canonical_integer() {
+    let wallet = protos::WalletV2 {
+        amount: vec![0, 1],
+        version: STATE_V2,
+    };
+    assert!(protos::Wallet::try_parse(wallet.to_bytes()).is_err());
+}
+
+#[test]
+fn state_rejects_unknown_version() {
+    let wallet = protos::WalletV2 {
+        amount: vec![1],
+        version: 7,
+    };
+    let err = protos::Wallet::try_parse(wallet.to_bytes()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CCApplyError>(),
+        Some(CCApplyError::InvalidTransaction(s)) if s == "Unsupported state version 7"
+    ));
+}
+
+#[test]
+fn migrate_entry_skips_unrepresentable() {
+    let transfer = protos::Transfer {
+        amount: "-5".into(),
+        block: 4.to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        encoding::migrate_entry(TRANSFER, "transfer", &transfer.to_bytes()),
+        None
+    );
+
+    let deal_order = protos::DealOrder {
+        maturity: "18446744073709551616".into(),
+        ..deal_order_v1()
+    };
+    assert_eq!(
+        encoding::migrate_entry(DEAL_ORDER, "deal", &deal_order.to_bytes()),
+        None
+    );
+
+    let migrated = encoding::migrate_entry(DEAL_ORDER, "deal", &deal_order_v1().to_bytes());
+    let migrated = migrated.unwrap();
+    assert_eq!(encoding::migrate_entry(DEAL_ORDER, "deal", &migrated), None);
+}
+
+#[test]
+fn migrate_state_success() {
+    init_logs();
+
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default().with_params(ProcessorParams {
+        state_v2_block: Some(11),
+        ..Default::default()
+    });
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    for &prefix in &encoding::MIGRATED_PREFIXES {
+        let cursor_id = Address::with_prefix_key(MIGRATION, prefix);
+        expect!(tx_ctx, get_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning |_| Ok(None));
+    }
+
+    // prefixes are scanned in buckets of a hex digit, so the sighash must start with one
+    let other_wallet_id = WalletId::from(&SigHash::from("a0sighash"));
+    let deal_order_id = Address::with_prefix_key(DEAL_ORDER, "dealorder");
+    let broken_deal_order = protos::DealOrder {
+        amount: "-1".into(),
+        ..deal_order_v1()
+    };
+    let broken_deal_order_id = Address::with_prefix_key(DEAL_ORDER, "brokendealorder");
+
+    let entries = vec![
+        (
+            wallet_id.to_string(),
+            wallet_with(Some(TX_FEE.clone())).unwrap(),
+        ),
+        (other_wallet_id.to_string(), wallet_with(Some(5)).unwrap()),
+        (deal_order_id.to_string(), deal_order_v1().to_bytes()),
+        (
+            broken_deal_order_id.to_string(),
+            broken_deal_order.to_bytes(),
+        ),
+    ];
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .returning(move |prefix| {
+            Ok(entries
+                .iter()
+                .filter(|(address, _)| address.starts_with(prefix))
+                .cloned()
+                .collect())
+        });
+
+    let other_wallet = protos::WalletV2 {
+        amount: vec![5],
+        version: STATE_V2,
+    };
+    let deal_order = protos::DealOrderV2::try_from(deal_order_v1()).unwrap();
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (other_wallet_id.to_string(), other_wallet.to_bytes()),
+            (deal_order_id.to_string(), deal_order.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
+        ],
+    );
+
+    execute_success(MigrateState, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn migrate_state_before_activation() {
+    init_logs();
+
+    let request = TpProcessRequest {
+        tip: 10,
+        ..Default::default()
+    };
+
+    // nothing is read or charged before the v2 encoding activates
+    let tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default().with_params(ProcessorParams {
+        state_v2_block: Some(11),
+        ..Default::default()
+    });
+    expect_forks_unset(&mut ctx);
+    execute_failure(
+        MigrateState,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "State migration is not active",
+    );
+}
+
+#[test]
+fn migrate_state_resumes_from_cursor() {
+    init_logs();
+
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default().with_params(ProcessorParams {
+        state_v2_block: Some(11),
+        ..Default::default()
+    });
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let mut deal_order_ids = vec![
+        Address::with_prefix_key(DEAL_ORDER, "first").to_string(),
+        Address::with_prefix_key(DEAL_ORDER, "second").to_string(),
+    ];
+    deal_order_ids.sort();
+    let (migrated_id, pending_id) = (deal_order_ids[0].clone(), deal_order_ids[1].clone());
+
+    // an earlier run stopped after the first deal order
+    for &prefix in &encoding::MIGRATED_PREFIXES {
+        let cursor_id = Address::with_prefix_key(MIGRATION, prefix);
+        let cursor = if prefix == DEAL_ORDER {
+            Some(migrated_id.clone().into_bytes())
+        } else {
+            None
+        };
+        let resumed = cursor.is_some();
+        expect!(tx_ctx, get_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning move |_| Ok(cursor));
+        if resumed {
+            expect!(tx_ctx, delete_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning |a| Ok(Some(a.to_owned())));
+        }
+    }
+
+    let entries = vec![
+        (migrated_id, deal_order_v1().to_bytes()),
+        (pending_id.clone(), deal_order_v1().to_bytes()),
+    ];
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .returning(move |prefix| {
+            Ok(entries
+                .iter()
+                .filter(|(address, _)| address.starts_with(prefix))
+                .cloned()
+                .collect())
+        });
+
+    let deal_order = protos::DealOrderV2::try_from(deal_order_v1()).unwrap();
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (pending_id, deal_order.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
+        ],
+    );
+
+    execute_success(MigrateState, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn encode_entry_upgrades_migrated_prefixes() {
+    let wallet_id = WalletId::from(&SigHash::from("mysighash"));
+    let v1 = wallet_with(Some(5)).unwrap();
+    let v2 = protos::WalletV2 {
+        amount: vec![5],
+        version: STATE_V2,
+    };
+    assert_eq!(
+        encoding::encode_entry(&wallet_id, v1).unwrap(),
+        v2.to_bytes()
+    );
+    assert_eq!(
+        encoding::encode_entry(&wallet_id, v2.to_bytes()).unwrap(),
+        v2.to_bytes()
+    );
+
+    // entries without a v2 encoding are written as they are
+    let address_id = Address::with_prefix_key(ADDR, "address");
+    let address = protos::Address::default().to_bytes();
+    assert_eq!(
+        encoding::encode_entry(&address_id, address.clone()).unwrap(),
+        address
+    );
+
+    let broken = protos::Wallet {
+        amount: "-1".into(),
+    };
+    assert!(encoding::encode_entry(&wallet_id, broken.to_bytes()).is_err());
+}
+
+#[test]
+fn state_v2_context_upgrades_writes() {
+    let wallet_id = WalletId::from(&SigHash::from("mysighash"));
+    let v2 = protos::WalletV2 {
+        amount: vec![5],
+        version: STATE_V2,
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    expect_set_state_entries(&mut tx_ctx, vec![(wallet_id.to_string(), v2.to_bytes())]);
+
+    let upgrading = encoding::StateV2Context(&tx_ctx);
+    upgrading
+        .set_state_entries(vec![(wallet_id.to_string(), wallet_with(Some(5)).unwrap())])
+        .unwrap();
+
+    let broken = protos::Wallet {
+        amount: "-1".into(),
+    };
+    assert!(upgrading
+        .set_state_entry(wallet_id.to_string(), broken.to_bytes())
+        .is_err());
+}
+
+#[test]
+fn state_version_dispatch() {
+    let address = protos::Address {
+        blockchain: "ethereum".into(),
+        value: "myaddress".into(),
+        network: "rinkeby".into(),
+        sighash: "mysighash".into(),
+    };
+    let mut buf = address.to_bytes();
+    assert_eq!(encoding::state_version(&buf).unwrap(), encoding::STATE_V1);
+    assert_eq!(protos::Address::try_parse(&buf).unwrap(), address);
+
+    protos::StateVersion { version: STATE_V2 }
+        .encode(&mut buf)
+        .unwrap();
+    let err = protos::Address::try_parse(&buf).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CCApplyError>(),
+        Some(CCApplyError::InvalidTransaction(s)) if s == "Unsupported state version 2"
+    ));
+
+    let wallet = wallet_with(Some(5)).unwrap();
+    assert!(protos::WalletV2::try_parse(&wallet).is_err());
+}
+
+// --- Multisig ---
+
+fn multisig_with(members: &[&str], threshold: u64) -> protos::Multisig {
+    protos::Multisig {
+        members: members.iter().map(|m| m.to_string()).collect(),
+        threshold,
+        block: 1.to_string(),
+        sighash: members[0].into(),
+    }
+}
+
+fn spend_proposal(approvals: &[&str]) -> protos::SpendProposal {
+    protos::SpendProposal {
+        multisig: "multisig".into(),
+        sighash: "destination".into(),
+        amount: 40.to_string(),
+        approvals: approvals.iter().map(|a| a.to_string()).collect(),
+        expiration: 100,
+        block: 5.to_string(),
+    }
+}
+
+#[test]
+fn create_multisig_success() {
+    init_logs();
+
+    let command = CreateMultisig {
+        members: vec!["mysighash".into(), "other".into(), "third".into()],
+        threshold: 2,
+    };
+
+    let request = TpProcessRequest {
+        tip: 2,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let multisig_sighash = SigHash(utils::sha512_id(string!(MULTISIG, guid.as_str())));
+    let multisig_id = super::multisig_id(&multisig_sighash);
+    expect!(tx_ctx, get_state_entry where enclose!((multisig_id) move |a| a == multisig_id), returning |_| Ok(None));
+
+    let multisig = multisig_with(&["mysighash", "other", "third"], 2);
+
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (multisig_id, multisig.to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(1)),
+        ],
+    );
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn create_multisig_invalid_threshold() {
+    init_logs();
+
+    let request = TpProcessRequest::default();
+    let tx_ctx = MockTransactionContext::default();
+
+    for threshold in [0, 3].iter().copied() {
+        let command = CreateMultisig {
+            members: vec!["mysighash".into(), "other".into()],
+            threshold,
+        };
+
+        let mut ctx = MockHandlerContext::default();
+        expect!(ctx, sighash -> "mysighash");
+
+        execute_failure(command, &request, &tx_ctx, &mut ctx, "Invalid threshold");
+    }
+}
+
+#[test]
+fn create_multisig_duplicate_member() {
+    init_logs();
+
+    let command = CreateMultisig {
+        members: vec!["mysighash".into(), "other".into(), "other".into()],
+        threshold: 2,
+    };
+
+    let request = TpProcessRequest::default();
+    let tx_ctx = MockTransactionContext::default();
+
+    let mut ctx = MockHandlerContext::default();
+    expect!(ctx, sighash -> "mysighash");
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Duplicate member");
+}
+
+#[test]
+fn propose_spend_success() {
+    init_logs();
+
+    let command = ProposeSpend {
+        multisig: "multisig".into(),
+        sighash: "destination".into(),
+        amount: 40.into(),
+        expiration: 100,
+    };
+
+    let request = TpProcessRequest {
+        tip: 6,
+        ..Default::default()
     };
 
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("proposalguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&command.multisig),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
+
     let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
 
-    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, guid.as_str());
+    expect!(tx_ctx, get_state_entry where enclose!((proposal_id) move |a| a == proposal_id.as_str()), returning |_| Ok(None));
 
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (address.into(), ask_order.to_bytes()),
+            (
+                proposal_id.to_string(),
+                spend_proposal(&["mysighash"]).to_bytes(),
+            ),
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
+            make_fee(&guid, &my_sighash, Some(5)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- AddBidOrder ---
 #[test]
-fn add_bid_order_success() {
+fn propose_spend_not_member() {
     init_logs();
 
-    let command = AddBidOrder {
-        address_id: "addressid".into(),
-        amount_str: "1000".into(),
-        interest: "10000".into(),
-        maturity: "100".into(),
-        fee: "1".into(),
-        expiration: 10000,
+    let command = ProposeSpend {
+        multisig: "multisig".into(),
+        sighash: "destination".into(),
+        amount: 40.into(),
+        expiration: 100,
     };
 
-    let request = TpProcessRequest {
-        tip: 1,
-        ..Default::default()
-    };
+    let request = TpProcessRequest::default();
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let my_sighash = SigHash::from("mysighash");
-    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, sighash -> "outsider");
 
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
-    expect!(ctx, guid -> guid);
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&command.multisig),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
 
-    let address = Address::with_prefix_key(BID_ORDER, guid.as_str());
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "The party is not a member of the multisig wallet",
+    );
+}
 
-    expect!(tx_ctx, get_state_entry where enclose!((address) move |a| a == address.as_str()), returning |_| Ok(None));
+#[test]
+fn approve_spend_success() {
+    init_logs();
 
-    let address_proto = protos::Address {
-        blockchain: "ethereum".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.clone().into(),
-        value: "somevalue".into(),
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, "proposalguid").to_string();
+    let command = ApproveSpend {
+        proposal_id: proposal_id.clone(),
     };
 
-    expect!(tx_ctx,
-        get_state_entry
-            where enclose!((command.address_id => address_id) move |a| a == &address_id),
-        returning enclose!((my_sighash, address_proto) move |_| Ok(Some(
-            address_proto.to_bytes()
-        )))
-    );
-
-    let ask_order = protos::BidOrder {
-        blockchain: address_proto.blockchain.clone(),
-        address: command.address_id.clone(),
-        amount: command.amount_str.clone(),
-        interest: command.interest.clone(),
-        maturity: command.maturity.clone(),
-        fee: command.fee.clone(),
-        expiration: command.expiration,
-        block: (request.tip - 1).to_string(),
-        sighash: my_sighash.to_string(),
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
     };
 
-    let wallet_id = WalletId::from(&my_sighash);
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+    let my_sighash = SigHash::from("other");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        proposal_id.clone(),
+        Some(spend_proposal(&["mysighash"])),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&SigHash::from("multisig")),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
 
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (address.into(), ask_order.to_bytes()),
+            (
+                proposal_id,
+                spend_proposal(&["mysighash", "other"]).to_bytes(),
+            ),
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- AddOffer ---
-
 #[test]
-fn add_offer_success() {
+fn approve_spend_twice() {
     init_logs();
 
-    let command = AddOffer {
-        ask_order_id: "askorderid".into(),
-        bid_order_id: "bidorderid".into(),
-        expiration: 10000,
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, "proposalguid").to_string();
+    let command = ApproveSpend {
+        proposal_id: proposal_id.clone(),
     };
 
     let request = TpProcessRequest {
-        tip: 1,
+        tip: 11,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let my_sighash = SigHash::from("mysighash");
-    expect!(ctx, sighash -> my_sighash);
-
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
-
-    let wallet_id = WalletId::from(&my_sighash);
-    expect!(tx_ctx, get balance at wallet_id -> Some(TX_FEE.clone()));
+    expect!(ctx, sighash -> "mysighash");
 
-    let offer_address = Address::with_prefix_key(
-        OFFER,
-        &string!(&command.ask_order_id, &command.bid_order_id),
+    expect_get_state_entry(
+        &mut tx_ctx,
+        proposal_id,
+        Some(spend_proposal(&["mysighash"])),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&SigHash::from("multisig")),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
     );
 
-    expect!(tx_ctx, get_state_entry where enclose!((offer_address => address_id) move |a| a == address_id.as_str()), returning |_| Ok(None));
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "The spend was already approved by the party",
+    );
+}
 
-    // expect!(tx_ctx, get_state_entry where enclose!((offer_address -> address_id) move |a| a == address_id.as_str()), returning |_| Ok(None));
+#[test]
+fn approve_spend_expired() {
+    init_logs();
 
-    let ask_order = protos::AskOrder {
-        blockchain: "ethereum".into(),
-        address: "askaddressid".into(),
-        amount: "1000".into(),
-        interest: "10000".into(),
-        maturity: "100".into(),
-        fee: "1".into(),
-        expiration: 1000,
-        block: 0.to_string(),
-        sighash: my_sighash.to_string(),
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, "proposalguid").to_string();
+    let command = ApproveSpend {
+        proposal_id: proposal_id.clone(),
     };
 
-    expect!(tx_ctx, get_state_entry where enclose! { (command.ask_order_id => id) move |a|
-        a == id
-    }, returning enclose!((ask_order) move |_| Ok(Some(ask_order.to_bytes()))));
+    let request = TpProcessRequest {
+        tip: 200,
+        ..Default::default()
+    };
 
-    let bid_sighash = SigHash::from("biddersighash");
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
 
-    let bid_order = protos::BidOrder {
-        blockchain: "ethereum".into(),
-        address: "bidaddressid".into(),
-        amount: "1000".into(),
-        interest: "10000".into(),
-        maturity: "100".into(),
-        fee: "1".into(),
-        expiration: 1000,
-        block: 1.to_string(),
-        sighash: bid_sighash.to_string(),
-    };
+    expect!(ctx, sighash -> "other");
 
-    expect!(tx_ctx, get_state_entry where enclose! { (command.bid_order_id => id) move |a|
-        a == id
-    }, returning enclose!((bid_order) move |_| Ok(Some(bid_order.to_bytes()))));
+    expect_get_state_entry(
+        &mut tx_ctx,
+        proposal_id,
+        Some(spend_proposal(&["mysighash"])),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&SigHash::from("multisig")),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
 
-    let src_address_proto = protos::Address {
-        blockchain: "ethereum".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.clone().into(),
-        value: "somevalue".into(),
-    };
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "The proposal has expired",
+    );
+}
 
-    expect!(tx_ctx, get_state_entry where enclose! { (ask_order.address => id) move |a|
-        a == id
-    }, returning enclose!((src_address_proto) move |_| Ok(Some(src_address_proto.to_bytes()))));
+#[test]
+fn execute_spend_success() {
+    init_logs();
 
-    let dest_address_proto = protos::Address {
-        blockchain: "ethereum".into(),
-        network: "rinkeby".into(),
-        sighash: bid_sighash.clone().into(),
-        value: "somevalue".into(),
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, "proposalguid").to_string();
+    let command = ExecuteSpend {
+        proposal_id: proposal_id.clone(),
     };
-    expect!(tx_ctx, get_state_entry where enclose! { (bid_order.address => id) move |a|
-        a == id
-    }, returning enclose!((dest_address_proto) move |_| Ok(Some(dest_address_proto.to_bytes()))));
 
-    let offer = protos::Offer {
-        blockchain: src_address_proto.blockchain.clone(),
-        ask_order: command.ask_order_id.clone(),
-        bid_order: command.bid_order_id.clone(),
-        expiration: command.expiration,
-        block: (request.tip - 1).to_string(),
-        sighash: my_sighash.to_string(),
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
     };
 
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        proposal_id.clone(),
+        Some(spend_proposal(&["mysighash", "other"])),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&SigHash::from("multisig")),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
+
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    let multisig_wallet_id = WalletId::from(&SigHash::from("multisig"));
+    expect!(tx_ctx, get balance at multisig_wallet_id -> Some(100));
+
+    let dest_wallet_id = WalletId::from(&SigHash::from("destination"));
+    expect!(tx_ctx, get balance at dest_wallet_id, returning |_| Ok(None));
+
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (offer_address.into(), offer.to_bytes()),
+            (
+                multisig_wallet_id.to_string(),
+                wallet_with(Some(60)).unwrap(),
+            ),
+            (dest_wallet_id.to_string(), wallet_with(Some(40)).unwrap()),
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, None),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
+    expect_delete_state_entries(&mut tx_ctx, vec![proposal_id]);
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- AddDealOrder ---
+#[test]
+fn execute_spend_not_enough_approvals() {
+    init_logs();
+
+    let proposal_id = Address::with_prefix_key(SPEND_PROPOSAL, "proposalguid").to_string();
+    let command = ExecuteSpend {
+        proposal_id: proposal_id.clone(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
+    };
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "mysighash");
+
+    expect_get_state_entry(
+        &mut tx_ctx,
+        proposal_id,
+        Some(spend_proposal(&["mysighash"])),
+        None,
+    );
+    expect_get_state_entry(
+        &mut tx_ctx,
+        super::multisig_id(&SigHash::from("multisig")),
+        Some(multisig_with(&["mysighash", "other"], 2)),
+        None,
+    );
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Not enough approvals");
+}
+
+// --- Vesting ---
+
+fn vesting_with(amount: &Integer, claimed: &Integer) -> protos::Vesting {
+    protos::Vesting {
+        sighash: "beneficiary".into(),
+        amount: amount.to_string(),
+        claimed: claimed.to_string(),
+        block: 10.to_string(),
+        cliff: 20,
+        duration: 100,
+        owner: "mysighash".into(),
+    }
+}
+
+#[test]
+fn vested_amount_schedule() {
+    let vesting = vesting_with(&Integer::from(1000), &Integer::new());
+    let at = |head: u64| super::vested_amount(&vesting, &Integer::from(head)).unwrap();
+
+    assert_eq!(at(10), 0);
+    assert_eq!(at(29), 0);
+    assert_eq!(at(30), 200);
+    assert_eq!(at(65), 550);
+    assert_eq!(at(110), 1000);
+    assert_eq!(at(500), 1000);
+}
 
 #[test]
-fn add_deal_order_success() {
+fn create_vesting_success() {
     init_logs();
 
-    let command = AddDealOrder {
-        offer_id: "someofferid".into(),
-        expiration: 10000,
+    let amount = Integer::from(1000);
+    let command = CreateVesting {
+        sighash: "beneficiary".into(),
+        amount: amount.clone(),
+        cliff: 20,
+        duration: 100,
     };
 
     let request = TpProcessRequest {
-        tip: 5,
+        tip: 11,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
-
-    let address_id = Address::with_prefix_key(DEAL_ORDER, &command.offer_id);
-
-    // Check for existing deal order
-    expect!(tx_ctx, get_state_entry where enclose! {(address_id) move |a| a == address_id.as_str()}, returning |_| Ok(None));
+    expect_forks_unset(&mut ctx);
 
     let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("vestingguid");
 
-    // Get the sighash of the transaction submitter
     expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
+    expect!(ctx, guid -> guid);
 
-    let offer = protos::Offer {
-        blockchain: "ethereum".into(),
-        ask_order: "askorderid".into(),
-        bid_order: "bidorderid".into(),
-        expiration: 10000,
-        block: 4.to_string(),
-        sighash: my_sighash.to_string(),
-    };
-
-    // Get the offer specified in the transaction
-    expect!(
-        tx_ctx,
-        get_state_entry
-            where enclose! { (command.offer_id => offer_id) move |a|
-                a == offer_id
-            },
-        returning enclose! { (offer) move |_|
-            Ok(Some(offer.to_bytes()))
-        }
-    );
+    let wallet_id = WalletId::from(&my_sighash);
+    let balance = Integer::from(1005) + &*TX_FEE;
+    expect!(tx_ctx, get balance at wallet_id -> Some(balance));
 
-    let bid_order = protos::BidOrder {
-        blockchain: offer.blockchain.clone(),
-        address: "bidorderaddress".into(),
-        amount: 1.to_string(),
-        interest: 100.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 2.to_string(),
-        sighash: my_sighash.to_string(),
-    };
+    let vesting_id = Address::with_prefix_key(VESTING, guid.as_str());
+    expect!(tx_ctx, get_state_entry where enclose!((vesting_id) move |a| a == vesting_id.as_str()), returning |_| Ok(None));
 
-    // Get the bid order specified in the offer
-    expect!(
-        tx_ctx,
-        get_state_entry
-            where enclose! { (offer.bid_order => id) move|a|
-                a == id
-            },
-        returning enclose! { (bid_order) move |_|
-            Ok(Some(bid_order.to_bytes()))
-        }
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (
+                vesting_id.to_string(),
+                vesting_with(&amount, &Integer::new()).to_bytes(),
+            ),
+            (wallet_id.to_string(), wallet_with(Some(5)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
+        ],
     );
 
-    let other_sighash = SigHash::from("othersighash");
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
 
-    let ask_order = protos::AskOrder {
-        blockchain: offer.blockchain.clone(),
-        address: "askorderaddress".into(),
-        amount: 2.to_string(),
-        interest: 100.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 1.to_string(),
-        sighash: other_sighash.to_string(),
+#[test]
+fn create_vesting_invalid_schedule() {
+    init_logs();
+
+    let command = CreateVesting {
+        sighash: "beneficiary".into(),
+        amount: 1000.into(),
+        cliff: 200,
+        duration: 100,
     };
 
-    // Get the ask order specified in the offer
-    expect!(
-        tx_ctx,
-        get_state_entry
-            where enclose! { (offer.ask_order => id) move |a|
-                a == id
-            },
-        returning enclose! { (ask_order) move|_|
-            Ok(Some(ask_order.to_bytes()))
-        }
+    let request = TpProcessRequest::default();
+    let tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "mysighash");
+
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "Invalid vesting schedule",
     );
+}
 
-    // Make sure the fundraiser has enough wallet balance to cover the bid order fee + standard txn fee
-    let wallet_id = WalletId::from(&my_sighash);
-    let balance = Integer::try_parse(&bid_order.fee).unwrap() + &*TX_FEE;
-    expect!(tx_ctx, get balance at wallet_id -> Some(balance));
+#[test]
+fn claim_vested_partial() {
+    init_logs();
 
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: offer.blockchain,
-        src_address: ask_order.address,
-        dst_address: bid_order.address,
-        amount: bid_order.amount,
-        interest: bid_order.interest,
-        maturity: bid_order.maturity,
-        fee: bid_order.fee,
-        expiration: command.expiration,
-        sighash: my_sighash.to_string(),
-        block: (request.tip - 1).to_string(),
+    let vesting_id = Address::with_prefix_key(VESTING, "vestingguid").to_string();
+    let command = ClaimVested {
+        vesting_id: vesting_id.clone(),
+    };
+
+    // the last block is 65, so 55% of the amount is released
+    let request = TpProcessRequest {
+        tip: 66,
         ..Default::default()
     };
 
-    let guid = Guid::from("txnguid");
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+
+    let my_sighash = SigHash::from("beneficiary");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
     expect!(ctx, guid -> guid);
 
-    // Set new states
-    expect_set_state_entries(
+    let fee = TX_FEE.clone();
+    let amount = Integer::from(&fee * 1000);
+    let claimed = Integer::from(&fee * 200);
+    expect_get_state_entry(
         &mut tx_ctx,
-        vec![
-            // update fundraiser wallet to balance - fee
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            // register a new fee, for return later
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            // add the new deal order to state
-            (address_id.to_string(), deal_order.to_bytes()),
-        ],
+        vesting_id.clone(),
+        Some(vesting_with(&amount, &claimed)),
+        None,
     );
 
-    expect_delete_state_entries(
+    // the beneficiary has no wallet yet, the fee comes out of the claim
+    let wallet_id = WalletId::from(&my_sighash);
+    expect!(tx_ctx, get balance at wallet_id, returning |_| Ok(None));
+
+    let vested = Integer::from(&fee * 550);
+    let balance = Integer::from(&fee * 349);
+
+    expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            offer.ask_order.clone(),
-            offer.bid_order.clone(),
-            command.offer_id.clone(),
+            (vesting_id, vesting_with(&amount, &vested).to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(balance)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(65)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-fn expect_get_state_entry(
-    tx_ctx: &mut MockTransactionContext,
-    id: impl Into<String>,
-    ret: Option<impl Message + Default>,
-    times: Option<usize>,
-) {
-    let id = id.into();
-    let ret = ret.map(|m| m.to_bytes());
-    tx_ctx
-        .expect_get_state_entry()
-        .times(times.unwrap_or(1))
-        .withf(move |m| m == &id)
-        .return_once({
-            let ret = ret.clone();
-            |_| Ok(ret)
-        });
-}
-
-// --- CompleteDealOrder ---
-
 #[test]
-fn complete_deal_order_success() {
-    let command = CompleteDealOrder {
-        deal_order_id: "dealorderid".into(),
-        transfer_id: "transferid".into(),
+fn claim_vested_fully() {
+    init_logs();
+
+    let vesting_id = Address::with_prefix_key(VESTING, "vestingguid").to_string();
+    let command = ClaimVested {
+        vesting_id: vesting_id.clone(),
     };
 
     let request = TpProcessRequest {
-        tip: 5,
+        tip: 200,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let my_sighash = SigHash::from("mysighash");
+    let my_sighash = SigHash::from("beneficiary");
+    let guid = Guid::from("myguid");
 
     expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
 
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        sighash: my_sighash.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 2.to_string(),
-        ..Default::default()
-    };
-
+    let amount = Integer::from(1000);
     expect_get_state_entry(
         &mut tx_ctx,
-        &command.deal_order_id,
-        Some(deal_order.clone()),
-        Some(1),
+        vesting_id.clone(),
+        Some(vesting_with(&amount, &Integer::from(550))),
+        None,
     );
 
-    let src_address = protos::Address {
-        blockchain: deal_order.blockchain.clone(),
-        value: "someaddressvalue".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.to_string(),
-    };
+    let wallet_id = WalletId::from(&my_sighash);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
 
-    expect_get_state_entry(
+    expect_set_state_entries(
         &mut tx_ctx,
-        &deal_order.src_address,
-        Some(src_address.clone()),
-        Some(1),
+        vec![
+            (wallet_id.to_string(), wallet_with(Some(450)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(199)),
+        ],
     );
+    expect_delete_state_entries(&mut tx_ctx, vec![vesting_id]);
 
-    let transfer = protos::Transfer {
-        blockchain: deal_order.blockchain.clone(),
-        src_address: deal_order.src_address.clone(),
-        dst_address: deal_order.dst_address.clone(),
-        order: command.deal_order_id.clone(),
-        amount: deal_order.amount.clone(),
-        tx: "sometx".into(),
-        block: (request.tip - 1).to_string(),
-        processed: false,
-        sighash: my_sighash.to_string(),
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn claim_vested_before_cliff() {
+    init_logs();
+
+    let vesting_id = Address::with_prefix_key(VESTING, "vestingguid").to_string();
+    let command = ClaimVested {
+        vesting_id: vesting_id.clone(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 20,
+        ..Default::default()
     };
 
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "beneficiary");
+
     expect_get_state_entry(
         &mut tx_ctx,
-        command.transfer_id.clone(),
-        Some(transfer.clone()),
-        Some(1),
+        vesting_id,
+        Some(vesting_with(&Integer::from(1000), &Integer::new())),
+        None,
     );
 
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = &*TX_FEE - Integer::try_parse(&deal_order.fee).unwrap();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Nothing to claim");
+}
 
-    let updated_transfer = protos::Transfer {
-        processed: true,
-        ..transfer
+fn allowance_with(amount: impl Into<Integer>) -> protos::Allowance {
+    protos::Allowance {
+        owner: "owner".into(),
+        spender: "spender".into(),
+        amount: amount.into().to_string(),
+        block: 10.to_string(),
+    }
+}
+
+#[test]
+fn approve_success() {
+    init_logs();
+
+    let command = Approve {
+        spender: "spender".into(),
+        amount: 1000.into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
     };
 
-    let updated_deal_order = protos::DealOrder {
-        loan_transfer: command.transfer_id.clone(),
-        block: (request.tip - 1).to_string(),
-        ..deal_order.clone()
-    };
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let guid = Guid::from("txnguid");
+    let my_sighash = SigHash::from("owner");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
     expect!(ctx, guid -> guid);
 
+    charge_fee(&mut tx_ctx, &my_sighash);
+
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
+    let wallet_id = WalletId::from(&my_sighash);
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
+            (allowance_id.to_string(), allowance_with(1000).to_bytes()),
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (command.transfer_id.clone(), updated_transfer.to_bytes()),
-            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- LockDealOrder ---
-
 #[test]
-fn lock_deal_order_success() {
+fn approve_zero_revokes() {
     init_logs();
 
-    let command = LockDealOrder {
-        deal_order_id: "dealorderid".into(),
+    let command = Approve {
+        spender: "spender".into(),
+        amount: 0.into(),
     };
 
     let request = TpProcessRequest {
-        tip: 6,
+        tip: 11,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let my_sighash = SigHash::from("mysighash");
+    let my_sighash = SigHash::from("owner");
+    let guid = Guid::from("myguid");
 
     expect!(ctx, sighash -> my_sighash);
-
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        sighash: my_sighash.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        ..Default::default()
-    };
-
-    expect_get_state_entry(
-        &mut tx_ctx,
-        command.deal_order_id.clone(),
-        Some(deal_order.clone()),
-        Some(1),
-    );
-
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
-
-    let guid = Guid::from("txnguid");
     expect!(ctx, guid -> guid);
 
-    let updated_deal_order = protos::DealOrder {
-        lock: my_sighash.to_string(),
-        ..deal_order
-    };
+    charge_fee(&mut tx_ctx, &my_sighash);
 
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
+    let wallet_id = WalletId::from(&my_sighash);
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
+    expect_delete_state_entries(&mut tx_ctx, vec![allowance_id.to_string()]);
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- CloseDealOrder ---
+#[test]
+fn approve_self() {
+    init_logs();
+
+    let command = Approve {
+        spender: "owner".into(),
+        amount: 1000.into(),
+    };
+
+    let request = TpProcessRequest::default();
+    let tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "owner");
+
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Invalid spender");
+}
 
 #[test]
-fn close_deal_order_success() {
+fn send_funds_from_success() {
     init_logs();
 
-    let command = CloseDealOrder {
-        deal_order_id: "dealorderid".into(),
-        transfer_id: "repaytransferid".into(),
+    let command = SendFundsFrom {
+        owner: "owner".into(),
+        sighash: "receiver".into(),
+        amount: 400.into(),
     };
 
     let request = TpProcessRequest {
-        tip: 7,
+        tip: 11,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let my_sighash = SigHash::from("mysighash");
+    let my_sighash = SigHash::from("spender");
+    let guid = Guid::from("myguid");
 
     expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
 
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        lock: my_sighash.to_string(),
-        sighash: my_sighash.to_string(),
-        interest: 0.to_string(),
-        ..Default::default()
-    };
-
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
     expect_get_state_entry(
         &mut tx_ctx,
-        command.deal_order_id.clone(),
-        Some(deal_order.clone()),
-        Some(1),
+        allowance_id.to_string(),
+        Some(allowance_with(1000)),
+        None,
     );
 
-    let loan_transfer = protos::Transfer {
-        blockchain: deal_order.blockchain.clone(),
-        src_address: deal_order.src_address.clone(),
-        dst_address: deal_order.dst_address.clone(),
-        order: command.deal_order_id.clone(),
-        amount: deal_order.amount.clone(),
-        tx: "sometx".into(),
-        block: (request.tip - 1).to_string(),
-        processed: false,
-        sighash: my_sighash.to_string(),
-    };
-
-    expect_get_state_entry(
-        &mut tx_ctx,
-        deal_order.loan_transfer.clone(),
-        Some(loan_transfer.clone()),
-        Some(1),
-    );
+    charge_fee(&mut tx_ctx, &my_sighash);
 
-    let repayment_transfer = protos::Transfer {
-        blockchain: deal_order.blockchain.clone(),
-        src_address: deal_order.src_address.clone(),
-        dst_address: deal_order.dst_address.clone(),
-        order: command.deal_order_id.clone(),
-        amount: deal_order.amount.clone(),
-        tx: "somerepaytx".into(),
-        block: (request.tip - 1).to_string(),
-        processed: false,
-        sighash: my_sighash.to_string(),
-    };
+    let owner_wallet_id = WalletId::from(&SigHash::from("owner"));
+    expect!(tx_ctx, get balance at owner_wallet_id -> Some(500));
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        command.transfer_id.clone(),
-        Some(repayment_transfer.clone()),
-        Some(1),
-    );
+    let receiver_wallet_id = WalletId::from(&SigHash::from("receiver"));
+    expect!(tx_ctx, get balance at receiver_wallet_id -> Some(1));
 
     let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
-
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
-
-    let updated_deal_order = protos::DealOrder {
-        lock: my_sighash.to_string(),
-        repayment_transfer: command.transfer_id.clone(),
-        ..deal_order
-    };
-
-    let updated_repayment_transfer = protos::Transfer {
-        processed: true,
-        ..repayment_transfer
-    };
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
+            (owner_wallet_id.to_string(), wallet_with(Some(100)).unwrap()),
             (
-                command.transfer_id.clone(),
-                updated_repayment_transfer.to_bytes(),
+                receiver_wallet_id.to_string(),
+                wallet_with(Some(401)).unwrap(),
             ),
+            (allowance_id.to_string(), allowance_with(600).to_bytes()),
+            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- Exempt ---
-
 #[test]
-fn exempt_success() {
+fn send_funds_from_exhausts_allowance() {
     init_logs();
 
-    let command = Exempt {
-        deal_order_id: "dealorderid".into(),
-        transfer_id: "repaytransferid".into(),
+    let command = SendFundsFrom {
+        owner: "owner".into(),
+        sighash: "spender".into(),
+        amount: 1000.into(),
     };
 
     let request = TpProcessRequest {
-        tip: 7,
+        tip: 11,
         ..Default::default()
     };
 
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let my_sighash = SigHash::from("mysighash");
+    let my_sighash = SigHash::from("spender");
+    let guid = Guid::from("myguid");
 
     expect!(ctx, sighash -> my_sighash);
+    expect!(ctx, guid -> guid);
 
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        sighash: my_sighash.to_string(),
-        interest: 0.to_string(),
-        ..Default::default()
-    };
-
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
     expect_get_state_entry(
         &mut tx_ctx,
-        command.deal_order_id.clone(),
-        Some(deal_order.clone()),
-        Some(1),
+        allowance_id.to_string(),
+        Some(allowance_with(1000)),
+        None,
     );
 
-    let repayment_transfer = protos::Transfer {
-        blockchain: deal_order.blockchain.clone(),
-        src_address: deal_order.src_address.clone(),
-        dst_address: deal_order.dst_address.clone(),
-        order: command.deal_order_id.clone(),
-        amount: deal_order.amount.clone(),
-        tx: "somerepaytx".into(),
-        block: (request.tip - 1).to_string(),
-        processed: false,
-        sighash: my_sighash.to_string(),
+    charge_fee(&mut tx_ctx, &my_sighash);
+
+    let owner_wallet_id = WalletId::from(&SigHash::from("owner"));
+    expect!(tx_ctx, get balance at owner_wallet_id -> Some(1000));
+
+    // the spender is also the receiver, the funds land in the charged wallet
+    let wallet_id = WalletId::from(&my_sighash);
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![
+            (owner_wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
+            (wallet_id.to_string(), wallet_with(Some(1000)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
+        ],
+    );
+    expect_delete_state_entries(&mut tx_ctx, vec![allowance_id.to_string()]);
+
+    execute_success(command, &request, &tx_ctx, &mut ctx);
+}
+
+#[test]
+fn send_funds_from_exceeds_allowance() {
+    init_logs();
+
+    let command = SendFundsFrom {
+        owner: "owner".into(),
+        sighash: "receiver".into(),
+        amount: 1001.into(),
     };
 
+    let request = TpProcessRequest::default();
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "spender");
+
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
     expect_get_state_entry(
         &mut tx_ctx,
-        command.transfer_id.clone(),
-        Some(repayment_transfer.clone()),
-        Some(1),
+        allowance_id.to_string(),
+        Some(allowance_with(1000)),
+        None,
     );
 
-    let src_address = protos::Address {
-        blockchain: deal_order.blockchain.clone(),
-        value: "srcaddressvalue".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.to_string(),
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "Insufficient allowance",
+    );
+}
+
+#[test]
+fn send_funds_from_without_allowance() {
+    init_logs();
+
+    let command = SendFundsFrom {
+        owner: "owner".into(),
+        sighash: "receiver".into(),
+        amount: 1.into(),
+    };
+
+    let request = TpProcessRequest::default();
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+
+    expect!(ctx, sighash -> "spender");
+
+    let allowance_id = Address::with_prefix_key(ALLOWANCE, "ownerspender");
+    expect!(tx_ctx, get_state_entry where enclose!((allowance_id) move |a| a == allowance_id.as_str()), returning |_| Ok(None));
+
+    execute_failure(
+        command,
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        "Insufficient allowance",
+    );
+}
+
+const BURN_ETH_ADDRESS: &str = "0x52908400098527886e0f7030069857d2e4169ee7";
+
+fn burn_with(nonce: u64, processed: bool) -> protos::Burn {
+    protos::Burn {
+        sighash: "mysighash".into(),
+        eth_address: BURN_ETH_ADDRESS.into(),
+        amount: 1000.to_string(),
+        nonce,
+        block: 10.to_string(),
+        processed,
+        blockchain_tx_id: if processed {
+            "blockchainid".into()
+        } else {
+            String::new()
+        },
+    }
+}
+
+#[test]
+fn burn_coins_success() {
+    init_logs();
+
+    let command = BurnCoins {
+        eth_address: BURN_ETH_ADDRESS.into(),
+        amount: 1000.into(),
     };
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        deal_order.src_address.clone(),
-        Some(src_address.clone()),
-        Some(1),
-    );
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
+    };
 
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
-    let guid = Guid::from("txnguid");
+    let my_sighash = SigHash::from("mysighash");
+    let guid = Guid::from("myguid");
+
+    expect!(ctx, sighash -> my_sighash);
     expect!(ctx, guid -> guid);
 
-    let updated_deal_order = protos::DealOrder {
-        repayment_transfer: command.transfer_id.clone(),
-        ..deal_order
-    };
+    let wallet_id = WalletId::from(&my_sighash);
+    let balance = Integer::from(1005) + &*TX_FEE;
+    expect!(tx_ctx, get balance at wallet_id -> Some(balance));
 
-    let updated_repayment_transfer = protos::Transfer {
-        processed: true,
-        ..repayment_transfer
-    };
+    let counter = Address::with_prefix_key(BURN, BURN_NONCE_KEY);
+    expect!(tx_ctx, get_state_entry where enclose!((counter) move |a| a == counter.as_str()), returning |_| Ok(Some(b"7".to_vec())));
+
+    let burn_id = Address::with_prefix_key(BURN, "7");
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (command.deal_order_id.clone(), updated_deal_order.to_bytes()),
-            (
-                command.transfer_id.clone(),
-                updated_repayment_transfer.to_bytes(),
-            ),
+            (burn_id.to_string(), burn_with(7, false).to_bytes()),
+            (counter.to_string(), b"8".to_vec()),
+            (wallet_id.to_string(), wallet_with(Some(5)).unwrap()),
+            make_fee(&guid, &my_sighash, Some(10)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- AddRepaymentOrder ---
-
 #[test]
-fn add_repayment_order_success() {
+fn burn_coins_insufficient_funds() {
     init_logs();
 
-    let command = AddRepaymentOrder {
-        deal_order_id: "dealorderid".into(),
-        address_id: "buyeraddressid".into(),
-        amount: 5.to_string(),
-        expiration: 10000,
-    };
-
-    let request = TpProcessRequest {
-        tip: 7,
-        ..Default::default()
+    let command = BurnCoins {
+        eth_address: BURN_ETH_ADDRESS.into(),
+        amount: 1000.into(),
     };
 
+    let request = TpProcessRequest::default();
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let fundraiser_sighash = SigHash::from("fundraisersighash");
-
-    let my_sighash = SigHash::from("loanersighash");
-
-    let other_investor_sighash = SigHash::from("otherinvestorsighash");
-
+    let my_sighash = SigHash::from("mysighash");
     expect!(ctx, sighash -> my_sighash);
 
     let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+    expect!(tx_ctx, get balance at wallet_id -> Some(1000));
 
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Insufficient funds");
+}
 
-    let repay_id = Address::with_prefix_key(REPAYMENT_ORDER, &guid);
+#[test]
+fn burn_coins_invalid_eth_address() {
+    init_logs();
 
-    expect_get_state_entry(&mut tx_ctx, &repay_id, Option::<String>::None, Some(1));
+    for eth_address in &[
+        "ethaddress",
+        "0x52908400098527886e0f7030069857d2e4169ee",
+        "0x52908400098527886e0f7030069857d2e4169eeg",
+    ] {
+        let command = BurnCoins {
+            eth_address: eth_address.to_string(),
+            amount: 1000.into(),
+        };
 
-    // Construct the deal order
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        sighash: fundraiser_sighash.to_string(),
-        interest: 0.to_string(),
-        ..Default::default()
-    };
+        let request = TpProcessRequest::default();
+        let tx_ctx = MockTransactionContext::default();
+        let mut ctx = MockHandlerContext::default();
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        command.deal_order_id.clone(),
-        Some(deal_order.clone()),
-        Some(1),
-    );
+        let my_sighash = SigHash::from("mysighash");
+        expect!(ctx, sighash -> my_sighash);
 
-    let src_address = protos::Address {
-        blockchain: deal_order.blockchain.clone(),
-        value: "srcaddressvalue".into(),
-        network: "rinkeby".into(),
-        sighash: other_investor_sighash.to_string(),
-    };
+        execute_failure(
+            command,
+            &request,
+            &tx_ctx,
+            &mut ctx,
+            "Invalid Ethereum address",
+        );
+    }
+}
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        deal_order.src_address.clone(),
-        Some(src_address.clone()),
-        Some(1),
-    );
+#[test]
+fn confirm_burn_success() {
+    init_logs();
 
-    let new_address = protos::Address {
-        value: "newaddressvalue".into(),
-        sighash: my_sighash.to_string(),
-        ..src_address.clone()
+    let burn_id = Address::with_prefix_key(BURN, "7").to_string();
+    let command = ConfirmBurn {
+        burn_id: burn_id.clone(),
+        blockchain_tx_id: "blockchainid".into(),
+    };
+
+    let request = TpProcessRequest {
+        tip: 11,
+        ..Default::default()
     };
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
 
     expect_get_state_entry(
         &mut tx_ctx,
-        command.address_id.clone(),
-        Some(new_address.clone()),
-        Some(1),
+        burn_id.clone(),
+        Some(burn_with(7, false)),
+        None,
     );
 
+    let relayer = SigHash::from("relayersighash");
+    let guid = Guid::from("myguid");
+    expect!(ctx, sighash -> relayer);
     expect!(ctx, guid -> guid);
 
-    let repayment_order = protos::RepaymentOrder {
-        blockchain: src_address.blockchain,
-        src_address: command.address_id.clone(),
-        dst_address: deal_order.src_address,
-        amount: command.amount.clone(),
-        expiration: command.expiration,
-        block: (request.tip - 1).to_string(),
-        deal: command.deal_order_id.clone(),
-        sighash: my_sighash.to_string(),
-        ..Default::default()
-    };
+    let wallet_id = WalletId::from(&relayer);
+    let fee = TX_FEE.clone();
+    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+
+    expect_bridge_settings(&mut ctx, Some(["goerli", "testnet", "0xbridge"]));
+    expect!(ctx, verify where |c| c == "goerli burn mysighash 0xbridge 0x52908400098527886e0f7030069857d2e4169ee7 1000 blockchainid 7 testnet", returning |_| Ok(()));
 
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
+            (burn_id, burn_with(7, true).to_bytes()),
             (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (repay_id.to_string(), repayment_order.to_bytes()),
+            make_fee(&guid, &relayer, Some(10)),
         ],
     );
 
     execute_success(command, &request, &tx_ctx, &mut ctx);
 }
 
-// --- CompleteRepaymentOrder ---
-
 #[test]
-fn complete_repayment_order_success() {
+fn confirm_burn_already_confirmed() {
     init_logs();
 
-    let command = CompleteRepaymentOrder {
-        repayment_order_id: "repayorderid".into(),
-    };
-
-    let request = TpProcessRequest {
-        tip: 8,
-        ..Default::default()
+    let burn_id = Address::with_prefix_key(BURN, "7").to_string();
+    let command = ConfirmBurn {
+        burn_id: burn_id.clone(),
+        blockchain_tx_id: "blockchainid".into(),
     };
 
+    let request = TpProcessRequest::default();
     let mut tx_ctx = MockTransactionContext::default();
     let mut ctx = MockHandlerContext::default();
 
-    let fundraiser_sighash = SigHash::from("fundraisersighash");
-
-    let my_sighash = SigHash::from("investorsighash");
-
-    let buyer_sighash = SigHash::from("buyersighash");
+    expect_get_state_entry(&mut tx_ctx, burn_id, Some(burn_with(7, true)), None);
 
-    expect!(ctx, sighash -> my_sighash);
+    execute_failure(command, &request, &tx_ctx, &mut ctx, "Already confirmed");
+}
 
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+fn resolve_params(settings: &[(&str, &str)], height: u64) -> TxnResult<ProcessorParams> {
+    let settings: BTreeMap<String, String> = settings
+        .iter()
+        .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+    ProcessorParams::resolve(|key| Ok(settings.get(key).cloned()), height)
+}
 
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
+#[test]
+fn processor_params_default_without_settings() {
+    assert_eq!(
+        resolve_params(&[], 100).unwrap(),
+        ProcessorParams::default()
+    );
+}
 
-    let deal_order_id = "dealorderid".to_string();
+#[test]
+fn processor_params_override() {
+    let params = resolve_params(
+        &[
+            (CONFIRMATION_COUNT_KEY, "12"),
+            (REWARD_AMOUNT_KEY, "1000"),
+            (DEAL_EXP_FIX_BLOCK_KEY, "0"),
+        ],
+        100,
+    )
+    .unwrap();
+    assert_eq!(params.confirmation_count, 12);
+    assert_eq!(params.reward_amount, 1000);
+    assert_eq!(params.deal_exp_fix_block, 0);
+    assert_eq!(params.year_of_blocks, YEAR_OF_BLOCKS);
+}
 
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        sighash: fundraiser_sighash.to_string(),
-        interest: 0.to_string(),
-        ..Default::default()
-    };
+#[test]
+fn processor_params_activate_at_height() {
+    let settings = [(BLOCK_REWARD_PROCESSING_COUNT_KEY, "20@100")];
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        &deal_order_id,
-        Some(deal_order.clone()),
-        Some(1),
+    let before = resolve_params(&settings, 99).unwrap();
+    assert_eq!(
+        before.block_reward_processing_count,
+        BLOCK_REWARD_PROCESSING_COUNT
     );
 
-    let repayment_order = protos::RepaymentOrder {
-        blockchain: "ethereum".into(),
-        src_address: "buyeraddressid".into(),
-        dst_address: deal_order.src_address.clone(),
-        amount: 5.to_string(),
-        expiration: 10000,
-        block: (request.tip - 2).to_string(),
-        deal: deal_order_id.clone(),
-        sighash: buyer_sighash.to_string(),
-        ..Default::default()
-    };
+    let after = resolve_params(&settings, 100).unwrap();
+    assert_eq!(after.block_reward_processing_count, 20);
+}
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        &command.repayment_order_id,
-        Some(repayment_order.clone()),
-        Some(1),
+#[test]
+fn processor_params_keep_prior_value_until_activation() {
+    let settings = [(BLOCK_REWARD_PROCESSING_COUNT_KEY, "20, 30@100, 40@200")];
+
+    assert_eq!(
+        resolve_params(&settings, 99)
+            .unwrap()
+            .block_reward_processing_count,
+        20
+    );
+    assert_eq!(
+        resolve_params(&settings, 100)
+            .unwrap()
+            .block_reward_processing_count,
+        30
+    );
+    assert_eq!(
+        resolve_params(&settings, 250)
+            .unwrap()
+            .block_reward_processing_count,
+        40
     );
+}
 
-    let dst_address = protos::Address {
-        blockchain: repayment_order.blockchain.clone(),
-        value: "oldsrcaddressvalue".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.to_string(),
-    };
+#[test]
+fn processor_params_ignore_invalid_values() {
+    let defaults = ProcessorParams::default();
+    for &setting in &[
+        (YEAR_OF_BLOCKS_KEY, "0"),
+        (CONFIRMATION_COUNT_KEY, "12@soon"),
+        (REWARD_AMOUNT_KEY, "-1"),
+        (TREASURY_SHARE_KEY, "10001"),
+        (TREASURY_SIGHASH_KEY, "treasury"),
+        (EXPIRY_INDEX_BLOCK_KEY, "soon"),
+        (FEE_BUCKET_BLOCKS_KEY, "-1"),
+    ] {
+        assert_eq!(resolve_params(&[setting], 100).unwrap(), defaults);
+    }
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        repayment_order.dst_address.clone(),
-        Some(dst_address.clone()),
-        Some(1),
-    );
+    let params = resolve_params(&[(CONFIRMATION_COUNT_KEY, "12, 15@soon")], 100).unwrap();
+    assert_eq!(params.confirmation_count, 12);
+}
 
-    let updated_repayment_order = protos::RepaymentOrder {
-        previous_owner: my_sighash.to_string(),
-        ..repayment_order
-    };
+#[test]
+fn processor_params_fall_back_to_prior_valid_value() {
+    let settings = [(BLOCK_REWARD_PROCESSING_COUNT_KEY, "20,30@100,abc@200")];
+    assert_eq!(
+        resolve_params(&settings, 250)
+            .unwrap()
+            .block_reward_processing_count,
+        30
+    );
 
-    let updated_deal_order = protos::DealOrder {
-        lock: my_sighash.to_string(),
-        ..deal_order
-    };
+    let settings = [(TREASURY_SHARE_KEY, "2500@100, 10001@200")];
+    assert_eq!(resolve_params(&settings, 250).unwrap().treasury_share, 2500);
+}
 
-    expect_set_state_entries(
-        &mut tx_ctx,
-        vec![
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (
-                command.repayment_order_id.clone(),
-                updated_repayment_order.to_bytes(),
-            ),
-            (deal_order_id.clone(), updated_deal_order.to_bytes()),
+#[test]
+fn processor_params_treasury() {
+    let treasury = "ab".repeat(30);
+    let params = resolve_params(
+        &[
+            (TREASURY_SIGHASH_KEY, treasury.as_str()),
+            (TREASURY_SHARE_KEY, "2500@100"),
         ],
-    );
+        99,
+    )
+    .unwrap();
+    assert_eq!(params.treasury_sighash, Some(treasury.clone()));
+    assert_eq!(params.treasury_share, 0);
+
+    let params = resolve_params(&[(TREASURY_SHARE_KEY, "2500@100")], 100).unwrap();
+    assert_eq!(params.treasury_share, 2500);
+}
+
+fn fork_schedule(update1: Option<&str>) -> ForkSchedule {
+    let update1 = update1.map(str::to_owned);
+    ForkSchedule::resolve(&ProcessorParams::default(), |key| {
+        assert!(forks::SETTING_KEYS.contains(&key));
+        Ok(update1.clone().filter(|_| key == UPDATE1_KEY))
+    })
+    .unwrap()
+}
 
-    execute_success(command, &request, &tx_ctx, &mut ctx);
+#[test]
+fn fork_deal_expiration_refund_boundary() {
+    let forks = fork_schedule(None);
+    for tip in DEAL_EXP_FIX_BLOCK - 2..DEAL_EXP_FIX_BLOCK + 3 {
+        // the check previously inlined in Housekeeping
+        let legacy = tip > DEAL_EXP_FIX_BLOCK;
+        assert_eq!(
+            forks.is_active(Feature::DealExpirationRefund, tip),
+            legacy,
+            "tip {}",
+            tip
+        );
+    }
 }
 
-// --- CloseRepaymentOrder ---
+#[test]
+fn fork_reward_formula_update1_boundary() {
+    let update_block = 1000u64;
+    let forks = fork_schedule(Some("1000"));
+    for processed in update_block + 497..update_block + 504 {
+        // the check previously inlined in reward
+        let legacy = Integer::from(update_block) + 500 < processed;
+        assert_eq!(
+            forks.is_active(Feature::RewardFormulaUpdate1, processed),
+            legacy,
+            "processed block {}",
+            processed
+        );
+    }
+}
 
 #[test]
-fn close_repayment_order_success() {
-    init_logs();
+fn fork_reward_formula_update1_unset() {
+    let forks = fork_schedule(None);
+    assert_eq!(forks.activation(Feature::RewardFormulaUpdate1), None);
+    assert!(!forks.is_active(Feature::RewardFormulaUpdate1, u64::MAX));
+}
 
-    let command = CloseRepaymentOrder {
-        repayment_order_id: "repayorderid".into(),
-        transfer_id: "repaytransferid".into(),
-    };
+#[test]
+fn fork_reward_formula_update1_malformed() {
+    // a malformed height leaves the formula inactive instead of failing every transaction
+    for update1 in &["abc", "", "18446744073709551615"] {
+        let forks = fork_schedule(Some(update1));
+        assert_eq!(
+            forks.activation(Feature::RewardFormulaUpdate1),
+            None,
+            "update1 {:?}",
+            update1
+        );
+    }
+}
 
-    let request = TpProcessRequest {
-        tip: 9,
+#[test]
+fn fork_activation_follows_params() {
+    let params = ProcessorParams {
+        deal_exp_fix_block: 10,
         ..Default::default()
     };
+    let forks = ForkSchedule::resolve(&params, |_| Ok(None)).unwrap();
+    assert!(!forks.is_active(Feature::DealExpirationRefund, 10));
+    assert!(forks.is_active(Feature::DealExpirationRefund, 11));
+    for &feature in &Feature::ALL {
+        assert_eq!(
+            forks.is_active(feature, 0),
+            forks.activation(feature) == Some(0)
+        );
+    }
+}
 
-    let mut tx_ctx = MockTransactionContext::default();
-    let mut ctx = MockHandlerContext::default();
+/// The float-formatting reward computation `decayed_reward` replaced.
+fn legacy_decayed_reward(period: i32) -> Integer {
+    let fraction = (19.0f64 / 20.0f64).powi(period);
+    let fraction_str = format!("{:.6}", fraction);
+    let pos = fraction_str.find('.').unwrap();
+
+    let fraction_in_wei_str = if fraction_str.starts_with('0') {
+        format!("{}{:0<18}", &fraction_str[..pos], &fraction_str[pos + 1..])
+    } else {
+        let mut pos = 2;
+        for c in fraction_str.bytes().skip(pos) {
+            if c == b'0' {
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+        format!("{:0<width$}", &fraction_str[pos..], width = 20 - pos)
+    };
 
-    let fundraiser_sighash = SigHash::from("fundraisersighash");
+    28 * Integer::try_parse(&fraction_in_wei_str).unwrap()
+}
 
-    let my_sighash = SigHash::from("buyersighash");
+#[test]
+fn decayed_reward_matches_legacy() {
+    for period in 0..REWARD_DECAY_CUTOFF_PERIOD + 100 {
+        assert_eq!(
+            super::decayed_reward(period),
+            legacy_decayed_reward(period),
+            "period {}",
+            period
+        );
+    }
+}
 
-    let owner_sighash = SigHash::from("investorsighash");
+#[test]
+fn decayed_reward_values() {
+    assert_eq!(super::decayed_reward(0), 0);
+    assert_eq!(
+        super::decayed_reward(1),
+        Integer::from(950_000_000_000_000_000u64) * 28
+    );
+    assert!(super::decayed_reward(REWARD_DECAY_CUTOFF_PERIOD - 1) > 0);
+    assert_eq!(super::decayed_reward(REWARD_DECAY_CUTOFF_PERIOD), 0);
+    assert_eq!(super::decayed_reward(i32::MAX), 0);
+}
 
-    expect!(ctx, sighash -> my_sighash);
+#[test]
+fn decayed_reward_matches_legacy_exhaustive() {
+    // past the cutoff both round to zero, so samples up to i32::MAX cover the rest
+    let samples = [
+        REWARD_DECAY_CUTOFF_PERIOD + 1,
+        1_000,
+        65_536,
+        i32::MAX / 2,
+        i32::MAX - 1,
+        i32::MAX,
+    ];
+    for period in (0..=REWARD_DECAY_CUTOFF_PERIOD).chain(samples.iter().copied()) {
+        assert_eq!(
+            super::decayed_reward(period),
+            legacy_decayed_reward(period),
+            "period {}",
+            period
+        );
+    }
+}
 
-    let wallet_id = WalletId::from(&my_sighash);
-    let fee = TX_FEE.clone();
-    expect!(tx_ctx, get balance at wallet_id -> Some(fee));
+fn reward_schedule(setting: Option<&str>) -> TxnResult<Option<ActiveSchedule>> {
+    let setting = setting.map(str::to_owned);
+    RewardSchedule::resolve(|key| {
+        assert_eq!(key, REWARD_SCHEDULE_KEY);
+        Ok(setting.clone())
+    })
+}
 
-    let guid = Guid::from("txnguid");
-    expect!(ctx, guid -> guid);
+#[test]
+fn reward_schedule_parses_each_kind() {
+    assert_eq!(reward_schedule(None).unwrap(), None);
+    assert_eq!(
+        reward_schedule(Some("flat:5")).unwrap(),
+        Some(ActiveSchedule {
+            schedule: RewardSchedule::Flat {
+                amount: Integer::from(5)
+            },
+            activation: 0,
+        })
+    );
+    assert_eq!(
+        reward_schedule(Some("decay:1000:19/20:10@42")).unwrap(),
+        Some(ActiveSchedule {
+            schedule: RewardSchedule::GeometricDecay {
+                initial: Integer::from(1000),
+                numerator: 19,
+                denominator: 20,
+                period: 10,
+            },
+            activation: 42,
+        })
+    );
+    assert_eq!(
+        RewardSchedule::parse("halving:64:100").unwrap(),
+        RewardSchedule::StepHalving {
+            initial: Integer::from(64),
+            interval: 100,
+        }
+    );
+    assert_eq!(
+        RewardSchedule::parse("tail:64:100:3:1000").unwrap(),
+        RewardSchedule::TailEmission {
+            initial: Integer::from(64),
+            interval: 100,
+            tail: Integer::from(3),
+            cap: Integer::from(1000),
+        }
+    );
+}
 
-    let deal_order_id = "dealorderid".to_string();
+#[test]
+fn reward_schedule_rejects_invalid_settings() {
+    for setting in &[
+        "",
+        "flat",
+        "flat:abc",
+        "flat:5:6",
+        "curve:5",
+        "decay:1000:20/19:10",
+        "decay:1000:19:10",
+        "decay:1000:19/20:0",
+        "halving:64:0",
+        "tail:64:100:3",
+        "flat:5@soon",
+    ] {
+        let err = reward_schedule(Some(setting)).unwrap_err();
+        let expected = format!("Invalid reward schedule : {:?}", setting);
+        assert!(matches!(
+            err.downcast_ref::<CCApplyError>(),
+            Some(CCApplyError::InvalidTransaction(s)) if *s == expected
+        ));
+    }
+}
 
-    let deal_order = protos::DealOrder {
-        blockchain: "ethereum".into(),
-        src_address: "srcaddressid".into(),
-        dst_address: "dstaddressid".into(),
-        amount: 1.to_string(),
-        maturity: 1000.to_string(),
-        fee: 1.to_string(),
-        expiration: 10000,
-        block: 4.to_string(),
-        loan_transfer: "transferid".into(),
-        sighash: fundraiser_sighash.to_string(),
-        interest: 0.to_string(),
-        lock: my_sighash.to_string(),
-        ..Default::default()
-    };
+#[test]
+fn reward_schedule_values() {
+    let minted = Integer::new();
+
+    let decay = RewardSchedule::parse("decay:1000:1/2:10").unwrap();
+    assert_eq!(decay.reward(9, &minted), 1000);
+    assert_eq!(decay.reward(10, &minted), 500);
+    assert_eq!(decay.reward(35, &minted), 125);
+    assert_eq!(decay.reward(u64::MAX, &minted), 0);
+    // the decay rounds once, not once per period
+    let decay = RewardSchedule::parse("decay:1000:19/20:1").unwrap();
+    assert_eq!(decay.reward(100, &minted), 5);
+    assert_eq!(decay.reward(135, &minted), 0);
+
+    let halving = RewardSchedule::parse("halving:64:100").unwrap();
+    assert_eq!(halving.reward(0, &minted), 64);
+    assert_eq!(halving.reward(199, &minted), 32);
+    assert_eq!(halving.reward(600, &minted), 1);
+    assert_eq!(halving.reward(700, &minted), 0);
+    assert_eq!(halving.reward(u64::MAX, &minted), 0);
+
+    let tail = RewardSchedule::parse("tail:64:100:3:1000").unwrap();
+    assert_eq!(tail.reward(100, &minted), 32);
+    assert_eq!(tail.reward(u64::MAX, &minted), 3);
+    assert_eq!(tail.reward(0, &Integer::from(990)), 10);
+    assert_eq!(tail.reward(0, &Integer::from(1000)), 0);
+    assert_eq!(tail.reward(0, &Integer::from(1200)), 0);
+}
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        &deal_order_id,
-        Some(deal_order.clone()),
-        Some(1),
-    );
+#[test]
+fn reward_applies_schedule_from_activation_and_enforces_cap() {
+    init_logs();
 
-    let repayment_order = protos::RepaymentOrder {
-        blockchain: "ethereum".into(),
-        src_address: "buyeraddressid".into(),
-        dst_address: deal_order.src_address.clone(),
-        amount: 5.to_string(),
-        expiration: 10000,
-        block: (request.tip - 2).to_string(),
-        deal: deal_order_id.clone(),
-        sighash: my_sighash.to_string(),
-        previous_owner: owner_sighash.to_string(),
+    let request = TpProcessRequest::default();
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx,
+        get_setting(k if k == REWARD_SCHEDULE_KEY) -> Ok(Some("tail:100:2:10:250@5".into()))
+    );
+    expect!(ctx, get_setting(k if k == INITIAL_SUPPLY_KEY) -> Ok(None));
+
+    let supply = Address::with_prefix_key(SUPPLY, MINTED_SUPPLY_KEY);
+    expect!(tx_ctx, get_state_entry where enclose!((supply) move |a| a == supply.as_str()), returning |_| Ok(None));
+
+    // block 4 predates the schedule but counts towards the supply, blocks 5 and 6 pay the initial
+    // 100, block 7 pays the rest of the cap instead of the halved 50 and block 8 pays nothing
+    let rewards = vec![
+        (4u64, Some(Integer::from(20))),
+        (5, Some(Integer::from(100))),
+        (6, Some(Integer::from(100))),
+        (7, Some(Integer::from(30))),
+        (8, None),
+    ];
+    let mut entries = vec![(supply.to_string(), b"250".to_vec())];
+    for (height, reward) in rewards {
+        let signer = format!("signer{}", height);
+        expect!(tx_ctx, get_sig_by_num(h if *h == height) -> Ok(signer.clone()));
+        if let Some(reward) = reward {
+            let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+            expect!(tx_ctx, get balance at wallet_id -> None);
+            entries.push((wallet_id.to_string(), wallet_with(Some(reward)).unwrap()));
+        }
+    }
+    expect_set_state_entries(&mut tx_ctx, entries);
+
+    let params = ProcessorParams {
+        reward_amount: Integer::from(20),
         ..Default::default()
     };
+    super::reward(
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        &params,
+        &Integer::from(3),
+        &Integer::from(8),
+    )
+    .unwrap();
+}
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        &command.repayment_order_id,
-        Some(repayment_order.clone()),
-        Some(1),
-    );
+#[test]
+fn reward_signatures_pay_capped_blocks_in_block_order() {
+    init_logs();
 
-    let repayment_transfer = protos::Transfer {
-        blockchain: repayment_order.blockchain.clone(),
-        src_address: repayment_order.src_address.clone(),
-        dst_address: repayment_order.dst_address.clone(),
-        order: command.repayment_order_id.clone(),
-        amount: repayment_order.amount.clone(),
-        tx: "sometx".into(),
-        block: (request.tip - 2).to_string(),
-        processed: false,
-        sighash: my_sighash.to_string(),
+    let request = TpProcessRequest {
+        block_signature: "headblocksig".into(),
+        ..Default::default()
     };
-
-    expect_get_state_entry(
-        &mut tx_ctx,
-        &command.transfer_id,
-        Some(repayment_transfer.clone()),
-        Some(1),
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx,
+        get_setting(k if k == REWARD_SCHEDULE_KEY) -> Ok(Some("tail:100:10:10:150".into()))
     );
+    expect!(ctx, get_setting(k if k == INITIAL_SUPPLY_KEY) -> Ok(None));
 
-    let dst_address = protos::Address {
-        blockchain: repayment_order.blockchain.clone(),
-        value: "oldsrcaddressvalue".into(),
-        network: "rinkeby".into(),
-        sighash: my_sighash.to_string(),
-    };
+    let supply = Address::with_prefix_key(SUPPLY, MINTED_SUPPLY_KEY);
+    expect!(tx_ctx, get_state_entry where enclose!((supply) move |a| a == supply.as_str()), returning |_| Ok(None));
 
-    expect_get_state_entry(
-        &mut tx_ctx,
-        deal_order.src_address.clone(),
-        Some(dst_address.clone()),
-        Some(1),
+    // the signatures come from the last block down, the cap still pays block 1 in full and
+    // block 2 the rest, as the range path does
+    expect!(tx_ctx,
+        get_reward_block_signatures(id if id == "headblocksig", first if *first == 3, last if *last == 1)
+        -> Ok(vec!["signer3".into(), "signer2".into(), "signer1".into()])
     );
+    let mut entries = vec![(supply.to_string(), b"150".to_vec())];
+    for &(signer, reward) in &[("signer1", 100u64), ("signer2", 50)] {
+        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+        expect!(tx_ctx, get balance at wallet_id -> None);
+        entries.push((wallet_id.to_string(), wallet_with(Some(reward)).unwrap()));
+    }
+    expect_set_state_entries(&mut tx_ctx, entries);
 
-    let updated_repayment_order = protos::RepaymentOrder {
-        transfer: command.transfer_id.clone(),
-        ..repayment_order
-    };
+    super::reward(
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        &ProcessorParams::default(),
+        &Integer::new(),
+        &Integer::from(3),
+    )
+    .unwrap();
+}
 
-    let updated_deal_order = protos::DealOrder {
-        src_address: updated_repayment_order.src_address.clone(),
-        lock: "".to_string(),
-        ..deal_order
-    };
+#[test]
+fn reward_seeds_minted_supply_from_initial_supply() {
+    init_logs();
 
-    let updated_transfer = protos::Transfer {
-        processed: true,
-        ..repayment_transfer
-    };
+    let request = TpProcessRequest::default();
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    expect_forks_unset(&mut ctx);
+    expect!(ctx,
+        get_setting(k if k == REWARD_SCHEDULE_KEY) -> Ok(Some("tail:100:10:10:150".into()))
+    );
+    expect!(ctx, get_setting(k if k == INITIAL_SUPPLY_KEY) -> Ok(Some("120".into())));
+
+    let supply = Address::with_prefix_key(SUPPLY, MINTED_SUPPLY_KEY);
+    expect!(tx_ctx, get_state_entry where enclose!((supply) move |a| a == supply.as_str()), returning |_| Ok(None));
 
+    // the coins issued before the schedule leave 30 under the cap
+    let signer = "signer1";
+    expect!(tx_ctx, get_sig_by_num(h if *h == 1) -> Ok(signer.to_owned()));
+    let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+    expect!(tx_ctx, get balance at wallet_id -> None);
     expect_set_state_entries(
         &mut tx_ctx,
         vec![
-            (wallet_id.to_string(), wallet_with(Some(0)).unwrap()),
-            make_fee(&guid, &my_sighash, Some(request.tip - 1)),
-            (
-                command.repayment_order_id.clone(),
-                updated_repayment_order.to_bytes(),
-            ),
-            (deal_order_id.clone(), updated_deal_order.to_bytes()),
-            (command.transfer_id.clone(), updated_transfer.to_bytes()),
+            (supply.to_string(), b"150".to_vec()),
+            (wallet_id.to_string(), wallet_with(Some(30)).unwrap()),
         ],
     );
 
-    execute_success(command, &request, &tx_ctx, &mut ctx);
+    super::reward(
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        &ProcessorParams::default(),
+        &Integer::new(),
+        &Integer::from(1),
+    )
+    .unwrap();
 }
 
-// --- Housekeeping ---
-
-#[test]
-fn housekeeping_reward_in_chain() {
-    init_logs();
-
-    // Housekeeeping with block idx = 0
-    let command = Housekeeping {
-        block_idx: Integer::new(),
-    };
-
-    // Chain tip is far ahead
-    let request = TpProcessRequest {
-        tip: (CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT) * 4,
+fn award_with_treasury(
+    share: u64,
+    reward: u64,
+    treasury_reward: Option<u64>,
+    signer_reward: Option<u64>,
+) {
+    let treasury = "ab".repeat(30);
+    let params = ProcessorParams {
+        reward_amount: Integer::from(reward),
+        treasury_sighash: Some(treasury.clone()),
+        treasury_share: share,
         ..Default::default()
     };
-    let mut tx_ctx = MockTransactionContext::default();
-
-    // get_state_entry should be called on the processed_block_idx address, and we will return
-    // CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT, which will force housekeeping to run
-    expect!(tx_ctx,
-        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
-        -> Ok(Some(
-            Integer::from(CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT).to_string().into_bytes()
-        ))
-    );
 
-    // pretend update1 is not set
-    let mut ctx = MockHandlerContext::default();
-    expect!(ctx,
-        get_setting(k if k == "sawtooth.validator.update1") -> Ok(None)
-    );
+    let mut expected = BTreeMap::new();
+    if let Some(amount) = treasury_reward {
+        let wallet_id = WalletId::from(&SigHash(treasury));
+        expected.insert(wallet_id.to_string(), Integer::from(amount));
+    }
+    if let Some(amount) = signer_reward {
+        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(b"signer")));
+        expected.insert(wallet_id.to_string(), Integer::from(amount));
+    }
 
-    let height_start = CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT + 1;
-    let height_end = height_start + BLOCK_REWARD_PROCESSING_COUNT;
+    let mut rewards = BTreeMap::new();
+    super::award(
+        &mut rewards,
+        &params,
+        super::RewardFormula::Flat,
+        None,
+        &mut Integer::new(),
+        &Integer::from(1),
+        "signer",
+    )
+    .unwrap();
+    assert_eq!(rewards, expected);
+}
 
-    let mut signers = vec![];
+fn award_with_formula(formula: super::RewardFormula, block_idx: u64) -> BTreeMap<String, Integer> {
+    let mut rewards = BTreeMap::new();
+    super::award(
+        &mut rewards,
+        &ProcessorParams::default(),
+        formula,
+        None,
+        &mut Integer::new(),
+        &Integer::from(block_idx),
+        "signer",
+    )
+    .unwrap();
+    rewards
+}
 
-    // housekeeping tries to get the signatures for the blocks
-    // from height_start to height_end in order to issue mining rewards
-    // return a dummy signer
-    for height in height_start..height_end {
-        let signer = format!("signer{}", height);
-        signers.push(signer.clone());
-        expect!(tx_ctx,
-            get_sig_by_num(h if *h == height) -> Ok(signer)
-        );
-    }
+#[test]
+fn award_update1_pays_only_once_fixed() {
+    let block_idx = BLOCKS_IN_PERIOD_UPDATE1 + 1;
+    // the deployed update1 formula never paid its reward
+    assert!(award_with_formula(super::RewardFormula::Update1, block_idx).is_empty());
+
+    let wallet_id = WalletId::from(&SigHash(utils::sha512_id(b"signer")));
+    let mut expected = BTreeMap::new();
+    expected.insert(wallet_id.to_string(), super::decayed_reward(1));
+    assert_eq!(
+        award_with_formula(super::RewardFormula::Update1Fixed, block_idx),
+        expected
+    );
+}
 
-    let reward_amount = REWARD_AMOUNT.clone();
+#[test]
+fn fork_reward_formula_fix_from_setting() {
+    let forks = ForkSchedule::resolve(&ProcessorParams::default(), |key| {
+        Ok(Some("5000".to_owned()).filter(|_| key == REWARD_FORMULA_FIX_BLOCK_KEY))
+    })
+    .unwrap();
+    assert!(!forks.is_active(Feature::RewardFormulaFix, 4999));
+    assert!(forks.is_active(Feature::RewardFormulaFix, 5000));
+    assert!(!fork_schedule(None).is_active(Feature::RewardFormulaFix, u64::MAX));
+}
 
-    for (idx, signer) in signers.clone().into_iter().enumerate() {
-        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
+#[test]
+fn award_splits_reward_with_treasury() {
+    award_with_treasury(2500, 1000, Some(250), Some(750));
+    // the treasury share rounds down and the remainder goes to the signer
+    award_with_treasury(2500, 1001, Some(250), Some(751));
+    award_with_treasury(3333, 7, Some(2), Some(5));
+    // a share rounding to zero leaves the treasury wallet untouched
+    award_with_treasury(1, 9999, None, Some(9999));
+    award_with_treasury(0, 1000, None, Some(1000));
+    // the full share leaves the signer wallet untouched
+    award_with_treasury(10000, 1000, Some(1000), None);
+}
 
-        // the first signer has no wallet, the rest have an existing balance of `idx`
-        let balance = if idx == 0 { None } else { Some(idx as u64) };
+#[test]
+fn reward_reads_and_writes_each_wallet_once() {
+    const BLOCKS: u64 = 1_000;
+    const SIGNERS: u64 = 4;
 
-        log::info!("starting balance = {:?}", balance);
+    let request = TpProcessRequest::default();
+    let mut tx_ctx = MockTransactionContext::default();
+    let mut ctx = MockHandlerContext::default();
+    ctx.expect_get_setting().returning(|_| Ok(None));
 
-        // housekeeping should try to fetch the current wallet for each signer
-        // return the balance above
-        expect!(tx_ctx, get balance at wallet_id -> balance);
+    // every tenth block is signed by another validator
+    tx_ctx
+        .expect_get_sig_by_num()
+        .times(BLOCKS as usize)
+        .returning(|height| {
+            let signer = if height % 10 == 0 {
+                height / 10 % SIGNERS
+            } else {
+                0
+            };
+            Ok(format!("signer{}", signer))
+        });
+    // each rewarded wallet is read once and all of them are written in one batch
+    tx_ctx
+        .expect_get_state_entry()
+        .times(SIGNERS as usize)
+        .returning(|_| Ok(wallet_with(Some(1))));
+    tx_ctx
+        .expect_set_state_entries()
+        .times(1)
+        .withf(|entries| entries.len() == SIGNERS as usize)
+        .returning(|_| Ok(()));
 
-        // we expect the wallet to have an updated balance of reward_amount + old balance
-        let amount_expected = reward_amount.clone() + balance.unwrap_or(0);
+    super::reward(
+        &request,
+        &tx_ctx,
+        &mut ctx,
+        &ProcessorParams::default(),
+        &Integer::new(),
+        &Integer::from(BLOCKS),
+    )
+    .unwrap();
+}
 
-        log::info!("expect end wallet = {:?}", amount_expected);
-        // housekeeping should try to set the state to update
-        // the wallet balance with the reward added
-        expect!(
-            tx_ctx,
-            set balance at wallet_id to amount_expected
-        );
-    }
+fn prefix_context(addresses: &'static [&'static str]) -> MockTransactionContext {
+    let mut tx_ctx = MockTransactionContext::default();
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .returning(move |prefix| {
+            // the validator makes no promise about the order of the entries
+            Ok(addresses
+                .iter()
+                .rev()
+                .filter(|a| a.starts_with(prefix))
+                .map(|a| (a.to_string(), a.as_bytes().to_vec()))
+                .collect())
+        });
+    tx_ctx
+}
 
-    // housekeeping should then set the processed_block_idx to the last processed block height
-    // which in this case is height_end - 1
-    expect!(tx_ctx, set_state_entry(
-            addr if addr == PROCESSED_BLOCK_IDX.as_str(),
-            state if state == &(height_end - 1).to_string().into_bytes()
-        ) -> Ok(())
-    );
+fn scan_addresses(
+    tx_ctx: &dyn TransactionContext,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Vec<String> {
+    use crate::ext::TransactionContextExt;
 
-    // run housekeeping
-    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+    tx_ctx
+        .scan_prefix("ab", cursor, limit)
+        .unwrap()
+        .into_iter()
+        .map(|(address, _)| address)
+        .collect()
 }
 
 #[test]
-fn housekeeping_reward_fork() {
-    init_logs();
+fn scan_prefix_pages_in_address_order() {
+    let tx_ctx = prefix_context(&["ab00b", "ab00a", "ab3f0", "abff1", "ac000"]);
 
-    // Housekeeeping with block idx = 0
-    let command = Housekeeping {
-        block_idx: Integer::new(),
-    };
-
-    let last_processed = CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT;
+    assert_eq!(scan_addresses(&tx_ctx, None, 2), vec!["ab00a", "ab00b"]);
+    assert_eq!(
+        scan_addresses(&tx_ctx, Some("ab00b"), 2),
+        vec!["ab3f0", "abff1"]
+    );
+    assert_eq!(scan_addresses(&tx_ctx, Some("ab00a"), 1), vec!["ab00b"]);
+    assert_eq!(
+        scan_addresses(&tx_ctx, Some("abff1"), 2),
+        Vec::<String>::new()
+    );
+    assert_eq!(scan_addresses(&tx_ctx, None, 10).len(), 4);
+}
 
-    // Chain tip is far ahead
-    let request = TpProcessRequest {
-        tip: last_processed * 4,
-        block_signature: "headblocksig".into(),
-        ..Default::default()
-    };
+#[test]
+fn scan_prefix_bounds_fetches() {
+    // an empty prefix costs one fetch per bucket
     let mut tx_ctx = MockTransactionContext::default();
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .times(16)
+        .returning(|_| Ok(vec![]));
+    assert_eq!(scan_addresses(&tx_ctx, None, 2), Vec::<String>::new());
 
-    // get_state_entry should be called on the processed_block_idx address, and we will return
-    // CONFIRMATION_COUNT * 2 + BLOCK_REWARD_PROCESSING_COUNT, which will force housekeeping to run
-    expect!(tx_ctx,
-        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
-        -> Ok(Some(
-            Integer::from(last_processed).to_string().into_bytes()
-        ))
+    // a page resumes from the cursor's bucket and stops fetching once it is full
+    let mut tx_ctx = MockTransactionContext::default();
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .withf(|prefix| prefix == "ab3" || prefix == "ab4")
+        .times(2)
+        .returning(|prefix| {
+            Ok(vec![
+                (format!("{}0", prefix), vec![]),
+                (format!("{}1", prefix), vec![]),
+            ])
+        });
+    assert_eq!(
+        scan_addresses(&tx_ctx, Some("ab31"), 2),
+        vec!["ab40", "ab41"]
     );
+}
 
-    // pretend update1 is not set
+#[test]
+fn filter_resumes_from_cursor() {
+    let prefix = "ab";
+    let cursor_id = Address::with_prefix_key(HOUSEKEEPING_CURSOR, prefix);
+    let mut tx_ctx = prefix_context(&["ab00a", "ab00b", "ab3f0"]);
+
+    // the first run stops after two entries and records where it stopped
+    expect!(tx_ctx, get_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning |_| Ok(None));
+    expect!(tx_ctx, set_state_entry where enclose!((cursor_id) move |a, s| a == cursor_id.as_str() && s == b"ab00b"), returning |_, _| Ok(()));
+    let mut seen = vec![];
+    super::filter(&tx_ctx, prefix, 2, |addr, _| {
+        seen.push(addr.to_owned());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(seen, vec!["ab00a", "ab00b"]);
+
+    // the next run finishes the prefix and clears the cursor
+    let mut tx_ctx = prefix_context(&["ab00a", "ab00b", "ab3f0"]);
+    expect!(tx_ctx, get_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning |_| Ok(Some(b"ab00b".to_vec())));
+    expect!(tx_ctx, delete_state_entry where enclose!((cursor_id) move |a| a == cursor_id.as_str()), returning |a| Ok(Some(a.to_owned())));
+    let mut seen = vec![];
+    super::filter(&tx_ctx, prefix, 2, |addr, _| {
+        seen.push(addr.to_owned());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(seen, vec!["ab3f0"]);
+}
+
+fn order_context(entries: &[(String, Vec<u8>)]) -> MockTransactionContext {
+    let entries = entries.to_vec();
+    let mut tx_ctx = MockTransactionContext::default();
+    tx_ctx
+        .expect_get_state_entries_by_prefix()
+        .returning(move |prefix| {
+            Ok(entries
+                .iter()
+                .filter(|(address, _)| address.starts_with(prefix))
+                .cloned()
+                .collect())
+        });
+    tx_ctx
+}
+
+#[test]
+fn match_orders_moves_asks_after_a_pass_over_bids() {
+    let request = TpProcessRequest::default();
     let mut ctx = MockHandlerContext::default();
-    expect!(ctx,
-        get_setting(k if k == "sawtooth.validator.update1") -> Ok(None)
-    );
+    let bid = string!(NAMESPACE_PREFIX.as_str(), BID_ORDER);
+    let ask = string!(NAMESPACE_PREFIX.as_str(), ASK_ORDER);
+    let (first_bid, second_bid) = (format!("{}00a", bid), format!("{}00b", bid));
+    let first_ask = format!("{}00a", ask);
+    let entries = vec![
+        (first_bid.clone(), protos::BidOrder::default().to_bytes()),
+        (second_bid.clone(), protos::BidOrder::default().to_bytes()),
+        (first_ask.clone(), protos::AskOrder::default().to_bytes()),
+        (
+            format!("{}00b", ask),
+            protos::AskOrder::default().to_bytes(),
+        ),
+    ];
+    let bid_cursor_id = super::match_cursor(&bid);
+    let ask_cursor_id = super::match_cursor(&ask);
+
+    // the first run compares the first bid with the first ask, and the asks stay put
+    let mut tx_ctx = order_context(&entries);
+    expect!(tx_ctx, get_state_entry where enclose!((bid_cursor_id) move |a| a == bid_cursor_id.as_str()), returning |_| Ok(None));
+    expect!(tx_ctx, get_state_entry where enclose!((ask_cursor_id) move |a| a == ask_cursor_id.as_str()), returning |_| Ok(None));
+    expect!(tx_ctx, set_state_entry where enclose!((bid_cursor_id, first_bid) move |a, s| a == bid_cursor_id.as_str() && s == first_bid.as_bytes()), returning |_, _| Ok(()));
+    super::match_orders(&request, &tx_ctx, &mut ctx, 1).unwrap();
+
+    // once every bid has been compared with the first ask, the asks move on
+    let mut tx_ctx = order_context(&entries);
+    expect!(tx_ctx, get_state_entry where enclose!((bid_cursor_id) move |a| a == bid_cursor_id.as_str()), returning move |_| Ok(Some(second_bid.clone().into_bytes())));
+    expect!(tx_ctx, get_state_entry where enclose!((ask_cursor_id) move |a| a == ask_cursor_id.as_str()), returning |_| Ok(None));
+    expect!(tx_ctx, delete_state_entry where enclose!((bid_cursor_id) move |a| a == bid_cursor_id.as_str()), returning |a| Ok(Some(a.to_owned())));
+    expect!(tx_ctx, set_state_entry where enclose!((ask_cursor_id, first_ask) move |a, s| a == ask_cursor_id.as_str() && s == first_ask.as_bytes()), returning |_, _| Ok(()));
+    super::match_orders(&request, &tx_ctx, &mut ctx, 1).unwrap();
+}
 
-    // the get_reward_block_signatures path iterates in reverse inclusively, so if last_processed = 5
-    // and BLOCK_REWARD_PROCESSING_COUNT = 5, then the bounds
-    // should be [10, 6] i.e. [last_processed + BLOCK_REWARD_PROCESSING_COUNT, last_processed+1]
-    let last_pred = last_processed + 1;
-    let first_pred = last_processed + BLOCK_REWARD_PROCESSING_COUNT;
+fn ask_order_at(block: u64, expiration: u64) -> (Address, Vec<u8>) {
+    let ask_order = protos::AskOrder {
+        block: block.to_string(),
+        expiration,
+        ..Default::default()
+    };
+    (
+        Address::with_prefix_key(ASK_ORDER, &block.to_string()),
+        ask_order.to_bytes(),
+    )
+}
 
-    log::warn!("{}..{}", last_pred, first_pred);
+#[test]
+fn expire_order_reports_pending_expiry() {
+    let request = TpProcessRequest::default();
+    let ctx = MockHandlerContext::default();
+    let (id, proto) = ask_order_at(100, 10);
 
-    let signers: Vec<String> = (last_pred..first_pred)
-        .map(|i| format!("signer{}", i))
-        .collect();
+    // an order created at 100 with an expiration of 10 blocks has expired at block index 111
+    let tx_ctx = MockTransactionContext::default();
+    let pending =
+        super::expire_order(&request, &tx_ctx, &ctx, &Integer::from(110), &id, &proto).unwrap();
+    assert_eq!(pending, Some(111));
 
-    let signers_ = signers.clone();
+    let mut tx_ctx = MockTransactionContext::default();
+    expect!(tx_ctx, delete_state_entry where enclose!((id) move |a| a == id.as_str()), returning |a| Ok(Some(a.to_owned())));
+    let pending =
+        super::expire_order(&request, &tx_ctx, &ctx, &Integer::from(111), &id, &proto).unwrap();
+    assert_eq!(pending, None);
 
-    // housekeeping tries to get the signatures for the blocks
-    // iterating backwards from first_pred to last_pred
-    expect!(tx_ctx,
-        get_reward_block_signatures(id if id == "headblocksig", first if *first == first_pred, last if *last == last_pred) -> Ok(
-            signers_.clone()
+    // a repayment order taken over by a new owner never expires
+    let repayment_order = protos::RepaymentOrder {
+        block: "100".into(),
+        expiration: 10,
+        previous_owner: "previousowner".into(),
+        ..Default::default()
+    };
+    let id = Address::with_prefix_key(REPAYMENT_ORDER, "repayment");
+    let tx_ctx = MockTransactionContext::default();
+    for block_idx in &[110, 111] {
+        let pending = super::expire_order(
+            &request,
+            &tx_ctx,
+            &ctx,
+            &Integer::from(*block_idx),
+            &id,
+            &repayment_order.to_bytes(),
         )
-    );
+        .unwrap();
+        assert_eq!(pending, None);
+    }
+}
 
-    let reward_amount = REWARD_AMOUNT.clone();
+#[test]
+fn expiry_buckets_are_sorted_and_deduplicated() {
+    let bucket_id = Address::with_prefix_key(EXPIRY_INDEX, "111");
+    let existing = protos::ExpiryBucket {
+        ids: vec!["b".into(), "d".into()],
+    };
 
-    for (idx, signer) in signers.clone().into_iter().enumerate() {
-        let wallet_id = WalletId::from(&SigHash(utils::sha512_id(signer.as_bytes())));
-        let wallet_id_ = wallet_id.clone();
+    let mut tx_ctx = MockTransactionContext::default();
+    expect!(tx_ctx, get_state_entry where enclose!((bucket_id) move |a| a == bucket_id.as_str()), returning move |_| Ok(Some(existing.to_bytes())));
 
-        // the first signer has no wallet, the rest have an existing balance of `idx`
-        let balance = if idx == 0 { None } else { Some(idx as u64) };
+    let mut states = vec![];
+    super::add_expiry_bucket_state(
+        &tx_ctx,
+        &mut states,
+        111,
+        vec!["d".to_owned(), "a".to_owned(), "c".to_owned()],
+    )
+    .unwrap();
 
-        log::info!("starting balance = {:?}", balance);
+    let expected = protos::ExpiryBucket {
+        ids: vec!["a".into(), "b".into(), "c".into(), "d".into()],
+    };
+    assert_eq!(states, vec![(bucket_id.to_string(), expected.to_bytes())]);
+}
 
-        // housekeeping should try to fetch the current wallet for each signer
-        // return the balance above
-        expect!(
-            tx_ctx,
-            get_state_entry(k if k == wallet_id.as_str()) -> Ok(wallet_with(balance))
-        );
+#[test]
+fn expire_indexed_processes_buckets_up_to_block_idx() {
+    let request = TpProcessRequest::default();
+    let ctx = MockHandlerContext::default();
+    let (expired, proto) = ask_order_at(100, 10);
+    let filled = Address::with_prefix_key(ASK_ORDER, "filled");
 
-        // we expect the wallet to have an updated balance of reward_amount + old balance
-        let wallet_expected = crate::protos::Wallet {
-            amount: (reward_amount.clone() + balance.unwrap_or(0)).to_string(),
+    let mut tx_ctx = MockTransactionContext::default();
+    for height in 109..=112u64 {
+        let bucket_id = Address::with_prefix_key(EXPIRY_INDEX, &height.to_string());
+        let bucket = if height == 111 {
+            Some(
+                protos::ExpiryBucket {
+                    ids: vec![expired.to_string(), filled.to_string()],
+                }
+                .to_bytes(),
+            )
+        } else {
+            None
         };
-        let state_expected = wallet_expected.to_bytes();
-
-        log::info!("expect end wallet = {:?}", wallet_expected);
-        // housekeeping should try to set the state to update
-        // the wallet balance with the reward added
-        expect!(
-            tx_ctx,
-            set_state_entry(
-                addr if addr == wallet_id_.as_str(),
-                state if state == &state_expected
-            ) -> Ok(())
-        );
+        let found = bucket.is_some();
+        expect!(tx_ctx, get_state_entry where enclose!((bucket_id) move |a| a == bucket_id.as_str()), returning move |_| Ok(bucket));
+        if found {
+            expect!(tx_ctx, delete_state_entry where enclose!((bucket_id) move |a| a == bucket_id.as_str()), returning |a| Ok(Some(a.to_owned())));
+        }
     }
+    expect!(tx_ctx, get_state_entry where enclose!((expired) move |a| a == expired.as_str()), returning move |_| Ok(Some(proto)));
+    expect!(tx_ctx, get_state_entry where enclose!((filled) move |a| a == filled.as_str()), returning |_| Ok(None));
+    expect!(tx_ctx, delete_state_entry where enclose!((expired) move |a| a == expired.as_str()), returning |a| Ok(Some(a.to_owned())));
 
-    // housekeeping should then set the processed_block_idx to the last processed block height
-    // which in this case is height_end - 1
-    expect!(tx_ctx, set_state_entry(
-            addr if addr == PROCESSED_BLOCK_IDX.as_str(),
-            state if state == &(first_pred).to_string().into_bytes()
-        ) -> Ok(())
-    );
+    let cursor_id = Address::with_prefix_key(EXPIRY_INDEX, EXPIRY_CURSOR_KEY);
+    expect!(tx_ctx, set_state_entry where enclose!((cursor_id) move |a, s| a == cursor_id.as_str() && s == b"112"), returning |_, _| Ok(()));
 
-    // run housekeeping
-    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+    super::expire_indexed(&request, &tx_ctx, &ctx, 108, &Integer::from(112)).unwrap();
 }
 
 #[test]
-fn housekeeping_not_enough_confirmations() {
-    init_logs();
+fn processor_params_expiry_index_block() {
+    let params = resolve_params(&[(EXPIRY_INDEX_BLOCK_KEY, "500")], 100).unwrap();
+    assert_eq!(params.expiry_index_block, Some(500));
+    let forks = ForkSchedule::resolve(&params, |_| Ok(None)).unwrap();
+    assert!(!forks.is_active(Feature::ExpiryIndex, 499));
+    assert!(forks.is_active(Feature::ExpiryIndex, 500));
+}
 
-    // Housekeeeping with block idx = 0
-    let command = Housekeeping {
-        block_idx: Integer::new(),
+#[test]
+fn fee_aggregate_accumulates_per_bucket() {
+    let request = TpProcessRequest {
+        tip: 1251,
+        ..Default::default()
+    };
+    let mut ctx = MockHandlerContext::default();
+    let sighash = SigHash(String::from("aggregatedpayer"));
+    let aggregate_id = Address::with_prefix_key(FEE_AGGREGATE, "aggregatedpayer:100:12");
+    let existing = protos::FeeAggregate {
+        sighash: sighash.clone().into(),
+        block: "1201".into(),
+        count: 2,
+        total: Integer::from(&*TX_FEE * 2).to_string(),
+        last_block: "1240".into(),
     };
 
-    // no blocks have been processed
-    let last_processed = 0;
+    let mut tx_ctx = MockTransactionContext::default();
+    expect!(tx_ctx, get_state_entry where enclose!((aggregate_id) move |a| a == aggregate_id.as_str()), returning move |_| Ok(Some(existing.to_bytes())));
 
-    // Chain tip is not quite at the threshold for running because
-    // the blocks have not yet gotten enough confirmations
+    let mut states = vec![];
+    for _ in 0..2 {
+        utils::add_fee_aggregate(&mut ctx, &tx_ctx, &request, 100, &sighash, &mut states).unwrap();
+    }
+
+    // the aggregate keeps the block of its earliest fee and tracks its last one
+    let expected = protos::FeeAggregate {
+        sighash: sighash.into(),
+        block: "1201".into(),
+        count: 4,
+        total: Integer::from(&*TX_FEE * 4).to_string(),
+        last_block: "1250".into(),
+    };
+    assert_eq!(
+        states,
+        vec![(aggregate_id.to_string(), expected.to_bytes())]
+    );
+}
+
+#[test]
+fn fee_aggregate_starts_empty() {
     let request = TpProcessRequest {
-        tip: BLOCK_REWARD_PROCESSING_COUNT + 1,
-        block_signature: "headblocksig".into(),
+        tip: 1301,
         ..Default::default()
     };
+    let mut ctx = MockHandlerContext::default();
+    let sighash = SigHash(String::from("aggregatedpayer"));
+    let aggregate_id = Address::with_prefix_key(FEE_AGGREGATE, "aggregatedpayer:100:13");
+
     let mut tx_ctx = MockTransactionContext::default();
+    expect!(tx_ctx, get_state_entry where enclose!((aggregate_id) move |a| a == aggregate_id.as_str()), returning |_| Ok(None));
 
-    expect!(tx_ctx,
-        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
-        -> Ok(Some(
-            Integer::from(last_processed).to_string().into_bytes()
-        ))
+    let mut states = vec![];
+    utils::add_fee_aggregate(&mut ctx, &tx_ctx, &request, 100, &sighash, &mut states).unwrap();
+
+    let expected = protos::FeeAggregate {
+        sighash: sighash.into(),
+        block: "1300".into(),
+        count: 1,
+        total: TX_FEE.to_string(),
+        last_block: "1300".into(),
+    };
+    assert_eq!(
+        states,
+        vec![(aggregate_id.to_string(), expected.to_bytes())]
     );
+}
 
+#[test]
+fn fee_aggregate_refunded_after_its_last_fee() {
     let mut ctx = MockHandlerContext::default();
+    let sighash = SigHash(String::from("aggregatedpayer"));
+    let aggregate_id = Address::with_prefix_key(FEE_AGGREGATE, "aggregatedpayer:100:12");
 
-    // execute housekeeping
-    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+    // one fee at the start of the bucket and one at its end
+    let mut tx_ctx = MockTransactionContext::default();
+    expect!(tx_ctx, get_state_entry where enclose!((aggregate_id) move |a| a == aggregate_id.as_str()), returning |_| Ok(None));
+    let mut states = vec![];
+    for &tip in &[1201, 1300] {
+        let request = TpProcessRequest {
+            tip,
+            ..Default::default()
+        };
+        utils::add_fee_aggregate(&mut ctx, &tx_ctx, &request, 100, &sighash, &mut states).unwrap();
+    }
+    let (_, proto) = states.pop().unwrap();
+
+    let year = 1000;
+    let tx_ctx = MockTransactionContext::default();
+    for &block_idx in &[1200 + year + 1, 1299 + year] {
+        super::refund_fee_aggregate(
+            &tx_ctx,
+            year,
+            &Integer::from(block_idx),
+            &aggregate_id,
+            &proto,
+        )
+        .unwrap();
+    }
+
+    let mut tx_ctx = MockTransactionContext::default();
+    let wallet_id = WalletId::from(&sighash);
+    expect!(tx_ctx, get balance at wallet_id -> Some(1));
+    expect_set_state_entries(
+        &mut tx_ctx,
+        vec![(
+            wallet_id.to_string(),
+            wallet_with(Some(Integer::from(&*TX_FEE * 2) + 1)).unwrap(),
+        )],
+    );
+    expect!(tx_ctx, delete_state_entry where enclose!((aggregate_id) move |a| a == aggregate_id.as_str()), returning |a| Ok(Some(a.to_owned())));
+    super::refund_fee_aggregate(
+        &tx_ctx,
+        year,
+        &Integer::from(1299 + year + 1),
+        &aggregate_id,
+        &proto,
+    )
+    .unwrap();
 }
 
 #[test]
-fn housekeeping_within_block_reward_count() {
-    init_logs();
-
-    // Housekeeeping with block idx = 0
-    let command = Housekeeping {
-        block_idx: Integer::new(),
-    };
+fn processor_params_fee_bucket_blocks() {
+    let params = resolve_params(&[], 100).unwrap();
+    assert_eq!(params.fee_bucket_blocks, 0);
+
+    let params = resolve_params(&[(FEE_BUCKET_BLOCKS_KEY, "1000@500")], 499).unwrap();
+    assert_eq!(params.fee_bucket_blocks, 0);
+    let params = resolve_params(&[(FEE_BUCKET_BLOCKS_KEY, "1000@500")], 500).unwrap();
+    assert_eq!(params.fee_bucket_blocks, 1000);
+}
 
-    // pretend we've issued some rewards already
-    let last_processed = 4 * CONFIRMATION_COUNT + BLOCK_REWARD_PROCESSING_COUNT;
+#[test]
+fn fee_records_amount_from_activation() {
+    let mut ctx = MockHandlerContext::default();
+    expect_fork_settings(&mut ctx, &[(FEE_AMOUNTS_BLOCK_KEY, "11")]);
+    let guid = Guid("feeguid".into());
+    ctx.expect_guid()
+        .returning(enclose!((guid) move |_| guid.clone()));
+    let tx_ctx = MockTransactionContext::default();
+    let sighash = SigHash(String::from("feepayer"));
 
-    // Chain tip is not quite at the threshold for running because
-    // fewer than BLOCK_REWARD_PROCESSING_COUNT additional blocks have been processed
-    let request = TpProcessRequest {
-        tip: last_processed + BLOCK_REWARD_PROCESSING_COUNT - 1,
-        block_signature: "headblocksig".into(),
+    let mut request = TpProcessRequest {
+        tip: 10,
         ..Default::default()
     };
-    let mut tx_ctx = MockTransactionContext::default();
-
-    // Housekeeping should check the last processed block, then bail out
-    expect!(tx_ctx,
-        get_state_entry(k if k == PROCESSED_BLOCK_IDX.as_str())
-        -> Ok(Some(
-            Integer::from(last_processed).to_string().into_bytes()
-        ))
+    let mut states = vec![];
+    utils::add_fee(&mut ctx, &tx_ctx, &request, &sighash, &mut states).unwrap();
+    assert_eq!(states, vec![make_fee(&guid, &sighash, Some(9))]);
+
+    request.tip = 11;
+    let mut states = vec![];
+    utils::add_fee(&mut ctx, &tx_ctx, &request, &sighash, &mut states).unwrap();
+    let fee = protos::Fee {
+        sighash: sighash.into(),
+        block: "10".into(),
+        amount: TX_FEE.to_string(),
+    };
+    assert_eq!(
+        states,
+        vec![(
+            Address::with_prefix_key(FEE, guid.as_str()).to_string(),
+            fee.to_bytes()
+        )]
     );
+}
 
-    let mut ctx = MockHandlerContext::default();
+#[test]
+fn fee_v2_tells_missing_amounts_from_zero() {
+    for amount in &["", "0", "7"] {
+        let fee = protos::Fee {
+            sighash: "feepayer".into(),
+            block: "10".into(),
+            amount: amount.to_string(),
+        };
+        let v2 = protos::FeeV2::try_from(fee.clone()).unwrap();
+        assert_eq!(v2.paid.is_some(), !amount.is_empty());
+        let decoded = protos::FeeV2::try_parse(v2.to_bytes()).unwrap();
+        assert_eq!(protos::Fee::try_from(decoded).unwrap(), fee);
+    }
+}
 
-    // execute housekeeping
-    command.execute(&request, &tx_ctx, &mut ctx).unwrap();
+#[test]
+fn processor_params_state_v2_block() {
+    let params = resolve_params(&[(STATE_V2_BLOCK_KEY, "700")], 100).unwrap();
+    assert_eq!(params.state_v2_block, Some(700));
+    let forks = ForkSchedule::resolve(&params, |_| Ok(None)).unwrap();
+    assert!(!forks.is_active(Feature::StateV2, 699));
+    assert!(forks.is_active(Feature::StateV2, 700));
 }
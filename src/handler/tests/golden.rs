@@ -0,0 +1,302 @@
+//! Golden fixtures: each file in `tests/fixtures` holds a pre-state, a transaction and either the
+//! expected post-state or the expected error, in the form they take on chain: the transaction is
+//! its raw payload and header fields, and state values (settings included) are hex-encoded entry
+//! bytes. Fixtures are run through `CCTransactionHandler::apply`, with the handler context reading
+//! the signer, nonce and settings from the transaction and the pre-state like the real one does.
+//!
+//! ```json
+//! {
+//!   "description": "what the case covers",
+//!   "tip": 100,
+//!   "transaction": {
+//!     "payload": "hex",
+//!     "signer_public_key": "hex",
+//!     "nonce": "transaction nonce",
+//!     "family_version": "1.8"
+//!   },
+//!   "block_signers": { "99": "public key" },
+//!   "default_block_signer": "public key",
+//!   "pre_state": { "address": "hex" },
+//!   "post_state": { "address": "hex" },
+//!   "error": "expected message, instead of post_state"
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use sawtooth_sdk::{
+    messages::processor::TpProcessRequest,
+    processor::handler::{ApplyError, ContextError, TransactionContext, TransactionHandler},
+};
+use serde::Deserialize;
+
+use crate::handler::{
+    context::{mocked::MockHandlerContext, HandlerContext},
+    types::TxnResult,
+    CCTransactionHandler,
+};
+
+#[derive(Deserialize)]
+struct Fixture {
+    description: String,
+    tip: u64,
+    transaction: Transaction,
+    #[serde(default)]
+    block_signers: HashMap<u64, String>,
+    #[serde(default)]
+    default_block_signer: Option<String>,
+    #[serde(default)]
+    pre_state: BTreeMap<String, String>,
+    #[serde(default)]
+    post_state: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    payload: String,
+    #[serde(default)]
+    signer_public_key: String,
+    #[serde(default)]
+    nonce: String,
+    #[serde(default)]
+    family_version: String,
+}
+
+impl Transaction {
+    fn request(&self, tip: u64) -> Result<TpProcessRequest, String> {
+        let mut request = TpProcessRequest {
+            payload: hex::decode(&self.payload).map_err(|e| e.to_string())?,
+            tip,
+            ..Default::default()
+        };
+        let header = request.mut_header();
+        header.set_signer_public_key(self.signer_public_key.clone());
+        header.set_nonce(self.nonce.clone());
+        header.set_family_version(self.family_version.clone());
+        Ok(request)
+    }
+}
+
+/// An in-memory chain state, standing in for the validator. Clones share the same state.
+#[derive(Clone)]
+struct FixtureContext {
+    state: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    block_signers: HashMap<u64, String>,
+    default_block_signer: Option<String>,
+}
+
+impl FixtureContext {
+    fn block_signer(&self, block_num: u64) -> Result<String, ContextError> {
+        self.block_signers
+            .get(&block_num)
+            .or_else(|| self.default_block_signer.as_ref())
+            .cloned()
+            .ok_or_else(|| {
+                ContextError::ResponseAttributeError(format!("No signer for block {}", block_num))
+            })
+    }
+}
+
+impl TransactionContext for FixtureContext {
+    fn get_state_entry(&self, address: &str) -> Result<Option<Vec<u8>>, ContextError> {
+        Ok(self.state.lock().unwrap().get(address).cloned())
+    }
+
+    fn get_state_entries(
+        &self,
+        addresses: &[String],
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        let state = self.state.lock().unwrap();
+        Ok(addresses
+            .iter()
+            .filter_map(|a| state.get(a).map(|v| (a.clone(), v.clone())))
+            .collect())
+    }
+
+    fn set_state_entry(&self, address: String, data: Vec<u8>) -> Result<(), ContextError> {
+        self.state.lock().unwrap().insert(address, data);
+        Ok(())
+    }
+
+    fn set_state_entries(&self, entries: Vec<(String, Vec<u8>)>) -> Result<(), ContextError> {
+        self.state.lock().unwrap().extend(entries);
+        Ok(())
+    }
+
+    fn delete_state_entry(&self, address: &str) -> Result<Option<String>, ContextError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .remove(address)
+            .map(|_| address.to_owned()))
+    }
+
+    fn delete_state_entries(&self, addresses: &[String]) -> Result<Vec<String>, ContextError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(addresses
+            .iter()
+            .filter(|a| state.remove(*a).is_some())
+            .cloned()
+            .collect())
+    }
+
+    fn add_receipt_data(&self, _: &[u8]) -> Result<(), ContextError> {
+        Ok(())
+    }
+
+    fn add_event(&self, _: String, _: Vec<(String, String)>, _: &[u8]) -> Result<(), ContextError> {
+        Ok(())
+    }
+
+    fn get_sig_by_num(&self, block_num: u64) -> Result<String, ContextError> {
+        self.block_signer(block_num)
+    }
+
+    fn get_reward_block_signatures(
+        &self,
+        _: &str,
+        first_pred: u64,
+        last_pred: u64,
+    ) -> Result<Vec<String>, ContextError> {
+        (last_pred..=first_pred)
+            .rev()
+            .map(|block_num| self.block_signer(block_num))
+            .collect()
+    }
+
+    fn get_state_entries_by_prefix(
+        &self,
+        address: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, ContextError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .range(address.to_owned()..)
+            .take_while(|(a, _)| a.starts_with(address))
+            .map(|(a, v)| (a.clone(), v.clone()))
+            .collect())
+    }
+}
+
+fn decode_state(state: &BTreeMap<String, String>) -> BTreeMap<String, Vec<u8>> {
+    state
+        .iter()
+        .map(|(address, data)| (address.clone(), hex::decode(data).unwrap()))
+        .collect()
+}
+
+fn encode_state(state: &BTreeMap<String, Vec<u8>>) -> BTreeMap<String, String> {
+    state
+        .iter()
+        .map(|(address, data)| (address.clone(), hex::encode(data)))
+        .collect()
+}
+
+/// A real handler context over the fixture state. It never reaches a gateway, as `verify` is the
+/// only call that would.
+fn real_context(tx_ctx: &FixtureContext) -> TxnResult<HandlerContext<'_>> {
+    HandlerContext::create(zmq::Context::new(), String::new(), tx_ctx)
+}
+
+/// `apply` creates the mocked handler context in tests, so each call is forwarded to a real
+/// context reading the transaction and the fixture state.
+fn fixture_handler_context(
+    tx_ctx: &FixtureContext,
+    request: &TpProcessRequest,
+) -> TxnResult<MockHandlerContext> {
+    let params = real_context(tx_ctx)?.params(request)?.clone();
+
+    let mut ctx = MockHandlerContext::default();
+    let tip = request.get_tip();
+    ctx.expect_tip().returning(move || tip);
+    let view = tx_ctx.clone();
+    ctx.expect_sighash()
+        .returning(move |request| real_context(&view)?.sighash(request));
+    let view = tx_ctx.clone();
+    ctx.expect_guid()
+        .returning(move |request| real_context(&view).unwrap().guid(request));
+    let view = tx_ctx.clone();
+    ctx.expect_get_setting()
+        .returning(move |key| real_context(&view)?.get_setting(key));
+    ctx.expect_verify().returning(|_| Ok(()));
+    Ok(ctx.with_params(params))
+}
+
+fn run_fixture(path: &Path) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let fixture: Fixture = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+
+    let request = fixture.transaction.request(fixture.tip)?;
+    let mut tx_ctx = FixtureContext {
+        state: Arc::new(Mutex::new(decode_state(&fixture.pre_state))),
+        block_signers: fixture.block_signers,
+        default_block_signer: fixture.default_block_signer,
+    };
+
+    let ctx = fixture_handler_context(&tx_ctx, &request).map_err(|e| e.to_string())?;
+    let create = MockHandlerContext::create_context();
+    create.expect().return_once(move |_, _, _| Ok(ctx));
+
+    let result = CCTransactionHandler::new("tcp://localhost:55555").apply(&request, &mut tx_ctx);
+
+    match (result, fixture.error, fixture.post_state) {
+        (Ok(()), None, Some(expected)) => {
+            let found = encode_state(&tx_ctx.state.lock().unwrap());
+            if found == expected {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{}\nexpected state : {:#?}\nfound state : {:#?}",
+                    fixture.description, expected, found
+                ))
+            }
+        }
+        (Err(ApplyError::InvalidTransaction(s)), Some(expected), _) if s == expected => Ok(()),
+        (Err(e), Some(expected), _) => Err(format!(
+            "{}\nexpected error {:?}, found {}",
+            fixture.description, expected, e
+        )),
+        (Ok(()), Some(expected), _) => Err(format!(
+            "{}\nexpected error {:?}, but the transaction succeeded",
+            fixture.description, expected
+        )),
+        (Err(e), None, _) => Err(format!("{}\nunexpected error {}", fixture.description, e)),
+        (Ok(()), None, None) => Err("A fixture needs either post_state or error".into()),
+    }
+}
+
+fn fixture_paths() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn golden_fixtures() {
+    let paths = fixture_paths();
+    assert!(!paths.is_empty(), "No fixtures found");
+
+    let failures: Vec<_> = paths
+        .iter()
+        .filter_map(|path| {
+            run_fixture(path)
+                .err()
+                .map(|e| format!("{} : {}", path.display(), e))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::ops::Deref;
 
@@ -6,9 +7,10 @@ use rug::Integer;
 use sawtooth_sdk::processor::handler::ApplyError;
 use sawtooth_sdk::processor::handler::ContextError;
 
+use crate::ext::IntegerExt;
 use crate::handler::constants::*;
 use crate::handler::utils::sha512_id;
-use crate::string;
+use crate::{bail_transaction, string};
 
 pub type TxnResult<T, E = anyhow::Error> = std::result::Result<T, E>;
 
@@ -183,3 +185,82 @@ pub type StateVec = Vec<(String, Vec<u8>)>;
 // pub struct BlockNum(Integer);
 
 pub type BlockNum = Integer;
+
+/// An interest rate of `interest` per `maturity` blocks, with `interest` scaled by `INTEREST_MULTIPLIER`.
+/// Rates compare by cross-multiplication, so no precision is lost to integer division.
+#[derive(Debug, Clone)]
+pub struct Rate {
+    interest: Integer,
+    maturity: Integer,
+}
+
+impl Rate {
+    pub fn try_parse(interest: &str, maturity: &str) -> TxnResult<Self> {
+        let interest = Integer::try_parse(interest)?;
+        let maturity = Integer::try_parse(maturity)?;
+        if maturity == 0 {
+            bail_transaction!("The maturity must be greater than zero");
+        }
+        Ok(Self { interest, maturity })
+    }
+
+    pub fn interest(&self) -> &Integer {
+        &self.interest
+    }
+
+    pub fn maturity(&self) -> &Integer {
+        &self.maturity
+    }
+
+    /// Compares the truncated per-block rates, as family versions before 1.8 did.
+    pub fn legacy_cmp(&self, other: &Self) -> Ordering {
+        let lhs = Integer::from(&self.interest / &self.maturity);
+        let rhs = Integer::from(&other.interest / &other.maturity);
+        lhs.cmp(&rhs)
+    }
+
+    /// The number of maturity periods started after `elapsed` blocks.
+    pub fn ticks(&self, elapsed: &Integer) -> Integer {
+        Integer::from(elapsed + &self.maturity) / &self.maturity
+    }
+}
+
+impl Ord for Rate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = Integer::from(&self.interest * &other.maturity);
+        let rhs = Integer::from(&other.interest * &self.maturity);
+        lhs.cmp(&rhs)
+    }
+}
+
+impl PartialOrd for Rate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Rate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Rate {}
+
+impl crate::protos::AskOrder {
+    pub fn rate(&self) -> TxnResult<Rate> {
+        Rate::try_parse(&self.interest, &self.maturity)
+    }
+}
+
+impl crate::protos::BidOrder {
+    pub fn rate(&self) -> TxnResult<Rate> {
+        Rate::try_parse(&self.interest, &self.maturity)
+    }
+}
+
+impl crate::protos::DealOrder {
+    pub fn rate(&self) -> TxnResult<Rate> {
+        Rate::try_parse(&self.interest, &self.maturity)
+    }
+}
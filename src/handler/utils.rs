@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use std::fmt::Write;
 
-use crate::ext::IntegerExt;
+use crate::ext::{IntegerExt, MessageExt};
 
 use prost::Message;
 use rug::Integer;
@@ -16,7 +16,9 @@ use crate::handler::constants::{
 
 use super::constants::INTEREST_MULTIPLIER;
 use super::constants::INVALID_NUMBER_ERR;
+use super::forks::Feature;
 use super::types::BlockNum;
+use super::types::Rate;
 use super::types::State;
 use super::types::StateVec;
 use super::types::WalletId;
@@ -109,6 +111,80 @@ pub fn get_signed_integer(
     Integer::try_parse_signed(str_value)
 }
 
+pub fn get_string_list(
+    map: &BTreeMap<Value, Value>,
+    key: &str,
+    name: &str,
+) -> TxnResult<Vec<String>> {
+    let items = match map.get(&Value::Text(key.into())) {
+        Some(Value::Array(items)) => items,
+        Some(value) => bail_transaction!("Value for {} was not a list, found : {:?}", name, value),
+        None => bail_transaction!("Expecting {}", name),
+    };
+
+    let mut strings = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            Value::Text(s) => strings.push(s.clone()),
+            _ => bail_transaction!("Entries of {} must be strings, found : {:?}", name, item),
+        }
+    }
+    Ok(strings)
+}
+
+/// Reads a list of `[sighash, amount]` pairs.
+pub fn get_transfer_list(
+    map: &BTreeMap<Value, Value>,
+    key: &str,
+    name: &str,
+) -> TxnResult<Vec<(SigHash, Integer)>> {
+    let items = match map.get(&Value::Text(key.into())) {
+        Some(Value::Array(items)) => items,
+        Some(value) => bail_transaction!("Value for {} was not a list, found : {:?}", name, value),
+        None => bail_transaction!("Expecting {}", name),
+    };
+    if items.is_empty() {
+        bail_transaction!("Expecting at least one entry in {}", name);
+    }
+
+    let mut transfers = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            Value::Array(pair) => match pair.as_slice() {
+                [Value::Text(sighash), Value::Text(amount)] => {
+                    transfers.push((SigHash(sighash.clone()), Integer::try_parse(amount)?));
+                }
+                _ => bail_transaction!(
+                    "Entries of {} must be [sighash, amount] pairs, found : {:?}",
+                    name,
+                    item
+                ),
+            },
+            _ => bail_transaction!(
+                "Entries of {} must be [sighash, amount] pairs, found : {:?}",
+                name,
+                item
+            ),
+        }
+    }
+    Ok(transfers)
+}
+
+pub fn get_bool_or_default(map: &BTreeMap<Value, Value>, key: &str, name: &str) -> TxnResult<bool> {
+    match map.get(&Value::Text(key.into())) {
+        None => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(Value::Text(s)) => match s.to_lowercase().as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => bail_transaction!("Value for {} was not a boolean, found : {:?}", name, s),
+        },
+        Some(value) => {
+            bail_transaction!("Value for {} was not a boolean, found : {:?}", name, value)
+        }
+    }
+}
+
 pub fn get_u64(map: &BTreeMap<Value, Value>, key: &str, name: &str) -> TxnResult<u64> {
     let str_value = get_string(map, key, name)?;
     str_value
@@ -208,6 +284,19 @@ pub fn last_block(request: &TpProcessRequest) -> BlockNum {
     }
 }
 
+/// Whether the transaction targets at least the given family version. Versions that
+/// don't parse are treated as predating every gate.
+pub fn family_version_at_least(request: &TpProcessRequest, major: u32, minor: u32) -> bool {
+    let version = request.get_header().get_family_version();
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(found_major)), Some(Ok(found_minor))) => {
+            (found_major, found_minor) >= (major, minor)
+        }
+        _ => false,
+    }
+}
+
 pub fn get_state_data<A: AsRef<str>>(
     tx_ctx: &dyn TransactionContext,
     address: A,
@@ -244,37 +333,114 @@ pub fn add_state<M: Message>(states: &mut StateVec, id: String, message: &M) ->
 
 pub fn add_fee(
     ctx: &mut HandlerContext,
+    tx_ctx: &dyn TransactionContext,
     request: &TpProcessRequest,
     sighash: &SigHash,
     states: &mut StateVec,
 ) -> TxnResult<()> {
     let guid = ctx.guid(request);
-    let fee_id = Address::with_prefix_key(super::constants::FEE, guid.as_str());
+    add_fee_at(ctx, tx_ctx, request, guid.as_str(), sighash, states)
+}
+
+/// Records a fee under an explicit key, for transactions that charge more than one party.
+/// Once fees are aggregated, the key is unused and the fee is added to the aggregate of the
+/// payer and block bucket instead.
+pub fn add_fee_at(
+    ctx: &mut HandlerContext,
+    tx_ctx: &dyn TransactionContext,
+    request: &TpProcessRequest,
+    key: &str,
+    sighash: &SigHash,
+    states: &mut StateVec,
+) -> TxnResult<()> {
+    let bucket_blocks = ctx.params(request)?.fee_bucket_blocks;
+    if bucket_blocks != 0 {
+        return add_fee_aggregate(ctx, tx_ctx, request, bucket_blocks, sighash, states);
+    }
+
+    let fee_id = Address::with_prefix_key(super::constants::FEE, key);
+    let amount = if fee_amounts(ctx, request)? {
+        ctx.tx_fee()?.to_string()
+    } else {
+        String::new()
+    };
     let fee = crate::protos::Fee {
         sighash: sighash.clone().into(),
         block: last_block(request).to_string_radix(10),
+        amount,
     };
     add_state(states, fee_id.into(), &fee)
 }
 
+/// Fees record the amount paid once `Feature::FeeAmounts` is active; older fees leave it empty
+/// so that replays produce the same state, and housekeeping refunds the current fee for those.
+fn fee_amounts(ctx: &HandlerContext, request: &TpProcessRequest) -> TxnResult<bool> {
+    Ok(ctx
+        .forks(request)?
+        .is_active(Feature::FeeAmounts, request.get_tip()))
+}
+
+pub fn add_fee_aggregate(
+    ctx: &mut HandlerContext,
+    tx_ctx: &dyn TransactionContext,
+    request: &TpProcessRequest,
+    bucket_blocks: u64,
+    sighash: &SigHash,
+    states: &mut StateVec,
+) -> TxnResult<()> {
+    let block = last_block(request);
+    let bucket = Integer::from(&block / bucket_blocks);
+    // the bucket size is part of the key, so a resized bucket never reuses an older aggregate
+    let key = format!("{}:{}:{}", sighash.as_str(), bucket_blocks, bucket);
+    let aggregate_id: String =
+        Address::with_prefix_key(super::constants::FEE_AGGREGATE, &key).into();
+
+    // the aggregate may already be pending in this transaction's states
+    let pending = states
+        .iter()
+        .position(|(address, _)| *address == aggregate_id);
+    let existing = match pending {
+        Some(i) => Some(states.remove(i).1),
+        None => tx_ctx
+            .get_state_entry(&aggregate_id)
+            .map_err(CCApplyError::from)?,
+    };
+    let mut aggregate = match existing {
+        Some(state_data) => crate::protos::FeeAggregate::try_parse(&state_data)?,
+        None => crate::protos::FeeAggregate {
+            sighash: sighash.clone().into(),
+            block: block.to_string_radix(10),
+            total: "0".into(),
+            ..Default::default()
+        },
+    };
+
+    // no fee in the bucket is refunded before its last one is a year old
+    aggregate.last_block = block.to_string_radix(10);
+    aggregate.count += 1;
+    aggregate.total = (Integer::try_parse(&aggregate.total)? + ctx.tx_fee()?).to_string();
+    add_state(states, aggregate_id, &aggregate)
+}
+
 pub fn add_fee_state(
     ctx: &mut HandlerContext,
+    tx_ctx: &dyn TransactionContext,
     request: &TpProcessRequest,
     sighash: &SigHash,
     states: &mut StateVec,
     wallet_id: &WalletId,
     wallet: &crate::protos::Wallet,
 ) -> TxnResult<()> {
-    add_fee(ctx, request, sighash, states)?;
+    add_fee(ctx, tx_ctx, request, sighash, states)?;
     add_state(states, wallet_id.clone().into(), wallet)
 }
 
-pub fn calc_interest(amount: &Integer, ticks: &Integer, interest: &Integer) -> Integer {
+pub fn calc_interest(amount: &Integer, ticks: &Integer, rate: &Rate) -> Integer {
     let mut total = amount.clone();
     let mut i = Integer::from(0);
 
     while &i < ticks {
-        let compound = (total.clone() * interest) / INTEREST_MULTIPLIER;
+        let compound = (total.clone() * rate.interest()) / INTEREST_MULTIPLIER;
         total += compound;
         i += 1;
     }
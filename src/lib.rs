@@ -0,0 +1,15 @@
+#![allow(
+    clippy::suspicious_operation_groupings,
+    clippy::try_err,
+    clippy::wrong_self_convention
+)]
+#![deny(unused_must_use)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+pub mod ext;
+pub mod handler;
+
+#[allow(non_snake_case)]
+pub mod protos {
+    include!(concat!(env!("OUT_DIR"), "/cc.protos.rs"));
+}
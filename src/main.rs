@@ -1,20 +1,8 @@
-#![allow(
-    clippy::suspicious_operation_groupings,
-    clippy::try_err,
-    clippy::wrong_self_convention
-)]
 #![deny(unused_must_use)]
 #![cfg_attr(test, allow(dead_code, unused_imports))]
 
-pub mod ext;
-pub mod handler;
-
-#[allow(non_snake_case)]
-pub mod protos {
-    include!(concat!(env!("OUT_DIR"), "/cc.protos.rs"));
-}
-
 use anyhow::Result;
+use ccprocessor_rust::handler;
 use fern::FormatCallback;
 use fern::{colors::Color, Dispatch};
 use log::LevelFilter;